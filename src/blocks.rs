@@ -1,19 +1,23 @@
 //! Contains [`BlockParser`] for parsing bitcoin [`Block`] from the `blocks` directory.
 
-use crate::headers::ParsedHeader;
-use crate::xor::XorReader;
-use crate::HeaderParser;
-use anyhow::Result;
-use bitcoin::consensus::Decodable;
-use bitcoin::{Block, Transaction};
-use crossbeam_channel::{bounded, Receiver, Sender};
-use log::info;
-use std::cmp::min;
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::BufReader;
+use crate::events::ChainEvents;
+use crate::headers::{
+    median_times_past, GapPolicy, HeaderChain, HeaderInfoIter, HeaderReport, ParsedHeader,
+    StaleBranch,
+};
+use crate::params::ChainParams;
+use crate::source::{BlockSource, BlocksDir};
+use anyhow::{anyhow, bail, Context, Result};
+use bitcoin::pow::Work;
+use bitcoin::{Block, BlockHash, Network, Transaction, Txid};
+use crossbeam_channel::{bounded, select, Receiver, Sender};
+use log::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::Instant;
 use threadpool::ThreadPool;
@@ -48,10 +52,28 @@ use threadpool::ThreadPool;
 ///     println!("{}", block_hash);
 /// }
 /// ```
+///
+/// You can also read a single block by its height or hash without spawning any threads:
+/// ```no_run
+/// use bitcoin_block_parser::blocks::*;
+///
+/// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+/// let block = parser.get_block_at_height(170).unwrap();
+/// let same_block = parser.get_block(&block.block_hash()).unwrap();
+/// println!("First bitcoin transaction: {:?}", same_block.txdata[1]);
+/// ```
 #[derive(Clone, Debug)]
 pub struct BlockParser {
+    /// Where the blocks are read from
+    source: Arc<dyn BlockSource>,
     /// The parsed headers used for locating the blocks
     headers: Vec<ParsedHeader>,
+    /// Position of each header in `headers` keyed by block hash
+    positions: Arc<HashMap<BlockHash, usize>>,
+    /// Branches of stale blocks that forked from the headers
+    stale: Vec<StaleBranch>,
+    /// Diagnostics about headers that could not be connected to the genesis block
+    report: HeaderReport,
     /// A logger for reporting on the progress of the parsing
     logger: ParserLogger,
     /// Options that can have an effect on memory and cpu performance
@@ -60,6 +82,21 @@ pub struct BlockParser {
     start_height: usize,
     /// The block height range to end at
     end_height: usize,
+    /// Unix timestamp of the earliest median-time-past to parse
+    start_time: Option<u32>,
+    /// Unix timestamp of the latest median-time-past to parse
+    end_time: Option<u32>,
+    /// Positions in `headers` of the selected blocks in the order they were selected, parsing
+    /// every block between the start and end heights if `None`
+    selection: Option<Vec<usize>>,
+    /// Selected heights that are not in the active chain
+    missing_heights: Vec<usize>,
+    /// Selected hashes that are not in the active chain
+    missing_hashes: Vec<BlockHash>,
+    /// Skips any selected blocks whose header does not match
+    header_filter: Option<HeaderFilter>,
+    /// Median-time-past of each header in `headers`, computed when first needed
+    median_times: OnceLock<Arc<[u32]>>,
 }
 
 impl BlockParser {
@@ -74,20 +111,78 @@ impl BlockParser {
 
     /// Creates a parser with custom [`ParserOptions`].
     pub fn new_with_opts(blocks_dir: &str, options: ParserOptions) -> Result<Self> {
-        let headers = HeaderParser::parse(blocks_dir)?;
-        Ok(Self {
-            headers,
+        Self::from_source(BlocksDir::new(blocks_dir), options)
+    }
+
+    /// Creates a parser that reads blocks from any [`BlockSource`].
+    ///
+    /// - Returns an `Err` if unable to read the headers from the `source`.
+    /// - Returns an `Err` if the [`ParserOptions::chain_params`] are for an Elements chain, use
+    ///   [`crate::elements::ElementsParser`] instead.
+    pub fn from_source(source: impl BlockSource + 'static, options: ParserOptions) -> Result<Self> {
+        Self::from_shared_source(Arc::new(source), options)
+    }
+
+    /// Creates a parser from a [`BlockSource`] that may be shared with other parsers.
+    ///
+    /// - Returns an `Err` for Elements chains, whose blocks cannot be decoded into [`Block`].
+    pub(crate) fn from_shared_source(
+        source: Arc<dyn BlockSource>,
+        options: ParserOptions,
+    ) -> Result<Self> {
+        let params = options.params();
+        if params.elements {
+            bail!(
+                "{} blocks cannot be parsed as bitcoin blocks, use ElementsParser",
+                params.name
+            );
+        }
+        let chain = source.chain(&options)?;
+        Ok(Self::from_chain(source, chain, options))
+    }
+
+    /// Creates a parser from an already parsed [`HeaderChain`].
+    pub(crate) fn from_chain(
+        source: Arc<dyn BlockSource>,
+        chain: HeaderChain,
+        options: ParserOptions,
+    ) -> Self {
+        let positions = chain
+            .active
+            .iter()
+            .enumerate()
+            .map(|(position, header)| (header.hash, position))
+            .collect();
+        Self {
+            source,
+            headers: chain.active,
+            positions: Arc::new(positions),
+            stale: chain.stale,
+            report: chain.report,
             logger: ParserLogger::new(),
             options,
             start_height: 0,
             end_height: usize::MAX,
-        })
+            start_time: None,
+            end_time: None,
+            selection: None,
+            missing_heights: vec![],
+            missing_hashes: vec![],
+            header_filter: None,
+            median_times: OnceLock::new(),
+        }
+    }
+
+    /// Returns the [`Network`] the blocks belong to, useful for deriving addresses.
+    /// - Returns `None` if [`ParserOptions::chain_params`] is set to a chain other than bitcoin,
+    ///   whose addresses cannot be derived with a [`Network`].
+    pub fn network(&self) -> Option<Network> {
+        self.options.params().network()
     }
 
     /// Sets the *inclusive* start of block heights to parse.
     ///
-    /// * `start_height` - must be less than the total number of blocks, `0` will start at the
-    ///    genesis block.
+    /// * `start_height` - `0` will start at the genesis block.
     pub fn start_height(mut self, start_height: usize) -> Self {
         self.start_height = start_height;
         self
@@ -96,61 +191,753 @@ impl BlockParser {
     /// Sets the *inclusive* end of block heights to parse.
     ///
     /// * `end_height` - the height to end at, [`usize::MAX`] will stop at the last block
-    ///    available.
+    ///   available.
     pub fn end_height(mut self, end_height: usize) -> Self {
         self.end_height = end_height;
         self
     }
 
+    /// Sets the *inclusive* start of block times to parse, combined with the start height.
+    ///
+    /// * `start_time` - unix timestamp compared against the median-time-past of each block, which
+    ///   unlike the block timestamp never decreases, so the first block is deterministic.
+    ///
+    /// # Example
+    /// Parsing every block from March 2021:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let march = parser.start_time(1_614_556_800).end_time(1_617_235_199);
+    /// println!("Transactions: {}", march.parse(|block| block.txdata.len()).sum::<usize>());
+    /// ```
+    pub fn start_time(mut self, start_time: u32) -> Self {
+        self.start_time = Some(start_time);
+        self
+    }
+
+    /// Sets the *inclusive* end of block times to parse, combined with the end height.
+    ///
+    /// * `end_time` - unix timestamp compared against the median-time-past of each block.
+    pub fn end_time(mut self, end_time: u32) -> Self {
+        self.end_time = Some(end_time);
+        self
+    }
+
+    /// Selects specific blocks to parse by their `heights`, rather than every block between the
+    /// start and end heights.
+    ///
+    /// - Selected heights outside of the start and end heights are not parsed.
+    /// - Heights that are not in the active chain are skipped, see
+    ///   [`BlockParser::missing_heights`].
+    /// - [`ParserIterator::ordered`] returns the blocks in the order they were selected.
+    ///
+    /// # Example
+    /// Parsing the blocks where the subsidy halves:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let halvings = parser.heights([210_000, 420_000, 630_000, 840_000]);
+    /// for height in halvings.missing_heights() {
+    ///     println!("Not synced to height {}", height);
+    /// }
+    /// for time in halvings.parse(|block| block.header.time).ordered() {
+    ///     println!("{}", time);
+    /// }
+    /// ```
+    pub fn heights(mut self, heights: impl IntoIterator<Item = usize>) -> Self {
+        let (mut positions, mut missing) = (vec![], vec![]);
+        for height in heights {
+            match self
+                .headers
+                .binary_search_by_key(&height, |header| header.height)
+            {
+                Ok(position) => positions.push(position),
+                Err(_) => {
+                    warn!("Skipping height {} that is not in the active chain", height);
+                    missing.push(height);
+                }
+            }
+        }
+        self.selection = Some(Self::unique(positions.into_iter()));
+        self.missing_heights = missing;
+        self.missing_hashes = vec![];
+        self
+    }
+
+    /// Selects specific blocks to parse by their `hashes`, rather than every block between the
+    /// start and end heights.
+    ///
+    /// - Selected blocks outside of the start and end heights are not parsed.
+    /// - Hashes that are not in the active chain are skipped, see
+    ///   [`BlockParser::missing_hashes`].
+    /// - [`ParserIterator::ordered`] returns the blocks in the order they were selected.
+    pub fn hashes(mut self, hashes: impl IntoIterator<Item = BlockHash>) -> Self {
+        let (mut positions, mut missing) = (vec![], vec![]);
+        for hash in hashes {
+            match self.positions.get(&hash) {
+                Some(position) => positions.push(*position),
+                None => {
+                    warn!("Skipping block {} that is not in the active chain", hash);
+                    missing.push(hash);
+                }
+            }
+        }
+        self.selection = Some(Self::unique(positions.into_iter()));
+        self.missing_hashes = missing;
+        self.missing_heights = vec![];
+        self
+    }
+
+    /// Returns the heights passed to [`BlockParser::heights`] that are not in the active chain,
+    /// such as heights above the tip, which will not be parsed.
+    pub fn missing_heights(&self) -> &[usize] {
+        &self.missing_heights
+    }
+
+    /// Returns the hashes passed to [`BlockParser::hashes`] that are not in the active chain,
+    /// such as stale or unknown blocks, which will not be parsed.
+    pub fn missing_hashes(&self) -> &[BlockHash] {
+        &self.missing_hashes
+    }
+
+    /// Only parses the blocks where `filter` returns true given their header and height, so the
+    /// transactions of the other blocks are never read.
+    ///
+    /// - Applies to the blocks between the start and end heights or the selected blocks.
+    /// - Replaces any filter that was previously set.
+    /// - Does not apply to [`BlockParser::header_info`] or [`BlockParser::events_since`].
+    ///
+    /// # Example
+    /// Parsing the blocks that signal for taproot with version bit 2:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let signalling = parser
+    ///     .start_height(680_000)
+    ///     .end_height(710_000)
+    ///     .filter_headers(|header, _| header.inner.version.is_signalling_soft_fork(2));
+    /// println!("Signalling blocks: {}", signalling.parse(|_| 1).sum::<usize>());
+    /// ```
+    pub fn filter_headers(
+        mut self,
+        filter: impl Fn(&ParsedHeader, usize) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.header_filter = Some(HeaderFilter(Arc::new(filter)));
+        self
+    }
+
+    /// Removes any duplicate `positions`, keeping the first.
+    fn unique(positions: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut seen = HashSet::new();
+        positions
+            .filter(|position| seen.insert(*position))
+            .collect()
+    }
+
     /// Parse all [`bitcoin::Block`] into type `T` and return a [`ParserIterator<T>`].  Results will
     /// be in random order due to multithreading.
     ///
+    /// If a block cannot be read or `extract` panics the iterator stops early, then panics on the
+    /// calling thread with the height, file and offset of the block.  Use
+    /// [`BlockParser::try_parse`] to handle the errors instead.
+    ///
     /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
-    ///    computation and data reduction here as possible.
+    ///   computation and data reduction here as possible.
     pub fn parse<T: Send + 'static>(
         &self,
         extract: impl Fn(Block) -> T + Clone + Send + 'static,
     ) -> ParserIterator<T> {
-        let end_height = min(self.end_height, self.headers.len() - 1);
-        let header_range = self.headers[self.start_height..=end_height].to_vec();
+        self.parse_with(self.block_reader(), extract)
+    }
+
+    /// Parse all [`bitcoin::Block`] into type `T`, returning an `Err` for every block that cannot
+    /// be read or where `extract` panics.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// for result in parser.try_parse(|block| block.txdata.len()).ordered() {
+    ///     match result {
+    ///         Ok(num_tx) => println!("{}", num_tx),
+    ///         // The error describes the height, file and offset of the block
+    ///         Err(e) => println!("{:?}", e),
+    ///     }
+    /// }
+    /// ```
+    pub fn try_parse<T: Send + 'static>(
+        &self,
+        extract: impl Fn(Block) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<Result<T>> {
+        self.try_parse_with(self.block_reader(), extract)
+    }
+
+    /// Parse all [`bitcoin::Block`] into type `T` like [`BlockParser::parse`], also passing the
+    /// [`BlockContext`] that describes where the block is located in the chain and on disk.
+    ///
+    /// # Example
+    /// Finding the largest block and the file it is stored in:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let iterator = parser.parse_with_context(|context, _| (context.size, context.height, context.path));
+    /// let (size, height, path) = iterator.max().unwrap();
+    /// println!("Block {} in {:?} is {} bytes", height, path, size);
+    /// ```
+    pub fn parse_with_context<T: Send + 'static>(
+        &self,
+        extract: impl Fn(BlockContext, Block) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<T> {
+        let read_block = self.block_reader();
+        let (positions, times) = (self.positions.clone(), self.median_times());
+        let read = move |header: &ParsedHeader| {
+            let block = read_block(header)?;
+            let median_time_past = times[positions[&header.hash]];
+            Ok((BlockContext::new(header, &block, median_time_past), block))
+        };
+        self.parse_with(read, move |(context, block)| extract(context, block))
+    }
+
+    /// Parse every [`Transaction`] into type `T` and return a [`TxIterator<T>`] with one result
+    /// per transaction.  Results of the same block are returned together in index order, but the
+    /// blocks will be in random order due to multithreading.
+    ///
+    /// # Example
+    /// Finding the position of the largest transaction:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let iterator = parser.parse_transactions(|context, tx| (tx.total_size(), context.txid));
+    /// let (size, txid) = iterator.max().unwrap();
+    /// println!("Transaction {} is {} bytes", txid, size);
+    /// ```
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse_transactions<T: Send + 'static>(
+        &self,
+        extract: impl Fn(TxContext, &Transaction) -> T + Clone + Send + 'static,
+    ) -> TxIterator<T> {
+        let read_block = self.block_reader();
+        let read = move |header: &ParsedHeader| Ok((header.height, read_block(header)?));
+        let iterator = self.parse_with(read, move |(height, block)| {
+            let txids = block.txdata.iter().map(Transaction::compute_txid);
+            let contexts = TxContext::of_block(height, block.block_hash(), txids);
+            contexts
+                .zip(&block.txdata)
+                .map(|(context, tx)| extract(context, tx))
+                .collect()
+        });
+        TxIterator::new(iterator)
+    }
+
+    /// Parses the selected blocks, reading each one with `read`.
+    pub(crate) fn parse_with<B, T: Send + 'static>(
+        &self,
+        read: impl Fn(&ParsedHeader) -> Result<B> + Clone + Send + 'static,
+        extract: impl Fn(B) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<T> {
+        self.try_parse_with(read, extract).stop_on_error()
+    }
+
+    /// Parses the selected blocks, reading each one with `read` and returning any errors.
+    pub(crate) fn try_parse_with<B, T: Send + 'static>(
+        &self,
+        read: impl Fn(&ParsedHeader) -> Result<B> + Clone + Send + 'static,
+        extract: impl Fn(B) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<Result<T>> {
+        self.parse_headers(self.selected_headers(), read, extract)
+    }
+
+    /// Returns the headers of the selected blocks between the start and end heights that match
+    /// the header filter, in the order they will be returned from [`ParserIterator::ordered`].
+    fn selected_headers(&self) -> Vec<ParsedHeader> {
+        let (start, end) = self.header_range();
+        let headers: Box<dyn Iterator<Item = &ParsedHeader>> = match &self.selection {
+            Some(selection) => Box::new(
+                selection
+                    .iter()
+                    .filter(|position| (start..end).contains(position))
+                    .map(|position| &self.headers[*position]),
+            ),
+            None => Box::new(self.headers[start..end].iter()),
+        };
+        headers
+            .filter(|header| match &self.header_filter {
+                Some(HeaderFilter(filter)) => filter(header, header.height),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the range of `headers` between the start and end heights and times.
+    fn header_range(&self) -> (usize, usize) {
+        // Heights may skip over gaps so search for the range instead of indexing
+        let mut start = self
+            .headers
+            .partition_point(|header| header.height < self.start_height);
+        let mut end = self
+            .headers
+            .partition_point(|header| header.height <= self.end_height);
+        if self.start_time.is_some() || self.end_time.is_some() {
+            // The median-time-past never decreases so it can be searched, unlike block timestamps
+            let times = self.median_times();
+            if let Some(start_time) = self.start_time {
+                start = start.max(times.partition_point(|time| *time < start_time));
+            }
+            if let Some(end_time) = self.end_time {
+                end = end.min(times.partition_point(|time| *time <= end_time));
+            }
+        }
+        (start, end.max(start))
+    }
+
+    /// Returns the median-time-past of each header, shared by every call.
+    fn median_times(&self) -> Arc<[u32]> {
+        let times = self
+            .median_times
+            .get_or_init(|| median_times_past(&self.headers).into());
+        times.clone()
+    }
+
+    /// Iterates over the [`crate::headers::HeaderInfo`] of every block between the start and end
+    /// heights and times in height order, without reading any transaction data.
+    pub fn header_info(&self) -> HeaderInfoIter<'_> {
+        let (start, end) = self.header_range();
+        HeaderInfoIter::new(&self.headers[..end], start)
+    }
+
+    /// Returns the [`ChainEvents`] needed to move from a previously known `prev_tip` to the tip of
+    /// the active chain, up to the end height.
+    ///
+    /// - Returns an `Err` if the `prev_tip` is not in the active chain or any stale branch.
+    /// - Blocks are read from disk on the calling thread as the events are iterated.
+    pub fn events_since(&self, prev_tip: &BlockHash) -> Result<ChainEvents> {
+        let (_, end) = self.header_range();
+        let source = self.source.clone();
+        match ChainEvents::from_tip(source, &self.headers[..end], &self.stale, prev_tip) {
+            Some(events) => Ok(events),
+            None => bail!("Previous tip {} is not in any known chain", prev_tip),
+        }
+    }
+
+    /// Returns the [`ParsedHeader`] of a block in the active chain given its hash.
+    pub fn get_header(&self, hash: &BlockHash) -> Option<&ParsedHeader> {
+        self.positions
+            .get(hash)
+            .map(|position| &self.headers[*position])
+    }
+
+    /// Returns the [`ParsedHeader`] of the block in the active chain at `height`.
+    pub fn get_header_at_height(&self, height: usize) -> Option<&ParsedHeader> {
+        let position = self
+            .headers
+            .binary_search_by_key(&height, |header| header.height)
+            .ok()?;
+        Some(&self.headers[position])
+    }
+
+    /// Returns the height of a block in the active chain given its hash.
+    pub fn height_of(&self, hash: &BlockHash) -> Option<usize> {
+        self.get_header(hash).map(|header| header.height)
+    }
+
+    /// Reads a single [`bitcoin::Block`] in the active chain given its hash.
+    ///
+    /// - Returns an `Err` if the block is not in the active chain or cannot be read.
+    /// - Reads directly from the [`BlockSource`] on the calling thread, use [`BlockParser::parse`] for bulk
+    ///   parsing.
+    pub fn get_block(&self, hash: &BlockHash) -> Result<Block> {
+        match self.get_header(hash) {
+            Some(header) => {
+                self.block_reader()(header).with_context(|| format!("Error reading {}", header))
+            }
+            None => bail!("Block {} is not in the active chain", hash),
+        }
+    }
+
+    /// Reads a single [`bitcoin::Block`] in the active chain at `height`.
+    ///
+    /// - Returns an `Err` if there is no block at `height` or it cannot be read.
+    pub fn get_block_at_height(&self, height: usize) -> Result<Block> {
+        match self.get_header_at_height(height) {
+            Some(header) => {
+                self.block_reader()(header).with_context(|| format!("Error reading {}", header))
+            }
+            None => bail!("No block at height {}", height),
+        }
+    }
+
+    /// Returns the [`HeaderReport`] describing any headers that could not be connected to the
+    /// genesis block.
+    pub fn report(&self) -> &HeaderReport {
+        &self.report
+    }
+
+    /// Returns every [`StaleBranch`] of blocks that lost to the active chain.
+    pub fn stale_branches(&self) -> &[StaleBranch] {
+        &self.stale
+    }
+
+    /// Parse the stale [`bitcoin::Block`] from every [`StaleBranch`] into type `T`, ignoring the
+    /// start and end heights and any selected blocks.  Results will be in random order due to multithreading.
+    ///
+    /// Stale blocks can share the same height so [`ParserIterator::ordered`] should not be used.
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse_stale<T: Send + 'static>(
+        &self,
+        extract: impl Fn(Block) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<T> {
+        // Branches can share headers so only parse each block once
+        let mut seen = HashSet::new();
+        let mut headers = vec![];
+        for branch in &self.stale {
+            for header in &branch.headers {
+                if seen.insert(header.hash) {
+                    headers.push(header.clone());
+                }
+            }
+        }
+        self.parse_headers(headers, self.block_reader(), extract)
+            .stop_on_error()
+    }
+
+    /// Returns a function that reads blocks from the source, verifying them if
+    /// [`ParserOptions::verify`] is set.
+    pub(crate) fn block_reader(
+        &self,
+    ) -> impl Fn(&ParsedHeader) -> Result<Block> + Clone + Send + 'static {
+        let source = self.source.clone();
+        let params = self.options.verify.then(|| self.options.params());
+        move |header| {
+            let block = source.read_block(header)?;
+            if let Some(params) = &params {
+                verify_block(header, &block, params)?;
+            }
+            Ok(block)
+        }
+    }
+
+    /// Parses the blocks of the headers on multiple threads, reading each one with `read`.
+    ///
+    /// The blocks are read in the order of the `headers`, which [`ParserIterator::ordered`] will
+    /// return them in.
+    fn parse_headers<B, T: Send + 'static>(
+        &self,
+        headers: impl IntoIterator<Item = ParsedHeader>,
+        read: impl Fn(&ParsedHeader) -> Result<B> + Clone + Send + 'static,
+        extract: impl Fn(B) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<Result<T>> {
         let pool = ThreadPool::new(self.options.num_threads);
         let (tx, rx) = bounded(self.options.channel_size);
+        let error = FirstError::default();
+        let cancel = CancelHandle::default();
+        let headers: Arc<Vec<ParsedHeader>> = Arc::new(headers.into_iter().collect());
+        let next = Arc::new(AtomicUsize::new(0));
 
-        for (index, header) in header_range.into_iter().enumerate() {
+        // Threads take the next header when they are free, so no work is queued up front
+        for _ in 0..self.options.num_threads {
             let logger = self.logger.clone();
             let tx = tx.clone();
             let extract = extract.clone();
-            let start_height = self.start_height;
+            let read = read.clone();
+            let error = error.clone();
+            let cancel = cancel.clone();
+            let headers = headers.clone();
+            let next = next.clone();
             pool.execute(move || {
-                let extract = match Self::parse_block(&header) {
-                    Ok(block) => extract(block),
-                    // Panic here because a blk file is corrupted, nothing else to do
-                    e => panic!("Error reading {:?} - {:?}", header.path, e),
-                };
-                let height = start_height + index;
-                let _ = tx.send((height, extract));
-                logger.increment();
+                while let Some(header) = headers.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    // No need to parse the remaining blocks once the iterator is going to stop
+                    if error.is_set() || cancel.is_cancelled() {
+                        break;
+                    }
+                    let result = catch_panic(|| read(header).map(&extract))
+                        .and_then(|result| result)
+                        .with_context(|| format!("Error parsing {}", header));
+                    if !cancel.send(&tx, (header.height, result)) {
+                        break;
+                    }
+                    logger.increment();
+                }
             });
         }
+        let heights = headers.iter().map(|header| header.height).collect();
         ParserIterator {
             rx,
             options: self.options.clone(),
-            start_height: self.start_height,
+            heights: Arc::new(heights),
+            error,
+            cancel,
+            derived: AtomicBool::new(false),
         }
     }
+}
+
+/// Describes a block passed to [`BlockParser::parse_with_context`].
+#[derive(Clone, Debug)]
+pub struct BlockContext {
+    /// Height of the block
+    pub height: usize,
+    /// Hash of the block
+    pub hash: BlockHash,
+    /// Path of the file the block was read from, empty for sources that do not read from files
+    pub path: PathBuf,
+    /// Byte offset in the file where the block was read from, see [`ParsedHeader::offset`]
+    pub offset: usize,
+    /// Size of the serialized block in bytes, as stored in the BLK file if known
+    pub size: usize,
+    /// Cumulative work of the chain up to and including this block
+    pub chainwork: Work,
+    /// Median timestamp of the last 11 blocks up to and including this block (BIP113)
+    pub median_time_past: u32,
+}
+
+impl BlockContext {
+    /// Creates the context of a `block` read from the location of the `header`.
+    fn new(header: &ParsedHeader, block: &Block, median_time_past: u32) -> Self {
+        Self {
+            height: header.height,
+            hash: header.hash,
+            path: header.path.clone(),
+            offset: header.offset,
+            // Sources that do not read from files do not know the size until the block is read
+            size: match header.size {
+                0 => block.total_size(),
+                size => size,
+            },
+            chainwork: header.chainwork,
+            median_time_past,
+        }
+    }
+}
 
-    /// Helper function for reading a block from the filesystem given the header.
-    fn parse_block(header: &ParsedHeader) -> Result<Block> {
-        let reader = BufReader::new(File::open(&header.path)?);
-        let mut reader = BufReader::new(XorReader::new(reader, header.xor_mask));
-        reader.seek_relative(header.offset as i64)?;
-        Ok(Block {
-            header: header.inner,
-            txdata: Vec::<Transaction>::consensus_decode_from_finite_reader(&mut reader)?,
+/// Describes a transaction passed to [`BlockParser::parse_transactions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TxContext {
+    /// Height of the block that contains the transaction
+    pub height: usize,
+    /// Hash of the block that contains the transaction
+    pub block_hash: BlockHash,
+    /// Position of the transaction in the block
+    pub index: usize,
+    /// Id of the transaction
+    pub txid: Txid,
+    /// Whether the transaction is the coinbase, which is always the first in the block
+    pub is_coinbase: bool,
+}
+
+impl TxContext {
+    /// Returns the context of every transaction in a block given their `txids` in order.
+    pub(crate) fn of_block(
+        height: usize,
+        block_hash: BlockHash,
+        txids: impl Iterator<Item = Txid>,
+    ) -> impl Iterator<Item = Self> {
+        txids.enumerate().map(move |(index, txid)| Self {
+            height,
+            block_hash,
+            index,
+            txid,
+            is_coinbase: index == 0,
         })
     }
 }
 
+/// Iterator returned from [`BlockParser::parse_transactions`] that returns the results of every
+/// transaction.
+///
+/// Like [`ParserIterator`] dropping the iterator stops all of its threads.
+pub struct TxIterator<T> {
+    /// Results of every transaction in a block, in index order
+    blocks: ParserIterator<Vec<T>>,
+    /// Remaining results of the block currently being returned
+    current: std::vec::IntoIter<T>,
+}
+
+impl<T: Send + 'static> TxIterator<T> {
+    /// Creates an iterator that flattens the results of every block.
+    pub(crate) fn new(blocks: ParserIterator<Vec<T>>) -> Self {
+        Self {
+            blocks,
+            current: vec![].into_iter(),
+        }
+    }
+
+    /// Orders the results by block height and then by the index of the transaction in the block.
+    pub fn ordered(&self) -> TxIterator<T> {
+        Self::new(self.blocks.ordered())
+    }
+
+    /// Stops every thread, see [`ParserIterator::cancel`].
+    pub fn cancel(&self) {
+        self.blocks.cancel();
+    }
+
+    /// Returns a handle that can cancel the iterator from another thread.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.blocks.cancel_handle()
+    }
+}
+
+impl<T> TxIterator<T> {
+    /// Returns the next result without panicking, see [`ParserIterator::try_next`].
+    pub fn try_next(&mut self) -> Result<Option<T>> {
+        loop {
+            if let Some(t) = self.current.next() {
+                return Ok(Some(t));
+            }
+            match self.blocks.try_next()? {
+                Some(results) => self.current = results.into_iter(),
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl<T> Iterator for TxIterator<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next()
+            .unwrap_or_else(|error| panic!("{:?}", error))
+    }
+}
+
+/// Function that returns true if the block of a header and height should be parsed.
+type HeaderPredicate = dyn Fn(&ParsedHeader, usize) -> bool + Send + Sync;
+
+/// Predicate set by [`BlockParser::filter_headers`].
+#[derive(Clone)]
+struct HeaderFilter(Arc<HeaderPredicate>);
+
+impl fmt::Debug for HeaderFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("HeaderFilter")
+    }
+}
+
+/// Returns an `Err` if the `block` read from the location of the `header` is corrupted.
+fn verify_block(header: &ParsedHeader, block: &Block, params: &ChainParams) -> Result<()> {
+    let version = block.header.version.to_consensus();
+    if block.block_hash() != header.hash {
+        bail!(
+            "Block hash {} does not match the header",
+            block.block_hash()
+        );
+    }
+    if !block.check_merkle_root() {
+        bail!("Merkle root does not match the transactions");
+    }
+    if !block.check_witness_commitment() {
+        bail!("Witness commitment does not match the transactions");
+    }
+    let has_pow = params.hash_is_pow && !params.has_aux_pow(version);
+    if has_pow && !block.header.target().is_met_by(header.hash) {
+        bail!("Block hash does not meet the target");
+    }
+    // Sources that do not know the size use zero, some chains store data after the block
+    let size = block.total_size();
+    let extra_data = params.extension_data || params.has_aux_pow(version);
+    if header.size != 0 && (size > header.size || (size < header.size && !extra_data)) {
+        bail!("Decoded size {} does not match size {}", size, header.size);
+    }
+    Ok(())
+}
+
+/// Runs `function`, returning an `Err` with the message if it panics.
+fn catch_panic<T>(function: impl FnOnce() -> T) -> Result<T> {
+    panic::catch_unwind(AssertUnwindSafe(function)).map_err(|payload| {
+        let message = match payload.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => match payload.downcast_ref::<String>() {
+                Some(message) => message.clone(),
+                None => "unknown panic".to_string(),
+            },
+        };
+        anyhow!("Panicked with '{}'", message)
+    })
+}
+
+/// Cancels a [`ParserIterator`] from another thread, see [`ParserIterator::cancel_handle`].
+#[derive(Clone, Debug)]
+pub struct CancelHandle {
+    /// Set once the iterator is cancelled
+    cancelled: Arc<AtomicBool>,
+    /// Dropped on cancel, which closes the channel and wakes every thread blocked on a send
+    closer: Arc<Mutex<Option<Sender<()>>>>,
+    /// Never receives a message, only becomes ready once the channel is closed
+    closed: Receiver<()>,
+}
+
+impl Default for CancelHandle {
+    fn default() -> Self {
+        let (closer, closed) = bounded(0);
+        Self {
+            cancelled: Arc::default(),
+            closer: Arc::new(Mutex::new(Some(closer))),
+            closed,
+        }
+    }
+}
+
+impl CancelHandle {
+    /// Stops every thread of the iterator, which will return `None` from then on.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.closer.lock().expect("Lock poisoned").take();
+    }
+
+    /// Returns true if the iterator was cancelled or dropped.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// Sends `message` to the next step, returning false if the iterator was cancelled or the
+    /// receiver was dropped before it could be sent.
+    fn send<T>(&self, tx: &Sender<T>, message: T) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        select! {
+            send(tx, message) -> result => result.is_ok(),
+            recv(self.closed) -> _ => false,
+        }
+    }
+}
+
+/// The first error from any thread of a [`ParserIterator`], returned once the iterator ends.
+#[derive(Clone, Debug, Default)]
+struct FirstError(Arc<Mutex<Option<anyhow::Error>>>);
+
+impl FirstError {
+    /// Stores the `error` unless an earlier error was already stored.
+    fn set(&self, error: anyhow::Error) {
+        let mut first = self.0.lock().expect("Lock poisoned");
+        if first.is_none() {
+            *first = Some(error);
+        }
+    }
+
+    /// Returns true if an error was stored.
+    fn is_set(&self) -> bool {
+        self.0.lock().expect("Lock poisoned").is_some()
+    }
+
+    /// Removes the stored error.
+    fn take(&self) -> Option<anyhow::Error> {
+        self.0.lock().expect("Lock poisoned").take()
+    }
+}
+
 /// Options that affect the performance of [`BlockParser`] and [`ParserIterator`].
 ///
 /// Generally changing these will be unnessary unless you really need to tune performance.
@@ -162,6 +949,37 @@ pub struct ParserOptions {
     pub channel_size: usize,
     /// The number of threads that will be spawned when running a multithreaded function.
     pub num_threads: usize,
+    /// Locate blocks using Bitcoin Core's `blocks/index` database rather than scanning every
+    /// BLK file, which greatly speeds up startup.
+    ///
+    /// The index only contains blocks that Bitcoin Core has connected, so the chain never has
+    /// gaps and the [`HeaderReport`] is always empty.  The `header_cache` and `gap_policy` are
+    /// not used, and the block index cannot be used for AuxPoW or Elements chains.
+    pub block_index: bool,
+    /// File that caches the headers scanned from the BLK files, speeding up later runs.
+    pub header_cache: Option<String>,
+    /// The network the blocks belong to, BLK files from any other network will return an `Err`.
+    pub network: Network,
+    /// What to do if some blocks are missing, see [`GapPolicy`].
+    pub gap_policy: GapPolicy,
+    /// Parameters for parsing a chain derived from bitcoin, overrides the `network` if set.
+    pub chain_params: Option<ChainParams>,
+    /// Checks the integrity of every block after it is read, returning an `Err` that contains
+    /// the file, offset and height of the first invalid block.
+    ///
+    /// Verifies the merkle root, the witness commitment, the proof-of-work and that the size
+    /// written before the block matches the decoded size, roughly doubling the cost of parsing.
+    pub verify: bool,
+}
+
+impl ParserOptions {
+    /// Returns the [`ChainParams`] of the chain being parsed.
+    pub(crate) fn params(&self) -> ChainParams {
+        match &self.chain_params {
+            Some(params) => params.clone(),
+            None => ChainParams::bitcoin(self.network),
+        }
+    }
 }
 
 impl Default for ParserOptions {
@@ -171,45 +989,100 @@ impl Default for ParserOptions {
             pipeline_size: 1,
             channel_size: 100,
             num_threads: 64,
+            block_index: false,
+            header_cache: None,
+            network: Network::Bitcoin,
+            gap_policy: GapPolicy::default(),
+            chain_params: None,
+            verify: false,
         }
     }
 }
 
 /// Iterator returned from [`BlockParser::parse`] that allows for advanced transformations.
+///
+/// Dropping the iterator, for example after [`Iterator::take`], stops all of its threads so
+/// no more blocks are read.  Iterators created from this one with functions such as
+/// [`ParserIterator::ordered`] share the same threads.
 pub struct ParserIterator<T> {
     /// The receiver coming from a previous transformation step.  `usize` is the block height.
     rx: Receiver<(usize, T)>,
     /// Options for tuning performance.
     options: ParserOptions,
-    /// The block height the parser started at.
-    start_height: usize,
+    /// The block heights the parser will return in the order [`ParserIterator::ordered`] returns
+    /// them, which may skip over gaps.
+    heights: Arc<Vec<usize>>,
+    /// The first error from any thread, which stops the iterator.
+    error: FirstError,
+    /// Shared by every iterator created from the same parse to stop all the threads.
+    cancel: CancelHandle,
+    /// Whether another iterator was created from this one, which keeps the threads running.
+    derived: AtomicBool,
 }
 
 impl<A: Send + 'static> ParserIterator<A> {
     /// Create a new iterator from an existing one, given a new receiver.
     fn create<T>(&self, rx: Receiver<(usize, T)>) -> ParserIterator<T> {
+        self.derived.store(true, Ordering::Relaxed);
         ParserIterator::<T> {
             rx,
             options: self.options.clone(),
-            start_height: self.start_height,
+            heights: self.heights.clone(),
+            error: self.error.clone(),
+            cancel: self.cancel.clone(),
+            derived: AtomicBool::new(false),
         }
     }
 
+    /// Stops reading blocks and running functions on every thread, releasing the files and
+    /// memory they hold.  The iterator will return `None` from then on.
+    ///
+    /// Dropping the iterator also cancels it.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    /// Returns a handle that can [`ParserIterator::cancel`] the iterator from another thread.
+    ///
+    /// # Example
+    /// Stopping the parser after a minute:
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let iterator = parser.parse(|block| block.txdata.len());
+    /// let handle = iterator.cancel_handle();
+    /// thread::spawn(move || {
+    ///     thread::sleep(Duration::from_secs(60));
+    ///     handle.cancel();
+    /// });
+    /// println!("Transactions: {}", iterator.sum::<usize>());
+    /// ```
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
     /// Adds the block height to this iterator.
     pub fn with_height(&self) -> ParserIterator<(usize, A)> {
         let (tx, rx) = bounded(self.options.channel_size);
         let parser = self.create(rx);
         let rx_a = self.rx.clone();
+        let cancel = self.cancel.clone();
 
         thread::spawn(move || {
             for (height, a) in rx_a {
-                let _ = tx.send((height, (height, a)));
+                if !cancel.send(&tx, (height, (height, a))) {
+                    break;
+                }
             }
         });
         parser
     }
 
-    /// Orders the results by block height, can be called for a small increase in
+    /// Orders the results by block height, or in the order the blocks were selected with
+    /// [`BlockParser::heights`] or [`BlockParser::hashes`].  Can be called for a small increase in
     /// memory and runtime.
     ///
     /// # Example
@@ -226,17 +1099,23 @@ impl<A: Send + 'static> ParserIterator<A> {
         let (tx, rx) = bounded(self.options.channel_size);
         let parser = self.create(rx);
         let rx_a = self.rx.clone();
-        let start_height = self.start_height;
+        let heights = self.heights.clone();
+        let cancel = self.cancel.clone();
 
         thread::spawn(move || {
-            let mut current_height = start_height;
+            let mut heights = heights.iter().peekable();
             let mut unordered: HashMap<usize, A> = HashMap::default();
 
             for (height, a) in rx_a {
                 unordered.insert(height, a);
-                while let Some(ordered) = unordered.remove(&current_height) {
-                    let _ = tx.send((current_height, ordered));
-                    current_height += 1;
+                while let Some(&&current_height) = heights.peek() {
+                    let Some(ordered) = unordered.remove(&current_height) else {
+                        break;
+                    };
+                    if !cancel.send(&tx, (current_height, ordered)) {
+                        return;
+                    }
+                    heights.next();
                 }
             }
         });
@@ -258,9 +1137,21 @@ impl<A: Send + 'static> ParserIterator<A> {
             let tx_b = tx_b.clone();
             let rx_a = self.rx.clone();
             let function = function.clone();
+            let error = self.error.clone();
+            let cancel = self.cancel.clone();
             pool.execute(move || {
                 for (height, a) in rx_a {
-                    let _ = tx_b.send((height, function(a)));
+                    if error.is_set() || cancel.is_cancelled() {
+                        break;
+                    }
+                    match catch_panic(|| function(a)) {
+                        Ok(b) => {
+                            if !cancel.send(&tx_b, (height, b)) {
+                                break;
+                            }
+                        }
+                        Err(e) => error.set(e.context(format!("Error mapping height {}", height))),
+                    }
                 }
             });
         }
@@ -276,7 +1167,7 @@ impl<A: Send + 'static> ParserIterator<A> {
     /// following code:
     /// ```no_run
     /// use bitcoin_block_parser::blocks::*;
-    /// use std::collections::HashMap;
+    /// use std::collections::{HashMap, HashSet};
     /// use std::convert::identity;
     /// use bitcoin::BlockHash;
     /// use bitcoin::hashes::Hash;
@@ -358,15 +1249,22 @@ impl<A: Send + 'static> ParserIterator<A> {
         let (tx_b, rx_b) = bounded(self.options.pipeline_size * self.options.num_threads);
         let (tx_c, rx_c) = bounded(self.options.pipeline_size * self.options.num_threads);
         let run = Arc::new(AtomicBool::new(true));
+        let error = self.error.clone();
+        let cancel = self.cancel.clone();
 
         thread::spawn(move || {
-            while run.load(Ordering::Relaxed) {
+            while run.load(Ordering::Relaxed) && !cancel.is_cancelled() {
                 let p1 = pipeline.clone();
                 let p2 = pipeline.clone();
-                Self::run_pipeline(&opts, &pool_a, &run, &rx_a, &tx_b, &move |a| p1.first(a));
+                let first = move |a| p1.first(a);
+                Self::run_pipeline(&opts, &pool_a, &run, &error, &cancel, &rx_a, &tx_b, &first);
                 pool_a.join();
-                pipeline.between();
-                Self::run_pipeline(&opts, &pool_b, &run, &rx_b, &tx_c, &move |b| p2.second(b));
+                if let Err(e) = catch_panic(|| pipeline.between()) {
+                    error.set(e.context("Error running pipeline"));
+                    break;
+                }
+                let second = move |b| p2.second(b);
+                Self::run_pipeline(&opts, &pool_b, &run, &error, &cancel, &rx_b, &tx_c, &second);
             }
         });
 
@@ -374,10 +1272,13 @@ impl<A: Send + 'static> ParserIterator<A> {
     }
 
     /// Helper for running the pipeline functions on multiple threads.
+    #[allow(clippy::too_many_arguments)]
     fn run_pipeline<X: Send + 'static, Y: Send + 'static>(
         options: &ParserOptions,
         pool: &ThreadPool,
         running: &Arc<AtomicBool>,
+        error: &FirstError,
+        cancel: &CancelHandle,
         rx: &Receiver<(usize, X)>,
         tx: &Sender<(usize, Y)>,
         function: &(impl Fn(X) -> Y + Clone + Send + 'static),
@@ -385,16 +1286,32 @@ impl<A: Send + 'static> ParserIterator<A> {
         // Spawns `num_threads` and run `function` on a `pipeline_size` # of items
         for _ in 0..options.num_threads {
             let running = running.clone();
+            let error = error.clone();
+            let cancel = cancel.clone();
             let tx = tx.clone();
             let rx = rx.clone();
             let function = function.clone();
             let pipeline_size = options.pipeline_size;
             pool.execute(move || {
                 for _ in 0..pipeline_size {
+                    if cancel.is_cancelled() {
+                        break;
+                    }
                     match rx.recv() {
-                        Ok((height, x)) => {
-                            let _ = tx.send((height, function(x)));
-                        }
+                        Ok((height, x)) => match catch_panic(|| function(x)) {
+                            Ok(y) => {
+                                if !cancel.send(&tx, (height, y)) {
+                                    running.store(false, Ordering::Relaxed);
+                                    break;
+                                }
+                            }
+                            Err(e) => {
+                                error.set(
+                                    e.context(format!("Error in pipeline at height {}", height)),
+                                );
+                                running.store(false, Ordering::Relaxed);
+                            }
+                        },
                         Err(_) => {
                             // Signal to the pipeline thread that we have consumed all input
                             running.store(false, Ordering::Relaxed);
@@ -406,11 +1323,92 @@ impl<A: Send + 'static> ParserIterator<A> {
     }
 }
 
+impl<A: Send + 'static> ParserIterator<Result<A>> {
+    /// Stops at the first `Err`, which will be returned once the iterator ends.
+    fn stop_on_error(&self) -> ParserIterator<A> {
+        let (tx, rx) = bounded(self.options.channel_size);
+        let parser = self.create(rx);
+        let rx_a = self.rx.clone();
+        let error = self.error.clone();
+        let cancel = self.cancel.clone();
+
+        thread::spawn(move || {
+            for (height, result) in rx_a {
+                match result {
+                    Ok(a) => {
+                        if !cancel.send(&tx, (height, a)) {
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        error.set(e);
+                        break;
+                    }
+                }
+            }
+        });
+        parser
+    }
+}
+
+impl<T> ParserIterator<T> {
+    /// Returns the next result, or the first error from any thread once all results are returned.
+    ///
+    /// Unlike [`Iterator::next`] this does not panic, so errors from reading blocks or from the
+    /// functions passed to [`ParserIterator::map_parallel`] and [`ParserIterator::pipeline`] can
+    /// be handled.
+    ///
+    /// # Example
+    /// ```no_run
+    /// use bitcoin_block_parser::blocks::*;
+    ///
+    /// let parser = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+    /// let mut iterator = parser
+    ///     .parse(|block| block.txdata.len())
+    ///     .map_parallel(|num_txs| num_txs.checked_sub(1).expect("coinbase"));
+    /// let mut total = 0;
+    /// loop {
+    ///     match iterator.try_next() {
+    ///         Ok(Some(num_txs)) => total += num_txs,
+    ///         Ok(None) => break,
+    ///         Err(e) => panic!("Stopped after {} transactions: {:?}", total, e),
+    ///     }
+    /// }
+    /// ```
+    pub fn try_next(&mut self) -> Result<Option<T>> {
+        if self.cancel.is_cancelled() {
+            return Ok(None);
+        }
+        match self.rx.recv() {
+            Ok((_, t)) => Ok(Some(t)),
+            Err(_) if self.cancel.is_cancelled() => Ok(None),
+            Err(_) => match self.error.take() {
+                Some(error) => Err(error),
+                None => Ok(None),
+            },
+        }
+    }
+}
+
 impl<T> Iterator for ParserIterator<T> {
     type Item = T;
 
+    /// Returns the next result.
+    ///
+    /// Panics once all results are returned if any thread failed, use
+    /// [`ParserIterator::try_next`] or [`BlockParser::try_parse`] to handle errors instead.
     fn next(&mut self) -> Option<Self::Item> {
-        self.rx.iter().map(|(_, t)| t).next()
+        self.try_next()
+            .unwrap_or_else(|error| panic!("{:?}", error))
+    }
+}
+
+impl<T> Drop for ParserIterator<T> {
+    /// Cancels the threads unless another iterator was created from this one.
+    fn drop(&mut self) {
+        if !self.derived.load(Ordering::Relaxed) {
+            self.cancel.cancel();
+        }
     }
 }
 
@@ -464,6 +1462,8 @@ impl ParserLogger {
         }
     }
 
+    // `is_multiple_of` would raise the minimum supported Rust version to 1.87
+    #[allow(clippy::manual_is_multiple_of)]
     fn increment(&self) {
         let num = self.num_parsed.fetch_add(1, Ordering::Relaxed);
 
@@ -476,3 +1476,493 @@ impl ParserLogger {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::tests::{blk_records, mine, regtest_chain, solve, spending_chain};
+    use crate::index::tests::write_index;
+    use crate::leveldb::tests::temp_dir;
+    use crate::source::MemoryBlocks;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{CompactTarget, ScriptBuf, Witness, WitnessCommitment};
+    use std::fs;
+    use std::path::Path;
+
+    /// Mines a block with a transaction that has a witness, committing to the `commitment` or the
+    /// correct witness commitment if `None`.
+    fn mine_segwit(prev: &Block, height: i64, commitment: Option<WitnessCommitment>) -> Block {
+        let mut block = mine(&prev.header, height);
+        let reserved = [0; 32];
+        block.txdata[0].input[0].witness = Witness::from_slice(&[reserved]);
+        let mut tx = block.txdata[0].clone();
+        tx.input[0].previous_output.txid = prev.txdata[0].compute_txid();
+        tx.input[0].witness = Witness::from_slice(&[[1]]);
+        block.txdata.push(tx);
+        let root = block.witness_root().unwrap();
+        let commitment =
+            commitment.unwrap_or_else(|| Block::compute_witness_commitment(&root, &reserved));
+        let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        script.extend(commitment.as_byte_array());
+        block.txdata[0].output.push(bitcoin::TxOut {
+            value: bitcoin::Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(script),
+        });
+        solve(&mut block);
+        block
+    }
+
+    /// Parses the blocks in `dir` with and without verification, returning the error of every
+    /// block that failed verification along with its header.
+    fn verify_errors(dir: &Path, options: ParserOptions) -> Vec<(String, String)> {
+        let dir = dir.to_str().unwrap();
+        let options = ParserOptions {
+            verify: false,
+            ..options
+        };
+        let parser = BlockParser::new_with_opts(dir, options.clone()).unwrap();
+        let num_blocks = parser.parse(|_| ()).count();
+        assert_eq!(num_blocks, parser.header_info().count());
+
+        let options = ParserOptions {
+            verify: true,
+            ..options
+        };
+        let parser = BlockParser::new_with_opts(dir, options).unwrap();
+        let results = parser.try_parse(|_| ()).with_height().ordered();
+        results
+            .filter_map(|(height, result)| {
+                let header = parser.get_header_at_height(height).unwrap();
+                Some((header.to_string(), format!("{:#}", result.err()?)))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn verifies_blocks() {
+        let dir = temp_dir("verify");
+        let blocks = regtest_chain(1);
+        let segwit = mine_segwit(&blocks[0], 1, None);
+        let bad_commitment = mine_segwit(&segwit, 2, Some(WitnessCommitment::all_zeros()));
+        let bad_merkle = mine(&bad_commitment.header, 3);
+        let bad_size = mine(&bad_merkle.header, 4);
+        let last = mine(&bad_size.header, 5);
+
+        let mut data = blk_records(&[blocks[0].clone(), segwit, bad_commitment]);
+        // The transactions no longer match the merkle root in the header
+        let mut changed = bad_merkle.clone();
+        changed.txdata[0].output[0].value = bitcoin::Amount::from_sat(1);
+        data.extend(blk_records(&[changed]));
+        // The size before the block includes 4 bytes that are not part of it
+        let mut record = blk_records(&[bad_size]);
+        let size = u32::from_le_bytes(record[4..8].try_into().unwrap()) + 4;
+        record[4..8].copy_from_slice(&size.to_le_bytes());
+        record.extend([0; 4]);
+        data.extend(record);
+        data.extend(blk_records(&[last]));
+        fs::write(dir.join("blk00000.dat"), data).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+
+        let errors = verify_errors(&dir, options);
+        let size_mismatch = format!("Decoded size {} does not match size {}", size - 4, size);
+        let causes = [
+            "Witness commitment does not match the transactions",
+            "Merkle root does not match the transactions",
+            &size_mismatch,
+        ];
+        assert_eq!(errors.len(), causes.len(), "{:?}", errors);
+        for ((header, error), (height, cause)) in errors.iter().zip((2..).zip(causes)) {
+            let expected = format!("Error parsing {}: {}", header, cause);
+            assert_eq!(error, &expected);
+            let location = format!(
+                "at height {} in {:?} at offset",
+                height,
+                dir.join("blk00000.dat")
+            );
+            assert!(error.contains(&location), "{}", error);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verifies_proof_of_work() {
+        let dir = temp_dir("verify-pow");
+        let mut blocks = regtest_chain(2);
+        // The block index does not check the proof-of-work of its headers
+        let mut bad_pow = mine(&blocks[1].header, 2);
+        bad_pow.header.bits = CompactTarget::from_consensus(0x1d00ffff);
+        blocks.push(bad_pow.clone());
+        blocks.push(mine(&bad_pow.header, 3));
+        write_index(&dir, &blocks);
+        let options = ParserOptions {
+            network: Network::Regtest,
+            block_index: true,
+            ..ParserOptions::default()
+        };
+
+        let errors = verify_errors(&dir, options);
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        let (header, error) = &errors[0];
+        assert!(header.contains("at height 2 in"), "{}", header);
+        let expected = format!(
+            "Error parsing {}: Block hash does not meet the target",
+            header
+        );
+        assert_eq!(error, &expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn returns_errors_from_try_next() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let source = MemoryBlocks::new(regtest_chain(10));
+        let parser = BlockParser::from_source(source, options).unwrap();
+        let fail_at_5 = |(height, _): (usize, ())| {
+            assert_ne!(height, 5, "boom");
+            height
+        };
+
+        let heights = parser.parse(|_| ()).with_height();
+        let mut iterator = heights.ordered().map_parallel(fail_at_5);
+        let mut results = vec![];
+        let err = loop {
+            match iterator.try_next() {
+                Ok(Some(height)) => results.push(height),
+                Ok(None) => panic!("Expected an error"),
+                Err(e) => break e,
+            }
+        };
+        assert!(
+            err.to_string().contains("Error mapping height 5"),
+            "{}",
+            err
+        );
+        assert!(results.len() < 10 && !results.contains(&5));
+
+        let heights = parser.parse(|_| ()).with_height();
+        let mut iterator = heights.ordered().pipeline_fn(fail_at_5, |height| height);
+        let mut results = std::iter::from_fn(|| iterator.try_next().transpose());
+        let err = results.find_map(Result::err).unwrap();
+        assert!(
+            err.to_string().contains("Error in pipeline at height 5"),
+            "{}",
+            err
+        );
+        assert_eq!(iterator.try_next().unwrap(), None);
+    }
+
+    #[test]
+    fn cancel_wakes_blocked_senders() {
+        let cancel = CancelHandle::default();
+        let (tx, rx) = bounded(0);
+        let sender = cancel.clone();
+        let received = thread::spawn(move || rx.recv());
+        assert!(sender.send(&tx, 1));
+        assert_eq!(received.join().unwrap(), Ok(1));
+
+        // Nothing receives from the channel so the send blocks until cancelled
+        let (tx, _rx) = bounded(0);
+        let blocked = thread::spawn(move || sender.send(&tx, 2));
+        thread::sleep(std::time::Duration::from_millis(50));
+        assert!(!blocked.is_finished());
+        cancel.cancel();
+        assert!(!blocked.join().unwrap());
+        assert!(!cancel.send(&bounded(1).0, 3));
+    }
+
+    #[test]
+    fn gets_blocks_in_active_chain() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let blocks = regtest_chain(5);
+        let stale = mine(&blocks[2].header, 30);
+        let source = MemoryBlocks::new(blocks.iter().chain([&stale]).cloned());
+        let parser = BlockParser::from_source(source, options).unwrap();
+
+        assert_eq!(
+            parser.get_block(&blocks[3].block_hash()).unwrap(),
+            blocks[3]
+        );
+        assert_eq!(parser.get_block_at_height(4).unwrap(), blocks[4]);
+        assert_eq!(parser.height_of(&blocks[2].block_hash()), Some(2));
+
+        let err = parser.get_block(&stale.block_hash()).unwrap_err();
+        let expected = format!("Block {} is not in the active chain", stale.block_hash());
+        assert_eq!(err.to_string(), expected);
+        let err = parser.get_block_at_height(5).unwrap_err();
+        assert_eq!(err.to_string(), "No block at height 5");
+    }
+
+    #[test]
+    fn parses_stale_branches() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let blocks = regtest_chain(7);
+        // Different coinbase heights give the stale blocks different hashes
+        let stale_1 = mine(&blocks[1].header, 20);
+        let stale_4 = mine(&blocks[3].header, 40);
+        let stale_5 = mine(&stale_4.header, 50);
+        let stale = [stale_1.clone(), stale_4.clone(), stale_5.clone()];
+        let source = MemoryBlocks::new(blocks.iter().chain(&stale).cloned());
+        let parser = BlockParser::from_source(source, options).unwrap();
+        assert_eq!(
+            parser.get_header_at_height(6).unwrap().hash,
+            blocks[6].block_hash()
+        );
+
+        let branches: Vec<_> = parser
+            .stale_branches()
+            .iter()
+            .map(|branch| {
+                let headers = branch.headers.iter().map(|h| (h.height, h.hash));
+                (branch.fork_height, headers.collect::<Vec<_>>())
+            })
+            .collect();
+        let expected = vec![
+            (1, vec![(2, stale_1.block_hash())]),
+            (
+                3,
+                vec![(4, stale_4.block_hash()), (5, stale_5.block_hash())],
+            ),
+        ];
+        assert_eq!(branches, expected);
+
+        let mut hashes: Vec<_> = parser.parse_stale(|block| block.block_hash()).collect();
+        hashes.sort();
+        let mut expected: Vec<_> = stale.iter().map(|block| block.block_hash()).collect();
+        expected.sort();
+        assert_eq!(hashes, expected);
+    }
+
+    #[test]
+    fn returns_missing_selections() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let blocks = regtest_chain(10);
+        let stale = mine(&blocks[3].header, 40);
+        let parser = BlockParser::from_source(MemoryBlocks::new(blocks.clone()), options).unwrap();
+
+        let parser = parser.heights([8, 12, 2, 8, 100]);
+        assert_eq!(parser.missing_heights(), &[12, 100]);
+        let heights: Vec<_> = parser
+            .parse(|_| ())
+            .with_height()
+            .ordered()
+            .map(|(h, _)| h)
+            .collect();
+        assert_eq!(heights, vec![8, 2]);
+
+        let parser = parser.hashes([stale.block_hash(), blocks[5].block_hash()]);
+        assert_eq!(parser.missing_hashes(), &[stale.block_hash()]);
+        assert!(parser.missing_heights().is_empty());
+        let hashes: Vec<_> = parser.parse(|block| block.block_hash()).collect();
+        assert_eq!(hashes, vec![blocks[5].block_hash()]);
+    }
+
+    #[test]
+    fn selects_times_by_median_time_past() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let mut blocks = regtest_chain(1);
+        let genesis_time = blocks[0].header.time;
+        for height in 1..25 {
+            let mut block = mine(&blocks[height - 1].header, height as i64);
+            block.header.time = genesis_time
+                + match height {
+                    // Jumps ahead then falls back behind the previous timestamps
+                    5 => 1200,
+                    14 => 900,
+                    _ => 100 * height as u32,
+                };
+            solve(&mut block);
+            blocks.push(block);
+        }
+        let times: Vec<u32> = blocks.iter().map(|block| block.header.time).collect();
+        let median_times: Vec<u32> = (0..times.len())
+            .map(|height| {
+                let mut recent = times[height.saturating_sub(10)..=height].to_vec();
+                recent.sort_unstable();
+                recent[recent.len() / 2]
+            })
+            .collect();
+        let parser = BlockParser::from_source(MemoryBlocks::new(blocks), options).unwrap();
+        let (start_time, end_time) = (genesis_time + 800, genesis_time + 1000);
+        let parser = parser.start_time(start_time).end_time(end_time);
+
+        let in_range = |time: &u32| (start_time..=end_time).contains(time);
+        let expected: Vec<_> = (0..times.len())
+            .filter(|height| in_range(&median_times[*height]))
+            .collect();
+        let by_time: Vec<_> = (0..times.len())
+            .filter(|height| in_range(&times[*height]))
+            .collect();
+        // Both edges differ from selecting by the raw block timestamps
+        assert_eq!(expected, (12..=16).collect::<Vec<_>>());
+        assert_eq!(by_time, vec![8, 9, 10, 14]);
+
+        let heights: Vec<_> = parser
+            .parse(|_| ())
+            .with_height()
+            .ordered()
+            .map(|(h, _)| h)
+            .collect();
+        assert_eq!(heights, expected);
+        let info: Vec<_> = parser
+            .header_info()
+            .map(|info| (info.height, info.median_time_past))
+            .collect();
+        let expected_info: Vec<_> = expected.iter().map(|h| (*h, median_times[*h])).collect();
+        assert_eq!(info, expected_info);
+
+        // An end time before any median-time-past selects nothing
+        let parser = parser.start_time(0).end_time(genesis_time - 1);
+        assert_eq!(parser.header_info().count(), 0);
+        assert_eq!(parser.parse(|_| ()).count(), 0);
+    }
+
+    #[test]
+    fn parses_with_context() {
+        let dir = temp_dir("context");
+        let blocks = regtest_chain(15);
+        fs::write(dir.join("blk00000.dat"), blk_records(&blocks)).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let parser = BlockParser::new_with_opts(dir.to_str().unwrap(), options).unwrap();
+        let parser = parser.start_height(3);
+
+        let contexts: Vec<_> = parser
+            .parse_with_context(|context, block| (context, block))
+            .ordered()
+            .collect();
+        assert_eq!(contexts.len(), 12);
+        let infos = parser.header_info();
+        // Each record is the magic and size followed by the block, whose transactions are after
+        // the 80 byte header
+        let records = blocks.iter().map(|block| 8 + block.total_size());
+        let mut offset = records.take(3).sum::<usize>() + 8 + 80;
+        for (((context, block), info), expected) in contexts.iter().zip(infos).zip(&blocks[3..]) {
+            assert_eq!(block, expected);
+            assert_eq!(context.height, info.height);
+            assert_eq!(context.hash, block.block_hash());
+            assert_eq!(context.median_time_past, info.median_time_past);
+            assert_eq!(context.chainwork, info.chainwork);
+            assert_eq!(context.size, block.total_size());
+            assert_eq!(context.path, dir.join("blk00000.dat"));
+            assert_eq!(context.offset, offset);
+            offset += 8 + block.total_size();
+        }
+        let times: Vec<_> = contexts[..3]
+            .iter()
+            .map(|(c, _)| c.median_time_past)
+            .collect();
+        let genesis_time = blocks[0].header.time;
+        // The median of the times of blocks 0..=3, 0..=4 and 0..=5 spaced 600 seconds apart
+        assert_eq!(
+            times,
+            vec![
+                genesis_time + 1200,
+                genesis_time + 1200,
+                genesis_time + 1800
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_transactions() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let blocks = spending_chain(8);
+        let parser = BlockParser::from_source(MemoryBlocks::new(blocks.clone()), options).unwrap();
+        let parser = parser.start_height(2);
+        let expected: Vec<_> = blocks
+            .iter()
+            .enumerate()
+            .skip(2)
+            .flat_map(|(height, block)| {
+                let txids = block.txdata.iter().map(Transaction::compute_txid);
+                TxContext::of_block(height, block.block_hash(), txids)
+            })
+            .collect();
+        assert_eq!(expected.len(), 15);
+
+        let contexts: Vec<_> = parser
+            .parse_transactions(|context, tx| {
+                assert_eq!(context.txid, tx.compute_txid());
+                assert_eq!(context.is_coinbase, tx.is_coinbase());
+                context
+            })
+            .ordered()
+            .collect();
+        assert_eq!(contexts, expected);
+        assert_eq!((contexts[0].height, contexts[0].index), (2, 0));
+        assert!(contexts[0].is_coinbase && !contexts[2].is_coinbase);
+
+        // Without ordering the transactions of each block are still returned together in order
+        let mut contexts: Vec<_> = parser.parse_transactions(|context, _| context).collect();
+        for pair in contexts.windows(2) {
+            match pair[0].block_hash == pair[1].block_hash {
+                true => assert_eq!(pair[0].index + 1, pair[1].index),
+                false => assert_eq!(pair[1].index, 0),
+            }
+        }
+        contexts.sort_by_key(|context| (context.height, context.index));
+        assert_eq!(contexts, expected);
+    }
+
+    #[test]
+    fn filters_headers() {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let blocks = regtest_chain(10);
+        let parser = BlockParser::from_source(MemoryBlocks::new(blocks.clone()), options).unwrap();
+        let parser = parser
+            .start_height(2)
+            .end_height(8)
+            .filter_headers(|header, height| {
+                assert_eq!(header.height, height);
+                height % 3 != 0
+            });
+
+        let heights: Vec<_> = parser
+            .parse(|_| ())
+            .with_height()
+            .ordered()
+            .map(|(h, _)| h)
+            .collect();
+        assert_eq!(heights, vec![2, 4, 5, 7, 8]);
+        let hashes: Vec<_> = parser.parse(|block| block.block_hash()).ordered().collect();
+        let expected: Vec<_> = [2, 4, 5, 7, 8]
+            .iter()
+            .map(|h| blocks[*h].block_hash())
+            .collect();
+        assert_eq!(hashes, expected);
+
+        // Filters apply to selected heights but header info still describes every block
+        let selected = parser.clone().heights([6, 5, 3, 2]);
+        let heights: Vec<_> = selected.parse(|_| ()).with_height().ordered().collect();
+        assert_eq!(heights, vec![(5, ()), (2, ())]);
+        let info: Vec<_> = parser.header_info().map(|info| info.height).collect();
+        assert_eq!(info, (2..=8).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,1217 @@
+//! Contains [`ElementsParser`] for parsing the blocks of Liquid and other Elements sidechains,
+//! which cannot be decoded into [`bitcoin::Block`].
+//!
+//! Elements headers are signed by the federation rather than mined, and transactions can hide
+//! their amounts and assets behind confidential commitments.  [`ConfidentialValue`] and
+//! [`ConfidentialAsset`] are explicit for unblinded outputs such as fees and peg-ins.
+
+use crate::blocks::{BlockParser, ParserIterator, ParserOptions};
+use crate::headers::ParsedHeader;
+use crate::params::ChainParams;
+use crate::source::{BlockSource, BlocksDir};
+use crate::xor::XorReader;
+use anyhow::{bail, Context, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::block::{Header, Version};
+use bitcoin::consensus::encode::Error;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::{hash_newtype, sha256, Hash};
+use bitcoin::io::{self, Read, Write};
+use bitcoin::merkle_tree;
+use bitcoin::{
+    BlockHash, CompactTarget, OutPoint, ScriptBuf, Sequence, TxMerkleNode, Txid, VarInt,
+};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+
+/// Version bit that indicates a dynamic federation header
+const DYNAFED_VERSION: u32 = 1 << 31;
+/// Outpoint index bit that indicates the input issues an asset
+const ISSUANCE_FLAG: u32 = 1 << 31;
+/// Outpoint index bit that indicates the input is a peg-in from bitcoin
+const PEGIN_FLAG: u32 = 1 << 30;
+/// Bits of the outpoint index that contain the output index
+const INDEX_MASK: u32 = 0x3fff_ffff;
+/// Target of the stand-in bitcoin headers, every signed block counts as the same amount of work
+const SIGNED_BLOCK_BITS: u32 = 0x207f_ffff;
+
+/// Multithreaded parser for [`ElementsBlock`], using the same [`ParserIterator`] as
+/// [`BlockParser`].
+///
+/// Uses [`ChainParams::liquid`] unless [`ParserOptions::chain_params`] is set to another Elements
+/// chain.  Headers are located with [`crate::HeaderParser`] so [`ParsedHeader::hash`] is the
+/// Elements block hash.
+///
+/// # Examples
+/// Summing the explicit L-BTC fees paid on Liquid:
+/// ```no_run
+/// use bitcoin_block_parser::elements::*;
+///
+/// let parser = ElementsParser::new("/home/user/.elements/liquidv1/blocks/").unwrap();
+/// let lbtc = AssetId::liquid_btc();
+/// let iterator = parser.parse(move |block| {
+///     let outputs = block.txdata.iter().flat_map(|tx| tx.output.iter());
+///     outputs
+///         .filter(|output| output.is_fee() && output.asset.explicit() == Some(lbtc))
+///         .filter_map(|output| output.value.explicit())
+///         .sum::<u64>()
+/// });
+/// println!("Total fees: {} sats", iterator.sum::<u64>());
+/// ```
+#[derive(Clone, Debug)]
+pub struct ElementsParser {
+    /// Locates the blocks and runs the threads
+    parser: BlockParser,
+}
+
+impl ElementsParser {
+    /// Creates a new parser given the `blocks` directory of a Liquid node.
+    ///
+    /// - Returns an `Err` if unable to parse the `blk` files.
+    pub fn new(blocks_dir: &str) -> Result<Self> {
+        Self::new_with_opts(blocks_dir, ParserOptions::default())
+    }
+
+    /// Creates a parser with custom [`ParserOptions`], defaulting to [`ChainParams::liquid`].
+    ///
+    /// - Returns an `Err` if the [`ParserOptions::chain_params`] are not for an Elements chain.
+    /// - Returns an `Err` unless the headers contain the block height and are signed like Liquid's,
+    ///   see [`ChainParams::height_in_header`] and [`ChainParams::signed_blocks`].  Other
+    ///   Elements chains, such as `elementsregtest` with its default settings, are not supported.
+    pub fn new_with_opts(blocks_dir: &str, mut options: ParserOptions) -> Result<Self> {
+        let params = options.chain_params.get_or_insert_with(ChainParams::liquid);
+        if !params.elements {
+            bail!("{} is not an Elements chain", params.name);
+        }
+        params.check_elements_headers()?;
+        let source = BlocksDir::new(blocks_dir);
+        let chain = source.chain(&options)?;
+        Ok(Self {
+            parser: BlockParser::from_chain(Arc::new(source), chain, options),
+        })
+    }
+
+    /// Sets the *inclusive* start of block heights to parse.
+    pub fn start_height(mut self, start_height: usize) -> Self {
+        self.parser = self.parser.start_height(start_height);
+        self
+    }
+
+    /// Sets the *inclusive* end of block heights to parse.
+    pub fn end_height(mut self, end_height: usize) -> Self {
+        self.parser = self.parser.end_height(end_height);
+        self
+    }
+
+    /// Sets the *inclusive* start of block times to parse, see [`BlockParser::start_time`].
+    pub fn start_time(mut self, start_time: u32) -> Self {
+        self.parser = self.parser.start_time(start_time);
+        self
+    }
+
+    /// Sets the *inclusive* end of block times to parse, see [`BlockParser::end_time`].
+    pub fn end_time(mut self, end_time: u32) -> Self {
+        self.parser = self.parser.end_time(end_time);
+        self
+    }
+
+    /// Selects specific blocks to parse by their heights, see [`BlockParser::heights`].
+    pub fn heights(mut self, heights: impl IntoIterator<Item = usize>) -> Self {
+        self.parser = self.parser.heights(heights);
+        self
+    }
+
+    /// Selects specific blocks to parse by their hashes, see [`BlockParser::hashes`].
+    pub fn hashes(mut self, hashes: impl IntoIterator<Item = BlockHash>) -> Self {
+        self.parser = self.parser.hashes(hashes);
+        self
+    }
+
+    /// Returns the selected heights that are not in the active chain, see
+    /// [`BlockParser::missing_heights`].
+    pub fn missing_heights(&self) -> &[usize] {
+        self.parser.missing_heights()
+    }
+
+    /// Returns the selected hashes that are not in the active chain, see
+    /// [`BlockParser::missing_hashes`].
+    pub fn missing_hashes(&self) -> &[BlockHash] {
+        self.parser.missing_hashes()
+    }
+
+    /// Only parses the blocks where `filter` returns true, see [`BlockParser::filter_headers`].
+    pub fn filter_headers(
+        mut self,
+        filter: impl Fn(&ParsedHeader, usize) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.parser = self.parser.filter_headers(filter);
+        self
+    }
+
+    /// Returns the [`ParsedHeader`] of the block in the active chain at `height`.
+    pub fn get_header_at_height(&self, height: usize) -> Option<&ParsedHeader> {
+        self.parser.get_header_at_height(height)
+    }
+
+    /// Reads a single [`ElementsBlock`] in the active chain given its hash.
+    ///
+    /// - Returns an `Err` if the block is not in the active chain or cannot be read.
+    pub fn get_block(&self, hash: &BlockHash) -> Result<ElementsBlock> {
+        match self.parser.get_header(hash) {
+            Some(header) => {
+                read_elements_block(header).with_context(|| format!("Error reading {}", header))
+            }
+            None => bail!("Block {} is not in the active chain", hash),
+        }
+    }
+
+    /// Parse all [`ElementsBlock`] into type `T` and return a [`ParserIterator<T>`].  Results will
+    /// be in random order due to multithreading.
+    ///
+    /// Panics on the calling thread if a block cannot be read, see [`ElementsParser::try_parse`].
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse<T: Send + 'static>(
+        &self,
+        extract: impl Fn(ElementsBlock) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<T> {
+        self.parser.parse_with(read_elements_block, extract)
+    }
+
+    /// Parse all [`ElementsBlock`] into type `T`, returning an `Err` for every block that cannot
+    /// be read or where `extract` panics.
+    pub fn try_parse<T: Send + 'static>(
+        &self,
+        extract: impl Fn(ElementsBlock) -> T + Clone + Send + 'static,
+    ) -> ParserIterator<Result<T>> {
+        self.parser.try_parse_with(read_elements_block, extract)
+    }
+}
+
+/// Reads the [`ElementsBlock`] that starts at [`ParsedHeader::header_offset`], since the
+/// transactions cannot be decoded without the full Elements header.
+pub(crate) fn read_elements_block(header: &ParsedHeader) -> Result<ElementsBlock> {
+    let reader = BufReader::new(File::open(&header.path)?);
+    let mut reader = BufReader::new(XorReader::new(reader, header.xor_mask));
+    reader.seek_relative(header.header_offset as i64)?;
+    let block = ElementsBlock::consensus_decode(&mut reader)?;
+    if block.block_hash() != header.hash {
+        bail!(
+            "Expected block {} but read {}",
+            header.hash,
+            block.block_hash()
+        );
+    }
+    Ok(block)
+}
+
+/// An Elements block, containing the header and transactions.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementsBlock {
+    /// The signed block header
+    pub header: ElementsHeader,
+    /// The transactions, starting with the coinbase
+    pub txdata: Vec<ElementsTransaction>,
+}
+
+impl ElementsBlock {
+    /// Returns the hash of the block header.
+    pub fn block_hash(&self) -> BlockHash {
+        self.header.block_hash()
+    }
+
+    /// Returns true if the merkle root in the header commits to the transactions.
+    pub fn check_merkle_root(&self) -> bool {
+        let txids = self.txdata.iter().map(|tx| tx.txid().to_raw_hash());
+        match merkle_tree::calculate_root(txids) {
+            Some(root) => TxMerkleNode::from_raw_hash(root) == self.header.merkle_root,
+            None => false,
+        }
+    }
+}
+
+impl Decodable for ElementsBlock {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            header: ElementsHeader::consensus_decode(reader)?,
+            txdata: decode_vec(reader)?,
+        })
+    }
+}
+
+impl Encodable for ElementsBlock {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.header.consensus_encode(writer)? + encode_vec(&self.txdata, writer)?)
+    }
+}
+
+/// An Elements block header, which replaces the proof-of-work with a signature from the
+/// federation.
+///
+/// Decoded in the format Liquid uses, with the block height and a signed block proof.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementsHeader {
+    /// Block version, without the dynamic federation bit
+    pub version: i32,
+    /// Hash of the previous block
+    pub prev_blockhash: BlockHash,
+    /// Root of the merkle tree of transactions
+    pub merkle_root: TxMerkleNode,
+    /// Block timestamp
+    pub time: u32,
+    /// Height of the block, which Elements commits to in the header
+    pub height: u32,
+    /// The signing rules and signature of the block
+    pub ext: HeaderExt,
+}
+
+impl ElementsHeader {
+    /// Returns the block hash, which commits to everything except the signatures.
+    pub fn block_hash(&self) -> BlockHash {
+        let mut engine = BlockHash::engine();
+        self.encode(&mut engine, false)
+            .expect("engines don't error");
+        BlockHash::from_engine(engine)
+    }
+
+    /// Returns true if the header uses the dynamic federation format.
+    pub fn is_dynafed(&self) -> bool {
+        matches!(self.ext, HeaderExt::Dynafed { .. })
+    }
+
+    /// Encodes the header, with or without the signatures.
+    fn encode<W: Write + ?Sized>(
+        &self,
+        writer: &mut W,
+        signatures: bool,
+    ) -> Result<usize, io::Error> {
+        let mut version = self.version;
+        if self.is_dynafed() {
+            version |= DYNAFED_VERSION as i32;
+        }
+        let mut len = version.consensus_encode(writer)?;
+        len += self.prev_blockhash.consensus_encode(writer)?;
+        len += self.merkle_root.consensus_encode(writer)?;
+        len += self.time.consensus_encode(writer)?;
+        len += self.height.consensus_encode(writer)?;
+        match &self.ext {
+            HeaderExt::Proof {
+                challenge,
+                solution,
+            } => {
+                len += challenge.consensus_encode(writer)?;
+                if signatures {
+                    len += solution.consensus_encode(writer)?;
+                }
+            }
+            HeaderExt::Dynafed {
+                current,
+                proposed,
+                signblock_witness,
+            } => {
+                len += current.consensus_encode(writer)?;
+                len += proposed.consensus_encode(writer)?;
+                if signatures {
+                    len += signblock_witness.consensus_encode(writer)?;
+                }
+            }
+        }
+        Ok(len)
+    }
+
+    /// Returns a [`bitcoin::block::Header`] with the same version, previous hash, merkle root and
+    /// time, used for resolving the chain.
+    ///
+    /// The stand-in has no proof-of-work, every header is given the same target so the longest
+    /// chain has the most chainwork.
+    pub(crate) fn to_bitcoin(&self) -> Header {
+        Header {
+            version: Version::from_consensus(self.version),
+            prev_blockhash: self.prev_blockhash,
+            merkle_root: self.merkle_root,
+            time: self.time,
+            bits: CompactTarget::from_consensus(SIGNED_BLOCK_BITS),
+            nonce: 0,
+        }
+    }
+}
+
+impl Decodable for ElementsHeader {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let version = u32::consensus_decode(reader)?;
+        let prev_blockhash = BlockHash::consensus_decode(reader)?;
+        let merkle_root = TxMerkleNode::consensus_decode(reader)?;
+        let time = u32::consensus_decode(reader)?;
+        let height = u32::consensus_decode(reader)?;
+        let ext = if version & DYNAFED_VERSION != 0 {
+            HeaderExt::Dynafed {
+                current: DynafedParams::consensus_decode(reader)?,
+                proposed: DynafedParams::consensus_decode(reader)?,
+                signblock_witness: Vec::<Vec<u8>>::consensus_decode(reader)?,
+            }
+        } else {
+            HeaderExt::Proof {
+                challenge: ScriptBuf::consensus_decode(reader)?,
+                solution: ScriptBuf::consensus_decode(reader)?,
+            }
+        };
+        Ok(Self {
+            version: (version & !DYNAFED_VERSION) as i32,
+            prev_blockhash,
+            merkle_root,
+            time,
+            height,
+            ext,
+        })
+    }
+}
+
+impl Encodable for ElementsHeader {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        self.encode(writer, true)
+    }
+}
+
+/// How an [`ElementsHeader`] is signed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HeaderExt {
+    /// Headers from before dynamic federations activated
+    Proof {
+        /// Script the block must satisfy
+        challenge: ScriptBuf,
+        /// Satisfies the challenge, not included in the block hash
+        solution: ScriptBuf,
+    },
+    /// Headers that can change the federation with [`DynafedParams`]
+    Dynafed {
+        /// Parameters in effect for the block
+        current: DynafedParams,
+        /// Parameters the block votes for
+        proposed: DynafedParams,
+        /// Satisfies the current `signblockscript`, not included in the block hash
+        signblock_witness: Vec<Vec<u8>>,
+    },
+}
+
+/// Dynamic federation parameters of an [`ElementsHeader`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DynafedParams {
+    /// No parameters, used when the block does not propose any
+    Null,
+    /// Parameters with the peg-in fields replaced by their merkle root
+    Compact {
+        /// Script the block must satisfy
+        signblockscript: ScriptBuf,
+        /// Maximum size of the `signblock_witness`
+        signblock_witness_limit: u32,
+        /// Merkle root of the elided fields
+        elided_root: [u8; 32],
+    },
+    /// Parameters including the peg-in fields, used at the start of each epoch
+    Full {
+        /// Script the block must satisfy
+        signblockscript: ScriptBuf,
+        /// Maximum size of the `signblock_witness`
+        signblock_witness_limit: u32,
+        /// Script that peg-ins on bitcoin must pay to
+        fedpeg_program: ScriptBuf,
+        /// Script of the federation that controls the peg
+        fedpegscript: Vec<u8>,
+        /// Additional parameters such as the PAK list
+        extension_space: Vec<Vec<u8>>,
+    },
+}
+
+impl Decodable for DynafedParams {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        match u8::consensus_decode(reader)? {
+            0 => Ok(DynafedParams::Null),
+            1 => Ok(DynafedParams::Compact {
+                signblockscript: ScriptBuf::consensus_decode(reader)?,
+                signblock_witness_limit: u32::consensus_decode(reader)?,
+                elided_root: <[u8; 32]>::consensus_decode(reader)?,
+            }),
+            2 => Ok(DynafedParams::Full {
+                signblockscript: ScriptBuf::consensus_decode(reader)?,
+                signblock_witness_limit: u32::consensus_decode(reader)?,
+                fedpeg_program: ScriptBuf::consensus_decode(reader)?,
+                fedpegscript: Vec::<u8>::consensus_decode(reader)?,
+                extension_space: Vec::<Vec<u8>>::consensus_decode(reader)?,
+            }),
+            _ => Err(Error::ParseFailed("invalid dynafed params type")),
+        }
+    }
+}
+
+impl Encodable for DynafedParams {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        match self {
+            DynafedParams::Null => 0u8.consensus_encode(writer),
+            DynafedParams::Compact {
+                signblockscript,
+                signblock_witness_limit,
+                elided_root,
+            } => Ok(1u8.consensus_encode(writer)?
+                + signblockscript.consensus_encode(writer)?
+                + signblock_witness_limit.consensus_encode(writer)?
+                + elided_root.consensus_encode(writer)?),
+            DynafedParams::Full {
+                signblockscript,
+                signblock_witness_limit,
+                fedpeg_program,
+                fedpegscript,
+                extension_space,
+            } => Ok(2u8.consensus_encode(writer)?
+                + signblockscript.consensus_encode(writer)?
+                + signblock_witness_limit.consensus_encode(writer)?
+                + fedpeg_program.consensus_encode(writer)?
+                + fedpegscript.consensus_encode(writer)?
+                + extension_space.consensus_encode(writer)?),
+        }
+    }
+}
+
+/// An Elements transaction, which has a flag byte instead of the segwit marker and witnesses for
+/// both inputs and outputs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementsTransaction {
+    /// Transaction version
+    pub version: u32,
+    /// The inputs, including any peg-ins and asset issuances
+    pub input: Vec<ElementsTxIn>,
+    /// The outputs, including the explicit fee output
+    pub output: Vec<ElementsTxOut>,
+    /// Block height or time the transaction is valid from
+    pub lock_time: LockTime,
+}
+
+impl ElementsTransaction {
+    /// Computes the transaction id, which does not commit to the witnesses.
+    pub fn txid(&self) -> Txid {
+        let mut engine = Txid::engine();
+        self.encode(&mut engine, false)
+            .expect("engines don't error");
+        Txid::from_engine(engine)
+    }
+
+    /// Returns true if this is the coinbase transaction of the block.
+    pub fn is_coinbase(&self) -> bool {
+        self.input.len() == 1 && self.input[0].previous_output.is_null()
+    }
+
+    /// Size of the transaction in bytes including witnesses.
+    pub fn total_size(&self) -> usize {
+        self.consensus_encode(&mut io::sink())
+            .expect("sinks don't error")
+    }
+
+    /// Returns true if any input or output has witness data.
+    fn has_witness(&self) -> bool {
+        self.input.iter().any(|input| !input.witness.is_empty())
+            || self.output.iter().any(|output| !output.witness.is_empty())
+    }
+
+    /// Encodes the transaction, with or without the witnesses.
+    fn encode<W: Write + ?Sized>(&self, writer: &mut W, witness: bool) -> Result<usize, io::Error> {
+        let mut len = self.version.consensus_encode(writer)?;
+        len += (witness as u8).consensus_encode(writer)?;
+        len += encode_vec(&self.input, writer)?;
+        len += encode_vec(&self.output, writer)?;
+        len += self.lock_time.consensus_encode(writer)?;
+        if witness {
+            for input in &self.input {
+                len += input.witness.consensus_encode(writer)?;
+            }
+            for output in &self.output {
+                len += output.witness.consensus_encode(writer)?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for ElementsTransaction {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let version = u32::consensus_decode(reader)?;
+        let flags = u8::consensus_decode(reader)?;
+        let mut input: Vec<ElementsTxIn> = decode_vec(reader)?;
+        let mut output: Vec<ElementsTxOut> = decode_vec(reader)?;
+        let lock_time = LockTime::consensus_decode(reader)?;
+        match flags {
+            0 => {}
+            1 => {
+                for input in input.iter_mut() {
+                    input.witness = InputWitness::consensus_decode(reader)?;
+                }
+                for output in output.iter_mut() {
+                    output.witness = OutputWitness::consensus_decode(reader)?;
+                }
+            }
+            _ => return Err(Error::ParseFailed("invalid transaction flags")),
+        }
+        Ok(Self {
+            version,
+            input,
+            output,
+            lock_time,
+        })
+    }
+}
+
+impl Encodable for ElementsTransaction {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        self.encode(writer, self.has_witness())
+    }
+}
+
+/// An input of an [`ElementsTransaction`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementsTxIn {
+    /// The output being spent, which is on bitcoin for a peg-in
+    pub previous_output: OutPoint,
+    /// Whether the input claims bitcoin that was sent to the federation
+    pub is_pegin: bool,
+    /// Script that satisfies the spent output
+    pub script_sig: ScriptBuf,
+    /// Sequence number of the input
+    pub sequence: Sequence,
+    /// New asset or reissuance created by the input
+    pub asset_issuance: Option<AssetIssuance>,
+    /// Witness data, empty if the transaction has none
+    pub witness: InputWitness,
+}
+
+impl Decodable for ElementsTxIn {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let mut previous_output = OutPoint::consensus_decode(reader)?;
+        let (mut is_issuance, mut is_pegin) = (false, false);
+        // The flags are stored in the output index, except in the coinbase
+        if previous_output.vout != u32::MAX {
+            is_issuance = previous_output.vout & ISSUANCE_FLAG != 0;
+            is_pegin = previous_output.vout & PEGIN_FLAG != 0;
+            previous_output.vout &= INDEX_MASK;
+        }
+        let script_sig = ScriptBuf::consensus_decode(reader)?;
+        let sequence = Sequence::consensus_decode(reader)?;
+        let asset_issuance = match is_issuance {
+            true => Some(AssetIssuance::consensus_decode(reader)?),
+            false => None,
+        };
+        Ok(Self {
+            previous_output,
+            is_pegin,
+            script_sig,
+            sequence,
+            asset_issuance,
+            witness: InputWitness::default(),
+        })
+    }
+}
+
+impl Encodable for ElementsTxIn {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        let mut previous_output = self.previous_output;
+        if !previous_output.is_null() {
+            previous_output.vout |= match self.asset_issuance {
+                Some(_) => ISSUANCE_FLAG,
+                None => 0,
+            };
+            previous_output.vout |= match self.is_pegin {
+                true => PEGIN_FLAG,
+                false => 0,
+            };
+        }
+        let mut len = previous_output.consensus_encode(writer)?;
+        len += self.script_sig.consensus_encode(writer)?;
+        len += self.sequence.consensus_encode(writer)?;
+        if let Some(issuance) = &self.asset_issuance {
+            len += issuance.consensus_encode(writer)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Issuance or reissuance of an asset by an [`ElementsTxIn`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssetIssuance {
+    /// Zero for a new issuance, otherwise the blinding factor of the reissuance token
+    pub asset_blinding_nonce: [u8; 32],
+    /// Contract hash for a new issuance, otherwise the entropy of the asset being reissued
+    pub asset_entropy: [u8; 32],
+    /// Amount of the asset issued
+    pub amount: ConfidentialValue,
+    /// Amount of reissuance tokens issued
+    pub inflation_keys: ConfidentialValue,
+}
+
+impl Decodable for AssetIssuance {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            asset_blinding_nonce: <[u8; 32]>::consensus_decode(reader)?,
+            asset_entropy: <[u8; 32]>::consensus_decode(reader)?,
+            amount: ConfidentialValue::consensus_decode(reader)?,
+            inflation_keys: ConfidentialValue::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for AssetIssuance {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.asset_blinding_nonce.consensus_encode(writer)?
+            + self.asset_entropy.consensus_encode(writer)?
+            + self.amount.consensus_encode(writer)?
+            + self.inflation_keys.consensus_encode(writer)?)
+    }
+}
+
+/// Witness data of an [`ElementsTxIn`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputWitness {
+    /// Rangeproof of a confidential issuance amount
+    pub amount_rangeproof: Vec<u8>,
+    /// Rangeproof of a confidential reissuance token amount
+    pub inflation_keys_rangeproof: Vec<u8>,
+    /// Segwit script witness
+    pub script_witness: Vec<Vec<u8>>,
+    /// Proof of the bitcoin transaction for a peg-in
+    pub pegin_witness: Vec<Vec<u8>>,
+}
+
+impl InputWitness {
+    /// Returns true if the witness has no data.
+    pub fn is_empty(&self) -> bool {
+        self.amount_rangeproof.is_empty()
+            && self.inflation_keys_rangeproof.is_empty()
+            && self.script_witness.is_empty()
+            && self.pegin_witness.is_empty()
+    }
+}
+
+impl Decodable for InputWitness {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            amount_rangeproof: Vec::<u8>::consensus_decode(reader)?,
+            inflation_keys_rangeproof: Vec::<u8>::consensus_decode(reader)?,
+            script_witness: Vec::<Vec<u8>>::consensus_decode(reader)?,
+            pegin_witness: Vec::<Vec<u8>>::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for InputWitness {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.amount_rangeproof.consensus_encode(writer)?
+            + self.inflation_keys_rangeproof.consensus_encode(writer)?
+            + self.script_witness.consensus_encode(writer)?
+            + self.pegin_witness.consensus_encode(writer)?)
+    }
+}
+
+/// An output of an [`ElementsTransaction`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementsTxOut {
+    /// Asset of the output
+    pub asset: ConfidentialAsset,
+    /// Amount of the asset
+    pub value: ConfidentialValue,
+    /// Nonce used to unblind a confidential output
+    pub nonce: ConfidentialNonce,
+    /// Script that must be satisfied to spend the output, empty for the fee output
+    pub script_pubkey: ScriptBuf,
+    /// Witness data, empty if the transaction has none
+    pub witness: OutputWitness,
+}
+
+impl ElementsTxOut {
+    /// Returns true if the output pays the transaction fee.
+    pub fn is_fee(&self) -> bool {
+        self.script_pubkey.is_empty()
+            && self.value.explicit().is_some()
+            && self.asset.explicit().is_some()
+    }
+}
+
+impl Decodable for ElementsTxOut {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            asset: ConfidentialAsset::consensus_decode(reader)?,
+            value: ConfidentialValue::consensus_decode(reader)?,
+            nonce: ConfidentialNonce::consensus_decode(reader)?,
+            script_pubkey: ScriptBuf::consensus_decode(reader)?,
+            witness: OutputWitness::default(),
+        })
+    }
+}
+
+impl Encodable for ElementsTxOut {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.asset.consensus_encode(writer)?
+            + self.value.consensus_encode(writer)?
+            + self.nonce.consensus_encode(writer)?
+            + self.script_pubkey.consensus_encode(writer)?)
+    }
+}
+
+/// Witness data of an [`ElementsTxOut`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OutputWitness {
+    /// Proves the blinded asset is one of the input assets
+    pub surjection_proof: Vec<u8>,
+    /// Proves the blinded value is in range
+    pub rangeproof: Vec<u8>,
+}
+
+impl OutputWitness {
+    /// Returns true if the witness has no data.
+    pub fn is_empty(&self) -> bool {
+        self.surjection_proof.is_empty() && self.rangeproof.is_empty()
+    }
+}
+
+impl Decodable for OutputWitness {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        Ok(Self {
+            surjection_proof: Vec::<u8>::consensus_decode(reader)?,
+            rangeproof: Vec::<u8>::consensus_decode(reader)?,
+        })
+    }
+}
+
+impl Encodable for OutputWitness {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.surjection_proof.consensus_encode(writer)?
+            + self.rangeproof.consensus_encode(writer)?)
+    }
+}
+
+hash_newtype! {
+    /// Identifies an asset, displayed in reverse byte order like a txid.
+    #[hash_newtype(backward)]
+    pub struct AssetId(sha256::Hash);
+}
+
+impl AssetId {
+    /// The asset id of L-BTC, the pegged bitcoin on Liquid.
+    pub fn liquid_btc() -> Self {
+        "6f0279e9ed041c3d710a9f57d0c02928416460c4b722ae3457a11eec381c526d"
+            .parse()
+            .expect("valid asset id")
+    }
+}
+
+/// Amount of an [`ElementsTxOut`] or [`AssetIssuance`], which may be hidden by a commitment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfidentialValue {
+    /// No value
+    Null,
+    /// Amount in satoshis
+    Explicit(u64),
+    /// Pedersen commitment to the amount
+    Confidential([u8; 33]),
+}
+
+impl ConfidentialValue {
+    /// Returns the amount if it is explicit.
+    pub fn explicit(&self) -> Option<u64> {
+        match self {
+            ConfidentialValue::Explicit(value) => Some(*value),
+            _ => None,
+        }
+    }
+}
+
+impl Decodable for ConfidentialValue {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        match u8::consensus_decode(reader)? {
+            0 => Ok(ConfidentialValue::Null),
+            // Explicit values are big-endian
+            1 => Ok(ConfidentialValue::Explicit(u64::from_be_bytes(
+                <[u8; 8]>::consensus_decode(reader)?,
+            ))),
+            prefix @ (8 | 9) => Ok(ConfidentialValue::Confidential(decode_commitment(
+                prefix, reader,
+            )?)),
+            _ => Err(Error::ParseFailed("invalid confidential value prefix")),
+        }
+    }
+}
+
+impl Encodable for ConfidentialValue {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        match self {
+            ConfidentialValue::Null => 0u8.consensus_encode(writer),
+            ConfidentialValue::Explicit(value) => {
+                Ok(1u8.consensus_encode(writer)? + value.to_be_bytes().consensus_encode(writer)?)
+            }
+            ConfidentialValue::Confidential(commitment) => commitment.consensus_encode(writer),
+        }
+    }
+}
+
+/// Asset of an [`ElementsTxOut`], which may be hidden by a commitment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfidentialAsset {
+    /// No asset
+    Null,
+    /// The asset id
+    Explicit(AssetId),
+    /// Blinded generator of the asset
+    Confidential([u8; 33]),
+}
+
+impl ConfidentialAsset {
+    /// Returns the asset id if it is explicit.
+    pub fn explicit(&self) -> Option<AssetId> {
+        match self {
+            ConfidentialAsset::Explicit(asset) => Some(*asset),
+            _ => None,
+        }
+    }
+}
+
+impl Decodable for ConfidentialAsset {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        match u8::consensus_decode(reader)? {
+            0 => Ok(ConfidentialAsset::Null),
+            1 => Ok(ConfidentialAsset::Explicit(AssetId::from_byte_array(
+                <[u8; 32]>::consensus_decode(reader)?,
+            ))),
+            prefix @ (10 | 11) => Ok(ConfidentialAsset::Confidential(decode_commitment(
+                prefix, reader,
+            )?)),
+            _ => Err(Error::ParseFailed("invalid confidential asset prefix")),
+        }
+    }
+}
+
+impl Encodable for ConfidentialAsset {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        match self {
+            ConfidentialAsset::Null => 0u8.consensus_encode(writer),
+            ConfidentialAsset::Explicit(asset) => {
+                Ok(1u8.consensus_encode(writer)?
+                    + asset.to_byte_array().consensus_encode(writer)?)
+            }
+            ConfidentialAsset::Confidential(commitment) => commitment.consensus_encode(writer),
+        }
+    }
+}
+
+/// Nonce of an [`ElementsTxOut`], usually the ephemeral key used to blind the output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfidentialNonce {
+    /// No nonce
+    Null,
+    /// Explicit nonce
+    Explicit([u8; 32]),
+    /// Public key shared with the receiver
+    Confidential([u8; 33]),
+}
+
+impl Decodable for ConfidentialNonce {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        match u8::consensus_decode(reader)? {
+            0 => Ok(ConfidentialNonce::Null),
+            1 => Ok(ConfidentialNonce::Explicit(<[u8; 32]>::consensus_decode(
+                reader,
+            )?)),
+            prefix @ (2 | 3) => Ok(ConfidentialNonce::Confidential(decode_commitment(
+                prefix, reader,
+            )?)),
+            _ => Err(Error::ParseFailed("invalid confidential nonce prefix")),
+        }
+    }
+}
+
+impl Encodable for ConfidentialNonce {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        match self {
+            ConfidentialNonce::Null => 0u8.consensus_encode(writer),
+            ConfidentialNonce::Explicit(nonce) => {
+                Ok(1u8.consensus_encode(writer)? + nonce.consensus_encode(writer)?)
+            }
+            ConfidentialNonce::Confidential(commitment) => commitment.consensus_encode(writer),
+        }
+    }
+}
+
+/// Reads the 32 bytes that follow the `prefix` of a commitment, returning all 33 bytes.
+fn decode_commitment<R: Read + ?Sized>(prefix: u8, reader: &mut R) -> Result<[u8; 33], Error> {
+    let mut commitment = [prefix; 33];
+    reader.read_exact(&mut commitment[1..])?;
+    Ok(commitment)
+}
+
+/// Decodes a vector of items prefixed by their count.
+fn decode_vec<T: Decodable, R: Read + ?Sized>(reader: &mut R) -> Result<Vec<T>, Error> {
+    let len = VarInt::consensus_decode(reader)?.0 as usize;
+    // Avoid allocating a large amount of memory if the count is corrupted
+    let mut items = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        items.push(T::consensus_decode(reader)?);
+    }
+    Ok(items)
+}
+
+/// Encodes a vector of items prefixed by their count.
+fn encode_vec<T: Encodable, W: Write + ?Sized>(
+    items: &[T],
+    writer: &mut W,
+) -> Result<usize, io::Error> {
+    let mut len = VarInt(items.len() as u64).consensus_encode(writer)?;
+    for item in items {
+        len += item.consensus_encode(writer)?;
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leveldb::tests::temp_dir;
+    use crate::source::{BlockSource, RawBlockFiles};
+    use crate::HeaderParser;
+    use bitcoin::consensus::{deserialize, serialize};
+    use bitcoin::hashes::sha256d;
+    use std::fs;
+
+    /// Returns the double SHA256 of `data`, the hash used for block hashes and txids.
+    fn sha256d(data: &[u8]) -> sha256d::Hash {
+        sha256d::Hash::hash(data)
+    }
+
+    #[test]
+    fn hashes_headers_without_signatures() {
+        let mut raw = vec![];
+        raw.extend(0x2000_0000u32.to_le_bytes());
+        raw.extend([0x11; 32]);
+        raw.extend([0x22; 32]);
+        raw.extend(1_600_000_000u32.to_le_bytes());
+        raw.extend(7u32.to_le_bytes());
+        // Challenge of OP_TRUE followed by the solution
+        raw.extend([0x01, 0x51]);
+        let hashed = raw.len();
+        raw.extend([0x02, 0x00, 0x00]);
+
+        let header: ElementsHeader = deserialize(&raw).unwrap();
+        assert_eq!(header.version, 0x2000_0000);
+        assert_eq!(header.height, 7);
+        assert!(!header.is_dynafed());
+        let expected = BlockHash::from_raw_hash(sha256d(&raw[..hashed]));
+        assert_eq!(header.block_hash(), expected);
+        assert_eq!(serialize(&header), raw);
+
+        let mut raw = vec![];
+        raw.extend((DYNAFED_VERSION | 0x2000_0000).to_le_bytes());
+        raw.extend([0x11; 32]);
+        raw.extend([0x22; 32]);
+        raw.extend(1_600_000_000u32.to_le_bytes());
+        raw.extend(8u32.to_le_bytes());
+        // Compact current params of OP_TRUE with a witness limit and elided root, null proposed
+        raw.extend([0x01, 0x01, 0x51]);
+        raw.extend(73u32.to_le_bytes());
+        raw.extend([0x33; 32]);
+        raw.push(0x00);
+        let hashed = raw.len();
+        raw.extend([0x01, 0x02, 0xaa, 0xbb]);
+
+        let header: ElementsHeader = deserialize(&raw).unwrap();
+        assert_eq!(header.version, 0x2000_0000);
+        assert!(header.is_dynafed());
+        let HeaderExt::Dynafed {
+            current,
+            proposed,
+            signblock_witness,
+        } = &header.ext
+        else {
+            panic!("Expected a dynafed header")
+        };
+        assert!(matches!(
+            current,
+            DynafedParams::Compact {
+                signblock_witness_limit: 73,
+                ..
+            }
+        ));
+        assert_eq!(proposed, &DynafedParams::Null);
+        assert_eq!(signblock_witness, &vec![vec![0xaa, 0xbb]]);
+        let expected = BlockHash::from_raw_hash(sha256d(&raw[..hashed]));
+        assert_eq!(header.block_hash(), expected);
+        assert_eq!(serialize(&header), raw);
+    }
+
+    #[test]
+    fn txid_ignores_witnesses() {
+        let lbtc = AssetId::liquid_btc();
+        let mut raw = vec![];
+        raw.extend(2u32.to_le_bytes());
+        raw.push(0x01);
+        // A peg-in input spending output 1
+        raw.push(0x01);
+        raw.extend([0x44; 32]);
+        raw.extend((1 | PEGIN_FLAG).to_le_bytes());
+        raw.push(0x00);
+        raw.extend(u32::MAX.to_le_bytes());
+        // An explicit L-BTC output and a confidential output
+        raw.push(0x02);
+        raw.push(0x01);
+        raw.extend(lbtc.to_byte_array());
+        raw.push(0x01);
+        raw.extend(1000u64.to_be_bytes());
+        raw.extend([0x00, 0x01, 0x51]);
+        raw.push(0x0a);
+        raw.extend([0x55; 32]);
+        raw.push(0x08);
+        raw.extend([0x66; 32]);
+        raw.push(0x02);
+        raw.extend([0x77; 32]);
+        raw.extend([0x01, 0x51]);
+        raw.extend(0u32.to_le_bytes());
+        let unsigned = raw.len();
+        // Input witness with a script witness and peg-in proof, then both output witnesses
+        raw.extend([0x00, 0x00, 0x01, 0x01, 0xab, 0x01, 0x01, 0xcd]);
+        raw.extend([0x00, 0x00, 0x02, 0x01, 0x02, 0x01, 0x03]);
+
+        let tx: ElementsTransaction = deserialize(&raw).unwrap();
+        assert!(tx.input[0].is_pegin);
+        assert_eq!(tx.input[0].previous_output.vout, 1);
+        assert_eq!(tx.input[0].witness.script_witness, vec![vec![0xab]]);
+        assert_eq!(tx.output[0].asset.explicit(), Some(lbtc));
+        assert_eq!(tx.output[0].value.explicit(), Some(1000));
+        let mut commitment = [0x66; 33];
+        commitment[0] = 0x08;
+        assert_eq!(
+            tx.output[1].value,
+            ConfidentialValue::Confidential(commitment)
+        );
+        assert_eq!(tx.output[1].witness.rangeproof, vec![0x03]);
+        assert_eq!(serialize(&tx), raw);
+        assert_eq!(tx.total_size(), raw.len());
+
+        let mut stripped = raw[..unsigned].to_vec();
+        stripped[4] = 0x00;
+        assert_eq!(tx.txid(), Txid::from_raw_hash(sha256d(&stripped)));
+        assert_ne!(tx.txid(), Txid::from_raw_hash(sha256d(&raw)));
+
+        let mut unwitnessed: ElementsTransaction = deserialize(&raw).unwrap();
+        unwitnessed.input[0].witness = InputWitness::default();
+        unwitnessed.output[1].witness = OutputWitness::default();
+        assert_eq!(serialize(&unwitnessed), stripped);
+        assert_eq!(unwitnessed.txid(), tx.txid());
+    }
+
+    /// Creates a signed block at `height` with only a coinbase transaction.
+    fn block(prev_blockhash: BlockHash, height: u32) -> ElementsBlock {
+        let coinbase = ElementsTransaction {
+            version: 2,
+            input: vec![ElementsTxIn {
+                previous_output: OutPoint::null(),
+                is_pegin: false,
+                script_sig: ScriptBuf::from_bytes(vec![0x01, height as u8]),
+                sequence: Sequence::MAX,
+                asset_issuance: None,
+                witness: InputWitness::default(),
+            }],
+            output: vec![ElementsTxOut {
+                asset: ConfidentialAsset::Explicit(AssetId::liquid_btc()),
+                value: ConfidentialValue::Explicit(0),
+                nonce: ConfidentialNonce::Null,
+                script_pubkey: ScriptBuf::from_bytes(vec![0x6a]),
+                witness: OutputWitness::default(),
+            }],
+            lock_time: LockTime::ZERO,
+        };
+        ElementsBlock {
+            header: ElementsHeader {
+                version: 0x2000_0000,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::from_raw_hash(coinbase.txid().to_raw_hash()),
+                time: 1_600_000_000 + height,
+                height,
+                ext: HeaderExt::Proof {
+                    challenge: ScriptBuf::from_bytes(vec![0x51]),
+                    solution: ScriptBuf::from_bytes(vec![0x00]),
+                },
+            },
+            txdata: vec![coinbase],
+        }
+    }
+
+    #[test]
+    fn reads_blocks_from_blk_files() {
+        let dir = temp_dir("elements-blocks");
+        let genesis = block(BlockHash::all_zeros(), 0);
+        let blocks = [genesis.clone(), block(genesis.block_hash(), 1)];
+        let params = ChainParams {
+            genesis_hash: genesis.block_hash(),
+            ..ChainParams::liquid()
+        };
+        let mut data = vec![];
+        for block in &blocks {
+            assert!(block.check_merkle_root());
+            let block = serialize(block);
+            data.extend(params.magic);
+            data.extend((block.len() as u32).to_le_bytes());
+            data.extend(block);
+        }
+        fs::write(dir.join("blk00000.dat"), data).unwrap();
+        let options = ParserOptions {
+            chain_params: Some(params.clone()),
+            ..ParserOptions::default()
+        };
+
+        let err = BlockParser::new_with_opts(dir.to_str().unwrap(), options.clone()).unwrap_err();
+        assert!(err.to_string().contains("ElementsParser"), "{}", err);
+
+        let parser = ElementsParser::new_with_opts(dir.to_str().unwrap(), options.clone()).unwrap();
+        let header = parser.get_header_at_height(1).unwrap();
+        assert_eq!(header.hash, blocks[1].block_hash());
+        let header_offset = 8 + serialize(&blocks[0]).len() + 8;
+        assert_eq!(header.header_offset, header_offset);
+        assert_eq!(
+            header.offset,
+            header_offset + serialize(&blocks[1].header).len()
+        );
+        for block in &blocks {
+            assert_eq!(&parser.get_block(&block.block_hash()).unwrap(), block);
+        }
+        let mut hashes: Vec<_> = parser.parse(|block| block.block_hash()).collect();
+        hashes.sort_by_key(|hash| *hash != genesis.block_hash());
+        assert_eq!(hashes, vec![blocks[0].block_hash(), blocks[1].block_hash()]);
+
+        // Raw block files are read with the signed headers of the chain
+        let raw_dir = temp_dir("elements-raw");
+        for block in &blocks {
+            let path = raw_dir.join(format!("{}.bin", block.block_hash()));
+            fs::write(path, serialize(block)).unwrap();
+        }
+        let chain = RawBlockFiles::new(&raw_dir).chain(&options).unwrap();
+        assert!(chain.report.skipped.is_empty());
+        let hashes: Vec<_> = chain.active.iter().map(|header| header.hash).collect();
+        assert_eq!(hashes, vec![blocks[0].block_hash(), blocks[1].block_hash()]);
+        assert_eq!(chain.active[1].offset, serialize(&blocks[1].header).len());
+
+        // Headers without the height or signatures would be misparsed
+        for (height_in_header, signed_blocks) in [(false, true), (true, false)] {
+            let options = ParserOptions {
+                chain_params: Some(ChainParams {
+                    name: "elementsregtest".to_string(),
+                    height_in_header,
+                    signed_blocks,
+                    ..params.clone()
+                }),
+                ..ParserOptions::default()
+            };
+            let expected =
+                "elementsregtest headers must contain the block height and be signed like Liquid's";
+            let err = ElementsParser::new_with_opts(dir.to_str().unwrap(), options.clone());
+            assert_eq!(err.unwrap_err().to_string(), expected);
+            let err = HeaderParser::parse_with_opts(dir.to_str().unwrap(), &options).unwrap_err();
+            assert_eq!(err.to_string(), expected);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&raw_dir).unwrap();
+    }
+}
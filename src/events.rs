@@ -0,0 +1,254 @@
+//! Contains [`ChainEvent`] for updating state derived from blocks when the best chain changes.
+
+use crate::headers::{ParsedHeader, StaleBranch};
+use crate::source::BlockSource;
+use anyhow::Result;
+use bitcoin::{Block, BlockHash};
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// A change to the best chain, returned in the order needed to move from a previous tip to the
+/// current one.
+#[derive(Clone, Debug)]
+pub enum ChainEvent {
+    /// The block at the height was added to the tip of the best chain
+    BlockConnected(usize, Block),
+    /// The block at the height with the hash was removed from the tip of the best chain by a reorg
+    BlockDisconnected(usize, BlockHash),
+}
+
+impl ChainEvent {
+    /// Height of the block that was connected or disconnected.
+    pub fn height(&self) -> usize {
+        match self {
+            ChainEvent::BlockConnected(height, _) => *height,
+            ChainEvent::BlockDisconnected(height, _) => *height,
+        }
+    }
+}
+
+/// A pending change that has not been read from disk yet.
+#[derive(Clone, Debug)]
+enum Step {
+    /// Read the block and connect it
+    Connect(ParsedHeader),
+    /// Disconnect the block
+    Disconnect(ParsedHeader),
+}
+
+impl Step {
+    /// Header of the block being changed.
+    fn header(&self) -> &ParsedHeader {
+        match self {
+            Step::Connect(header) => header,
+            Step::Disconnect(header) => header,
+        }
+    }
+}
+
+/// Iterator of [`ChainEvent`] that reads the connected blocks from disk as it goes.
+///
+/// Disconnected blocks are returned first starting from the previous tip, followed by the
+/// connected blocks in height order.  If a connected block cannot be read the `Err` is returned
+/// and the next call tries to read the same block again.
+#[derive(Clone, Debug)]
+pub struct ChainEvents {
+    /// Where the connected blocks are read from
+    source: Arc<dyn BlockSource>,
+    /// Changes that have not been returned yet
+    steps: VecDeque<Step>,
+    /// Hash of the tip after the last returned event
+    tip: Option<BlockHash>,
+}
+
+impl ChainEvents {
+    /// Connects every block in the `active` chain from the `start_height` onwards.
+    pub(crate) fn from_height(
+        source: Arc<dyn BlockSource>,
+        active: &[ParsedHeader],
+        start_height: usize,
+    ) -> Self {
+        let start = active.partition_point(|header| header.height < start_height);
+        Self {
+            source,
+            steps: active[start..].iter().cloned().map(Step::Connect).collect(),
+            tip: None,
+        }
+    }
+
+    /// Moves from the `prev_tip` to the tip of the `active` chain, disconnecting blocks from any
+    /// [`StaleBranch`] the `prev_tip` is in.
+    ///
+    /// Returns `None` if the `prev_tip` cannot be found in the headers.
+    pub(crate) fn from_tip(
+        source: Arc<dyn BlockSource>,
+        active: &[ParsedHeader],
+        stale: &[StaleBranch],
+        prev_tip: &BlockHash,
+    ) -> Option<Self> {
+        let mut steps = VecDeque::default();
+        // Usually the previous tip is close to the current tip
+        let fork_height = match active.iter().rev().find(|header| header.hash == *prev_tip) {
+            Some(header) => header.height,
+            None => {
+                let (branch, position) = stale.iter().find_map(|branch| {
+                    let position = branch
+                        .headers
+                        .iter()
+                        .position(|header| header.hash == *prev_tip)?;
+                    Some((branch, position))
+                })?;
+                let disconnected = branch.headers[..=position].iter().rev().cloned();
+                steps.extend(disconnected.map(Step::Disconnect));
+                branch.fork_height
+            }
+        };
+
+        let start = active.partition_point(|header| header.height <= fork_height);
+        steps.extend(active[start..].iter().cloned().map(Step::Connect));
+        Some(Self {
+            source,
+            steps,
+            tip: Some(*prev_tip),
+        })
+    }
+
+    /// Hash of the tip after the last returned event, `None` if no events were returned yet.
+    pub fn tip(&self) -> Option<BlockHash> {
+        self.tip
+    }
+
+    /// Returns true if there are no more events.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Drops the pending events below the `height`.
+    pub(crate) fn skip_below(&mut self, height: usize) {
+        self.steps.retain(|step| step.header().height >= height);
+    }
+}
+
+impl Iterator for ChainEvents {
+    type Item = Result<ChainEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let event = match self.steps.pop_front()? {
+            Step::Connect(header) => match self.source.read_block(&header) {
+                Ok(block) => {
+                    self.tip = Some(header.hash);
+                    Ok(ChainEvent::BlockConnected(header.height, block))
+                }
+                Err(e) => {
+                    // Try to connect the same block again on the next call
+                    self.steps.push_front(Step::Connect(header));
+                    Err(e)
+                }
+            },
+            Step::Disconnect(header) => {
+                self.tip = Some(header.inner.prev_blockhash);
+                Ok(ChainEvent::BlockDisconnected(header.height, header.hash))
+            }
+        };
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::ParserOptions;
+    use crate::headers::tests::{mine, regtest_chain};
+    use crate::headers::HeaderChain;
+    use crate::source::MemoryBlocks;
+    use anyhow::bail;
+    use bitcoin::Network;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Fails to read the block at height 2 the first time.
+    #[derive(Debug)]
+    struct FlakySource {
+        blocks: MemoryBlocks,
+        failed: AtomicBool,
+    }
+
+    impl BlockSource for FlakySource {
+        fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+            self.blocks.chain(options)
+        }
+
+        fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+            if header.height == 2 && !self.failed.swap(true, Ordering::SeqCst) {
+                bail!("Transient error");
+            }
+            self.blocks.read_block(header)
+        }
+    }
+
+    #[test]
+    fn retries_blocks_that_cannot_be_read() {
+        let blocks = regtest_chain(4);
+        let source = FlakySource {
+            blocks: MemoryBlocks::new(blocks.clone()),
+            failed: AtomicBool::new(false),
+        };
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let chain = source.chain(&options).unwrap();
+        let mut events = ChainEvents::from_height(Arc::new(source), &chain.active, 1);
+
+        assert_eq!(events.next().unwrap().unwrap().height(), 1);
+        assert!(events.next().unwrap().is_err());
+        assert_eq!(events.tip(), Some(blocks[1].block_hash()));
+        let heights: Vec<_> = events
+            .by_ref()
+            .map(|event| event.unwrap().height())
+            .collect();
+        assert_eq!(heights, vec![2, 3]);
+        assert_eq!(events.tip(), Some(blocks[3].block_hash()));
+    }
+
+    #[test]
+    fn disconnects_before_connecting_after_reorgs() {
+        let blocks = regtest_chain(4);
+        let mut fork = vec![mine(&blocks[1].header, 20)];
+        for height in 3..5 {
+            fork.push(mine(&fork.last().unwrap().header, height));
+        }
+        let source = MemoryBlocks::new(blocks.iter().chain(&fork).cloned());
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let chain = source.chain(&options).unwrap();
+        let prev_tip = blocks[3].block_hash();
+        let mut events =
+            ChainEvents::from_tip(Arc::new(source), &chain.active, &chain.stale, &prev_tip)
+                .unwrap();
+
+        let disconnected: Vec<_> = events
+            .by_ref()
+            .take(2)
+            .map(|event| match event.unwrap() {
+                ChainEvent::BlockDisconnected(height, hash) => (height, hash),
+                event => panic!("Expected a disconnect, got {:?}", event),
+            })
+            .collect();
+        let expected = vec![(3, blocks[3].block_hash()), (2, blocks[2].block_hash())];
+        assert_eq!(disconnected, expected);
+        assert_eq!(events.tip(), Some(blocks[1].block_hash()));
+
+        let connected: Vec<_> = events
+            .by_ref()
+            .map(|event| match event.unwrap() {
+                ChainEvent::BlockConnected(height, block) => (height, block.block_hash()),
+                event => panic!("Expected a connect, got {:?}", event),
+            })
+            .collect();
+        let expected: Vec<_> = (2..).zip(fork.iter().map(Block::block_hash)).collect();
+        assert_eq!(connected, expected);
+        assert_eq!(events.tip(), Some(fork[2].block_hash()));
+    }
+}
@@ -0,0 +1,260 @@
+//! Contains [`BlockFollower`] for streaming new blocks as `bitcoind` writes them.
+
+use crate::blocks::ParserOptions;
+use crate::events::{ChainEvent, ChainEvents};
+use crate::headers::HeaderCache;
+use crate::source::{BlockSource, BlocksDir};
+use crate::HeaderParser;
+use anyhow::{bail, Result};
+use bitcoin::Block;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Longest time to wait before retrying after repeated errors
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Follows the tip of the chain, returning every block in height order as it is written to the
+/// `blocks` directory.
+///
+/// Polls the BLK files and only rescans the ones that have changed, resolving the chain with the
+/// same logic as [`HeaderParser`].  Records that are only partially written are ignored until
+/// they have been completed.
+///
+/// - Blocks are read on the calling thread, use [`crate::blocks::BlockParser`] to catch up on
+///   historic blocks.
+/// - Always scans the BLK files, [`ParserOptions::block_index`] is ignored.  Set
+///   [`ParserOptions::header_cache`] to speed up startup.
+/// - Blocks from a new branch are returned again at the same heights after a reorg, use
+///   [`BlockFollower::events`] to also be told which blocks were disconnected.
+///
+/// # Examples
+/// Printing every block from height 850,000 onwards, waiting for new blocks once the tip is
+/// reached:
+/// ```no_run
+/// use bitcoin_block_parser::follow::*;
+///
+/// let follower = BlockFollower::new("/home/user/.bitcoin/blocks/").unwrap();
+/// for result in follower.start_height(850_000) {
+///     let (height, block) = result.unwrap();
+///     println!("New block {} at height {}", block.block_hash(), height);
+/// }
+/// ```
+pub struct BlockFollower {
+    /// Directory where the `*.blk` files are located
+    blocks_dir: String,
+    /// Options for scanning and resolving the headers
+    options: ParserOptions,
+    /// Headers scanned so far, only files that changed are rescanned
+    cache: HeaderCache,
+    /// Reads the blocks from the BLK files
+    source: Arc<dyn BlockSource>,
+    /// Changes to the chain that have not been returned yet
+    events: ChainEvents,
+    /// Height of the first block to return
+    start_height: usize,
+    /// How long to wait before checking the BLK files for changes
+    poll_interval: Duration,
+    /// Number of errors returned in a row, used to wait longer before retrying
+    failures: u32,
+}
+
+impl BlockFollower {
+    /// Creates a new follower given the `blocks` directory where the `*.blk` files are located.
+    ///
+    /// - Returns an `Err` if unable to parse the `blk` files.
+    pub fn new(blocks_dir: &str) -> Result<Self> {
+        Self::new_with_opts(blocks_dir, ParserOptions::default())
+    }
+
+    /// Creates a follower with custom [`ParserOptions`].
+    pub fn new_with_opts(blocks_dir: &str, options: ParserOptions) -> Result<Self> {
+        let xor_mask = HeaderParser::read_xor_mask(blocks_dir)?;
+        let source: Arc<dyn BlockSource> = Arc::new(BlocksDir::new(blocks_dir));
+        let mut follower = Self {
+            blocks_dir: blocks_dir.to_string(),
+            cache: HeaderCache::open(xor_mask, &options),
+            options,
+            events: ChainEvents::from_height(source.clone(), &[], 0),
+            source,
+            start_height: 0,
+            poll_interval: Duration::from_secs(1),
+            failures: 0,
+        };
+        follower.cache.update(blocks_dir)?;
+        follower.resolve()?;
+        Ok(follower)
+    }
+
+    /// Sets the height of the first block to return, `0` will start at the genesis block.
+    pub fn start_height(mut self, start_height: usize) -> Self {
+        self.start_height = start_height;
+        self.events.skip_below(start_height);
+        self
+    }
+
+    /// Sets how long to wait before checking the BLK files for changes, defaults to 1 second.
+    pub fn poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Scans the BLK files for changes, resolving the chain again if any headers changed.
+    fn update(&mut self) -> Result<()> {
+        if self.cache.update(&self.blocks_dir)? > 0 {
+            self.resolve()?;
+        }
+        Ok(())
+    }
+
+    /// Resolves the chain, queuing the events needed to move from the last returned tip.
+    fn resolve(&mut self) -> Result<()> {
+        if let Some(cache_file) = &self.options.header_cache {
+            self.cache.save(cache_file)?;
+        }
+        let chain = HeaderParser::resolve_cache(&self.cache, &self.options)?;
+        let source = self.source.clone();
+        self.events = match self.events.tip() {
+            Some(tip) => match ChainEvents::from_tip(source, &chain.active, &chain.stale, &tip) {
+                Some(events) => events,
+                None => bail!("Previous tip {} is not in any known chain", tip),
+            },
+            None => ChainEvents::from_height(source, &chain.active, self.start_height),
+        };
+        Ok(())
+    }
+
+    /// Returns the next [`ChainEvent`], waiting until the chain changes.
+    ///
+    /// After an `Err` the next call waits before retrying, doubling the wait for every error in a
+    /// row up to a minute.
+    pub fn next_event(&mut self) -> Option<Result<ChainEvent>> {
+        if self.failures > 0 {
+            let delay = self.poll_interval * 2_u32.pow(self.failures.min(6));
+            thread::sleep(delay.min(MAX_RETRY_DELAY));
+        }
+        let event = self.poll_event();
+        match event {
+            Ok(_) => self.failures = 0,
+            Err(_) => self.failures += 1,
+        }
+        Some(event)
+    }
+
+    /// Returns the next [`ChainEvent`], polling the BLK files until there is one.
+    fn poll_event(&mut self) -> Result<ChainEvent> {
+        loop {
+            if let Some(event) = self.events.next() {
+                return event;
+            }
+            self.update()?;
+            if self.events.is_empty() {
+                thread::sleep(self.poll_interval);
+            }
+        }
+    }
+
+    /// Returns an iterator of every [`ChainEvent`], including the blocks disconnected by reorgs.
+    pub fn events(mut self) -> impl Iterator<Item = Result<ChainEvent>> {
+        std::iter::from_fn(move || self.next_event())
+    }
+}
+
+impl Iterator for BlockFollower {
+    type Item = Result<(usize, Block)>;
+
+    /// Returns the next block and its height, waiting until it has been written.
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_event()? {
+                Ok(ChainEvent::BlockConnected(height, block)) => return Some(Ok((height, block))),
+                Ok(ChainEvent::BlockDisconnected(_, _)) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::headers::tests::{blk_records, mine, regtest_chain};
+    use crate::leveldb::tests::temp_dir;
+    use bitcoin::{BlockHash, Network};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    /// Simplifies the events so they can be compared.
+    fn summarize(event: ChainEvent) -> (bool, usize, BlockHash) {
+        match event {
+            ChainEvent::BlockConnected(height, block) => (true, height, block.block_hash()),
+            ChainEvent::BlockDisconnected(height, hash) => (false, height, hash),
+        }
+    }
+
+    /// Appends the `blocks` as records to the BLK file at `path`.
+    fn append(path: &Path, blocks: &[Block]) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&blk_records(blocks)).unwrap();
+    }
+
+    fn follower(dir: &Path) -> BlockFollower {
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        BlockFollower::new_with_opts(dir.to_str().unwrap(), options)
+            .unwrap()
+            .poll_interval(Duration::from_millis(10))
+    }
+
+    #[test]
+    fn follows_appended_blocks() {
+        let dir = temp_dir("follow-append");
+        let path = dir.join("blk00000.dat");
+        let blocks = regtest_chain(4);
+        fs::write(&path, blk_records(&blocks[..2])).unwrap();
+        let mut follower = follower(&dir).start_height(1);
+        let (height, block) = follower.next().unwrap().unwrap();
+        assert_eq!((height, block.block_hash()), (1, blocks[1].block_hash()));
+
+        // Waits for the blocks to be written
+        append(&path, &blocks[2..]);
+        let events: Vec<_> = (0..2)
+            .map(|_| summarize(follower.next_event().unwrap().unwrap()))
+            .collect();
+        let expected = vec![
+            (true, 2, blocks[2].block_hash()),
+            (true, 3, blocks[3].block_hash()),
+        ];
+        assert_eq!(events, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn disconnects_blocks_after_reorgs() {
+        let dir = temp_dir("follow-reorg");
+        let path = dir.join("blk00000.dat");
+        let blocks = regtest_chain(3);
+        fs::write(&path, blk_records(&blocks)).unwrap();
+        let mut follower = follower(&dir);
+        let heights: Vec<_> = follower.by_ref().take(3).map(|r| r.unwrap().0).collect();
+        assert_eq!(heights, vec![0, 1, 2]);
+
+        // A longer branch that forks after height 1 replaces the block at height 2
+        let fork_2 = mine(&blocks[1].header, 20);
+        let fork_3 = mine(&fork_2.header, 3);
+        append(&path, &[fork_2.clone(), fork_3.clone()]);
+        let events: Vec<_> = (0..3)
+            .map(|_| summarize(follower.next_event().unwrap().unwrap()))
+            .collect();
+        let expected = vec![
+            (false, 2, blocks[2].block_hash()),
+            (true, 2, fork_2.block_hash()),
+            (true, 3, fork_3.block_hash()),
+        ];
+        assert_eq!(events, expected);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
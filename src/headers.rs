@@ -1,41 +1,729 @@
 //! Used to parse the [`bitcoin::block::Header`] from the `blocks` directory to order and locate
 //! every block for later parsing.
 
+use crate::blocks::ParserOptions;
+use crate::elements::{ElementsBlock, ElementsHeader};
+use crate::index::BlockIndex;
+use crate::params::{read_transactions, ChainParams};
+use crate::source::BlockSource;
 use crate::xor::{XorReader, XOR_MASK_LEN};
 use anyhow::bail;
 use anyhow::Result;
 use bitcoin::block::Header;
-use bitcoin::consensus::Decodable;
+use bitcoin::consensus::{Decodable, Encodable};
 use bitcoin::hashes::Hash;
-use bitcoin::BlockHash;
-use log::info;
-use std::collections::HashMap;
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::p2p::Magic;
+use bitcoin::pow::{Target, Work};
+use bitcoin::script::{read_scriptint, Instruction};
+use bitcoin::{Block, BlockHash, Network, Transaction, VarInt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
 use std::fs;
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
+use std::time::SystemTime;
 use threadpool::ThreadPool;
 
 /// Before the header are 4 magic bytes and 4 bytes that indicate the block size
 const PRE_HEADER_SIZE: usize = 8;
+/// Number of blocks used to compute the median-time-past
+const MEDIAN_TIME_SPAN: usize = 11;
+/// Number of bytes read at a time when scanning for the next magic bytes
+const SCAN_CHUNK_SIZE: usize = 64 * 1024;
 
 /// Points to the on-disk location where a block starts (and the header ends)
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ParsedHeader {
     /// Consensus parsed `bitcoin::Header`
     pub inner: Header,
-    /// Byte offset from the beginning of the file
+    /// Byte offset from the beginning of the file where the transactions start
     pub offset: usize,
+    /// Byte offset from the beginning of the file where the header starts, `0` for sources that
+    /// do not read from BLK files
+    pub header_offset: usize,
     /// This header's block hash
     pub hash: BlockHash,
     /// Path of the BLK file
     pub path: PathBuf,
     /// XOR mask of the BLK file
     pub xor_mask: Option<[u8; XOR_MASK_LEN]>,
+    /// Cumulative work of the chain up to and including this block
+    pub chainwork: Work,
+    /// Height of the block, only known once the headers have been resolved into a chain
+    pub height: usize,
+    /// Size of the serialized block in bytes, read from the BLK file.  `0` if unknown, such as for
+    /// headers read from the block index or from sources that do not use BLK files.
+    pub size: usize,
+}
+
+impl ParsedHeader {
+    /// Where the block is located on disk, used to determine which block was received first.
+    fn location(&self) -> (&PathBuf, usize) {
+        (&self.path, self.offset)
+    }
+}
+
+impl fmt::Display for ParsedHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "block {} at height {}", self.hash, self.height)?;
+        // Sources that do not read from files leave the path empty
+        if !self.path.as_os_str().is_empty() {
+            write!(f, " in {:?} at offset {}", self.path, self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chain metadata derived from a [`ParsedHeader`] without reading any transaction data.
+#[derive(Clone, Debug)]
+pub struct HeaderInfo {
+    /// Consensus parsed `bitcoin::Header` containing the version, time, bits and nonce
+    pub header: Header,
+    /// Height of the block
+    pub height: usize,
+    /// This header's block hash
+    pub hash: BlockHash,
+    /// Target the block hash must be below
+    pub target: Target,
+    /// Difficulty relative to the maximum mainnet target, as reported by `getdifficulty`
+    pub difficulty: f64,
+    /// Cumulative work of the chain up to and including this block
+    pub chainwork: Work,
+    /// Median timestamp of the last 11 blocks up to and including this block (BIP113)
+    pub median_time_past: u32,
+    /// Size of the serialized block in bytes, `0` if it could not be read
+    pub size: usize,
+}
+
+/// Iterator of [`HeaderInfo`] over headers in height order.
+///
+/// # Examples
+/// Printing the difficulty history without parsing any blocks:
+/// ```no_run
+/// use bitcoin_block_parser::headers::*;
+///
+/// let headers = HeaderParser::parse("/home/user/.bitcoin/blocks/").unwrap();
+/// for info in HeaderInfoIter::new(&headers, 0).step_by(2016) {
+///     println!("{} {}", info.height, info.difficulty);
+/// }
+/// ```
+pub struct HeaderInfoIter<'a> {
+    /// Remaining headers to iterate over
+    headers: std::slice::Iter<'a, ParsedHeader>,
+    /// Timestamps of the most recent blocks for computing the median-time-past
+    times: VecDeque<u32>,
+    /// Last BLK file that an unknown block size was read from
+    file: Option<(PathBuf, XorReader<File>)>,
+}
+
+impl<'a> HeaderInfoIter<'a> {
+    /// Iterates over `headers` starting at index `start`, earlier headers are only used to
+    /// compute the median-time-past.
+    pub fn new(headers: &'a [ParsedHeader], start: usize) -> Self {
+        let start = start.min(headers.len());
+        let times = headers[start.saturating_sub(MEDIAN_TIME_SPAN - 1)..start]
+            .iter()
+            .map(|header| header.inner.time)
+            .collect();
+        Self {
+            headers: headers[start..].iter(),
+            times,
+            file: None,
+        }
+    }
+
+    /// Reads the size of a block from the 4 bytes before its header in the BLK file, only needed
+    /// for headers whose size is unknown.
+    fn read_size(&mut self, header: &ParsedHeader) -> Result<usize> {
+        let file = match self.file.take() {
+            Some((path, file)) if path == header.path => file,
+            _ => XorReader::new(File::open(&header.path)?, header.xor_mask),
+        };
+        let (_, file) = self.file.insert((header.path.clone(), file));
+        let mut size = [0; 4];
+        let Some(pos) = header.header_offset.checked_sub(size.len()) else {
+            bail!("No block size before {}", header);
+        };
+        file.seek(SeekFrom::Start(pos as u64))?;
+        file.read_exact(&mut size)?;
+        Ok(u32::from_le_bytes(size) as usize)
+    }
+}
+
+impl Iterator for HeaderInfoIter<'_> {
+    type Item = HeaderInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.headers.next()?;
+        if self.times.len() == MEDIAN_TIME_SPAN {
+            self.times.pop_front();
+        }
+        self.times.push_back(header.inner.time);
+
+        Some(HeaderInfo {
+            header: header.inner,
+            height: header.height,
+            hash: header.hash,
+            target: header.inner.target(),
+            difficulty: header.inner.difficulty_float(),
+            chainwork: header.chainwork,
+            median_time_past: median(&self.times),
+            size: match header.size {
+                // Headers from the block index only know where the block starts
+                0 if !header.path.as_os_str().is_empty() => self.read_size(header).unwrap_or(0),
+                size => size,
+            },
+        })
+    }
+}
+
+/// Returns the median of the recent block `times`.
+fn median(times: &VecDeque<u32>) -> u32 {
+    let mut times: Vec<u32> = times.iter().copied().collect();
+    times.sort_unstable();
+    times[times.len() / 2]
+}
+
+/// Returns the median-time-past of every header in height order, computed from the header
+/// timestamps alone so no block sizes are read like [`HeaderInfoIter`].
+pub(crate) fn median_times_past(headers: &[ParsedHeader]) -> Vec<u32> {
+    let mut times = VecDeque::with_capacity(MEDIAN_TIME_SPAN);
+    headers
+        .iter()
+        .map(|header| {
+            if times.len() == MEDIAN_TIME_SPAN {
+                times.pop_front();
+            }
+            times.push_back(header.inner.time);
+            median(&times)
+        })
+        .collect()
+}
+
+/// The active chain of headers and every stale branch that forked from it.
+#[derive(Clone, Debug)]
+pub struct HeaderChain {
+    /// Headers of the chain with the most work in height order, starting from the genesis block
+    pub active: Vec<ParsedHeader>,
+    /// Branches of stale (orphaned) blocks that lost to the active chain
+    pub stale: Vec<StaleBranch>,
+    /// Diagnostics about headers that could not be connected to the genesis block
+    pub report: HeaderReport,
+}
+
+impl HeaderChain {
+    /// Creates the chain from the `active` headers, grouping the `stale` headers into branches.
+    pub(crate) fn new(
+        active: Vec<ParsedHeader>,
+        stale: impl Iterator<Item = ParsedHeader>,
+    ) -> Self {
+        let heights: HashMap<BlockHash, usize> = active
+            .iter()
+            .map(|header| (header.hash, header.height))
+            .collect();
+        let stale: HashMap<BlockHash, ParsedHeader> = stale
+            .filter(|header| !heights.contains_key(&header.hash))
+            .map(|header| (header.hash, header))
+            .collect();
+        let parents: HashSet<BlockHash> = stale
+            .values()
+            .map(|header| header.inner.prev_blockhash)
+            .collect();
+
+        // Every stale header without children is the tip of a branch
+        let mut branches = vec![];
+        for tip in stale
+            .values()
+            .filter(|header| !parents.contains(&header.hash))
+        {
+            let mut headers = vec![tip.clone()];
+            let mut prev = tip.inner.prev_blockhash;
+            while let Some(header) = stale.get(&prev) {
+                headers.push(header.clone());
+                prev = header.inner.prev_blockhash;
+            }
+            if let Some(fork_height) = heights.get(&prev) {
+                headers.reverse();
+                for (height, header) in (fork_height + 1..).zip(headers.iter_mut()) {
+                    header.height = height;
+                }
+                branches.push(StaleBranch {
+                    fork_height: *fork_height,
+                    headers,
+                });
+            }
+        }
+        branches.sort_by_key(|branch| (branch.fork_height, branch.headers.len()));
+
+        Self {
+            active,
+            stale: branches,
+            report: HeaderReport::default(),
+        }
+    }
+
+    /// Resolves the `headers` read by a [`BlockSource`] into a chain, reporting the
+    /// byte ranges that were `skipped` while reading them.
+    ///
+    /// - Forks are resolved to the chain with the most work.
+    /// - Headers that do not connect to the genesis block are handled according to
+    ///   [`ParserOptions::gap_policy`].
+    /// - Returns an `Err` if the chain does not start with the genesis block of the chain in the
+    ///   `options`.
+    pub fn resolve<'a>(
+        headers: impl Iterator<Item = &'a ParsedHeader>,
+        skipped: BTreeMap<PathBuf, Vec<Range<usize>>>,
+        options: &ParserOptions,
+    ) -> Result<HeaderChain> {
+        // Resolve reorgs and order the headers by block height
+        let params = options.params();
+        let mut chain = HeaderParser::resolve_headers(headers, options.gap_policy, &params)?;
+        chain.report.skipped = skipped;
+        if !chain.report.skipped.is_empty() {
+            warn!(
+                "Skipped invalid bytes in {} BLK files: {:?}",
+                chain.report.skipped.len(),
+                chain.report.skipped
+            );
+        }
+        HeaderParser::check_genesis(&chain.active, &params)?;
+        info!(
+            "Finished reading {} headers with {} stale branches",
+            chain.active.len(),
+            chain.stale.len()
+        );
+        Ok(chain)
+    }
+}
+
+/// A branch of blocks that forked from the active chain and was later abandoned.
+///
+/// Branches that fork from the same stale block will contain some of the same headers.
+#[derive(Clone, Debug)]
+pub struct StaleBranch {
+    /// Height of the last block the branch shares with the active chain
+    pub fork_height: usize,
+    /// Headers of the branch in height order, the first is at height `fork_height + 1`
+    pub headers: Vec<ParsedHeader>,
+}
+
+impl StaleBranch {
+    /// Number of stale blocks in the branch, which is the depth of the reorg it caused.
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    /// Returns true if the branch has no blocks.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+}
+
+/// What to do when some headers cannot be connected to the genesis block, which happens if the
+/// node is still syncing (blocks are downloaded out of order) or if a BLK file is missing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum GapPolicy {
+    /// Return an `Err` describing the gaps
+    Error,
+    /// Stop the chain at the first missing block
+    #[default]
+    Truncate,
+    /// Skip over missing blocks, using the BIP34 height in the coinbase to place the headers
+    /// after each gap.  The chainwork of those headers does not include the missing blocks.  Forks
+    /// after a gap are resolved to the chain with the most work, the others become stale branches.
+    ///
+    /// Only works if the chain enforces BIP34 at the height of the gap, see
+    /// [`ChainParams::bip34_height`], otherwise the height is reported as unknown and the headers
+    /// after the gap are left out as with [`GapPolicy::Truncate`].  Never works for Elements
+    /// chains.
+    Continue,
+}
+
+/// Diagnostics about the headers that could not be connected to the genesis block and the parts
+/// of BLK files that did not contain valid blocks.
+#[derive(Clone, Debug, Default)]
+pub struct HeaderReport {
+    /// Every missing block that other headers build on, ordered by height
+    pub gaps: Vec<Gap>,
+    /// Byte ranges of each BLK file that were skipped over because they contained garbage or a
+    /// truncated block, not including the zeros Bitcoin Core preallocates at the end of files or
+    /// a block at the end that is still being written
+    pub skipped: BTreeMap<PathBuf, Vec<Range<usize>>>,
+}
+
+impl HeaderReport {
+    /// Returns true if every header connected to the genesis block and no bytes were skipped.
+    pub fn is_empty(&self) -> bool {
+        self.gaps.is_empty() && self.skipped.is_empty()
+    }
+
+    /// Total number of headers that could not be connected to the genesis block.
+    pub fn num_unreachable(&self) -> usize {
+        self.gaps.iter().map(|gap| gap.unreachable.len()).sum()
+    }
+}
+
+/// A block that is missing from the `blocks` directory and the headers that descend from it.
+#[derive(Clone, Debug)]
+pub struct Gap {
+    /// Hash of the missing block
+    pub missing: BlockHash,
+    /// Height of the missing block if it could be read from the BIP34 coinbase of a descendant
+    pub height: Option<usize>,
+    /// Headers that cannot be connected to the genesis block because of the missing block
+    pub unreachable: Vec<ParsedHeader>,
+}
+
+impl Gap {
+    /// Returns the BLK files that contain the unreachable headers.
+    pub fn files(&self) -> BTreeSet<&PathBuf> {
+        self.unreachable.iter().map(|header| &header.path).collect()
+    }
+}
+
+impl fmt::Display for Gap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let height = match self.height {
+            Some(height) => height.to_string(),
+            None => "unknown".to_string(),
+        };
+        write!(
+            f,
+            "missing block {} at height {} needed by {} headers in {:?}",
+            self.missing,
+            height,
+            self.unreachable.len(),
+            self.files()
+        )
+    }
+}
+
+/// Headers scanned from every BLK file, allowing later runs to only rescan files that changed.
+///
+/// The cache file starts with the [`CacheKey`] followed by a [`ScannedFile`] entry for each BLK
+/// file.  Saving only appends the entries of files that changed, later entries replace earlier
+/// ones for the same file.  The file is rewritten once it contains more replaced entries than
+/// live ones.
+#[derive(Clone, Debug)]
+pub(crate) struct HeaderCache {
+    /// XOR mask and chain parameters the BLK files were scanned with
+    key: CacheKey,
+    /// Scanned BLK files keyed by their path
+    files: HashMap<PathBuf, ScannedFile>,
+    /// Files that were scanned since the cache was last saved
+    changed: HashSet<PathBuf>,
+    /// Number of entries in the cache file, including ones that were replaced
+    written: usize,
+    /// Whether the cache file must be rewritten instead of appended to
+    rewrite: bool,
+    /// Maximum number of threads used to scan BLK files, see [`ParserOptions::num_threads`]
+    num_threads: usize,
+}
+
+/// Identifies the BLK files a cache file belongs to.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct CacheKey {
+    /// XOR mask of the BLK files when they were scanned
+    xor_mask: Option<[u8; XOR_MASK_LEN]>,
+    /// Parameters of the chain the BLK files belong to
+    params: ChainParams,
+}
+
+/// Headers scanned from a single BLK file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ScannedFile {
+    /// Length of the file in bytes when it was scanned
+    len: u64,
+    /// Last modification time of the file when it was scanned
+    modified: Option<SystemTime>,
+    /// Byte offset where the next record will be written
+    end: usize,
+    /// Headers found in the file
+    headers: Vec<CachedHeader>,
+    /// Byte ranges that did not contain valid blocks
+    skipped: Vec<Range<usize>>,
+}
+
+impl ScannedFile {
+    /// Returns true if the first and last cached headers are still stored at the same offsets of
+    /// the file at `path`, so that it can be scanned from the previous end.
+    fn is_unchanged(
+        &self,
+        path: &Path,
+        xor_mask: Option<[u8; XOR_MASK_LEN]>,
+        params: &ChainParams,
+    ) -> bool {
+        let Ok(file) = File::open(path) else {
+            return false;
+        };
+        let file_len = file
+            .metadata()
+            .map_or(0, |metadata| metadata.len() as usize);
+        let mut reader = BufReader::new(XorReader::new(file, xor_mask));
+        let mut headers = self.headers.first().into_iter().chain(self.headers.last());
+        headers.all(|header| {
+            let Some(offset) = header.header_offset.checked_sub(PRE_HEADER_SIZE) else {
+                return false;
+            };
+            if reader.seek(SeekFrom::Start(offset as u64)).is_err() {
+                return false;
+            }
+            let record = HeaderParser::read_record(&mut reader, path, params, offset, file_len);
+            matches!(record, Ok(Some(record)) if record.hash == header.hash && record.size == header.size)
+        })
+    }
+}
+
+/// The parts of a [`ParsedHeader`] that are known when a BLK file is scanned, the path and XOR
+/// mask are shared by the whole file and the height and chainwork are computed when resolving.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CachedHeader {
+    /// Consensus parsed `bitcoin::Header`
+    inner: Header,
+    /// Block hash, kept so that resolving does not need to hash every header again
+    hash: BlockHash,
+    /// See [`ParsedHeader::offset`]
+    offset: usize,
+    /// See [`ParsedHeader::header_offset`]
+    header_offset: usize,
+    /// See [`ParsedHeader::size`]
+    size: usize,
+}
+
+impl CachedHeader {
+    fn new(header: ParsedHeader) -> Self {
+        Self {
+            inner: header.inner,
+            hash: header.hash,
+            offset: header.offset,
+            header_offset: header.header_offset,
+            size: header.size,
+        }
+    }
+}
+
+impl HeaderCache {
+    /// Create an empty cache for the BLK files identified by the `key`.
+    fn new(key: CacheKey, num_threads: usize) -> Self {
+        Self {
+            key,
+            files: HashMap::default(),
+            changed: HashSet::default(),
+            written: 0,
+            rewrite: true,
+            num_threads,
+        }
+    }
+
+    /// Loads the [`ParserOptions::header_cache`] if one is set, otherwise creates an empty cache.
+    pub(crate) fn open(xor_mask: Option<[u8; XOR_MASK_LEN]>, options: &ParserOptions) -> Self {
+        let key = CacheKey {
+            xor_mask,
+            params: options.params(),
+        };
+        match &options.header_cache {
+            Some(cache_file) => Self::load(cache_file, key, options.num_threads),
+            None => Self::new(key, options.num_threads),
+        }
+    }
+
+    /// Loads the `cache_file`, returning an empty cache if it is missing or invalid.
+    fn load(cache_file: &str, key: CacheKey, num_threads: usize) -> Self {
+        let Ok(file) = File::open(cache_file) else {
+            return Self::new(key, num_threads);
+        };
+        let mut reader = BufReader::new(file);
+        match bincode::deserialize_from::<_, CacheKey>(&mut reader) {
+            Ok(existing) if existing == key => info!("Found header cache '{}'", cache_file),
+            Ok(_) => {
+                warn!(
+                    "Ignoring header cache '{}' for different BLK files",
+                    cache_file
+                );
+                return Self::new(key, num_threads);
+            }
+            Err(_) => return Self::new(key, num_threads),
+        }
+
+        let mut cache = Self::new(key, num_threads);
+        cache.rewrite = false;
+        while reader.fill_buf().is_ok_and(|buf| !buf.is_empty()) {
+            match bincode::deserialize_from::<_, (PathBuf, ScannedFile)>(&mut reader) {
+                Ok((path, scanned)) => {
+                    cache.files.insert(path, scanned);
+                    cache.written += 1;
+                }
+                Err(_) => {
+                    // An append was interrupted, keep the complete entries before it
+                    warn!("Header cache '{}' ends with a partial entry", cache_file);
+                    cache.rewrite = true;
+                    break;
+                }
+            }
+        }
+        cache
+    }
+
+    /// Writes the files that changed to `cache_file`, rewriting it if needed.
+    pub(crate) fn save(&mut self, cache_file: &str) -> Result<()> {
+        if self.rewrite || self.written > 2 * self.files.len() {
+            let tmp_file = format!("{}.tmp", cache_file);
+            let mut writer = BufWriter::new(File::create(&tmp_file)?);
+            bincode::serialize_into(&mut writer, &self.key)?;
+            for entry in &self.files {
+                bincode::serialize_into(&mut writer, &entry)?;
+            }
+            writer.flush()?;
+            drop(writer);
+            fs::rename(tmp_file, cache_file)?;
+            self.written = self.files.len();
+        } else if !self.changed.is_empty() {
+            let file = fs::OpenOptions::new().append(true).open(cache_file)?;
+            let mut writer = BufWriter::new(file);
+            for path in &self.changed {
+                if let Some(scanned) = self.files.get(path) {
+                    bincode::serialize_into(&mut writer, &(path, scanned))?;
+                    self.written += 1;
+                }
+            }
+            writer.flush()?;
+        }
+        self.changed.clear();
+        self.rewrite = false;
+        Ok(())
+    }
+
+    /// Scans BLK files in the directory that are new or changed since they were last scanned,
+    /// returning the number of files whose headers or skipped bytes changed.
+    ///
+    /// Bitcoin Core may modify a file without writing a complete block, so files can be scanned
+    /// without changing the resolved chain.  Files that grew are scanned from the previous end
+    /// unless their cached headers have moved.
+    pub(crate) fn update(&mut self, blocks_dir: &str) -> Result<usize> {
+        let mut files = HashMap::default();
+        let mut scans = vec![];
+
+        for path in HeaderParser::blk_files(blocks_dir)? {
+            let metadata = fs::metadata(&path)?;
+            let len = metadata.len();
+            // Blocks may be written into preallocated space without changing the length
+            let modified = metadata.modified().ok();
+            let (scanned, shrunk) = match self.files.remove(&path) {
+                Some(scanned) if scanned.len == len && scanned.modified == modified => {
+                    files.insert(path, scanned);
+                    continue;
+                }
+                Some(scanned) if scanned.len <= len => (scanned, false),
+                // Files that are new or have shrunk are scanned from the beginning
+                previous => (ScannedFile::default(), previous.is_some()),
+            };
+            scans.push((path, scanned, shrunk, len, modified));
+        }
+
+        // Files that no longer exist can only be removed by rewriting the cache
+        let mut num_changed = self.files.len();
+        self.rewrite |= !self.files.is_empty();
+        self.files = files;
+        if scans.is_empty() {
+            return Ok(num_changed);
+        }
+
+        info!(
+            "Reading headers from {} BLK files in {}",
+            scans.len(),
+            blocks_dir
+        );
+        let (tx, rx) = mpsc::channel();
+        let pool = ThreadPool::new(self.num_threads.clamp(1, scans.len()));
+        for (path, mut scanned, shrunk, len, modified) in scans {
+            // Read headers from every new or grown BLK file in a new thread
+            let tx = tx.clone();
+            let xor_mask = self.key.xor_mask;
+            let params = self.key.params.clone();
+            pool.execute(move || {
+                // A file rewritten with the same or greater length, such as after a reindex,
+                // no longer contains the cached headers so is scanned from the beginning
+                let rewritten = !scanned.is_unchanged(&path, xor_mask, &params);
+                if rewritten {
+                    scanned = ScannedFile::default();
+                }
+                let shrunk = shrunk || rewritten;
+                let results =
+                    HeaderParser::parse_headers_file(path.clone(), xor_mask, &params, scanned.end)
+                        .map(|(headers, end, skipped)| {
+                            // Bytes after the previous end are scanned again
+                            let previous = scanned.skipped.clone();
+                            scanned.skipped.retain(|range| range.end <= scanned.end);
+                            scanned.skipped.extend(skipped);
+                            let changed =
+                                shrunk || !headers.is_empty() || scanned.skipped != previous;
+                            scanned.len = len;
+                            scanned.modified = modified;
+                            scanned.end = end;
+                            scanned
+                                .headers
+                                .extend(headers.into_iter().map(CachedHeader::new));
+                            (path, scanned, changed)
+                        });
+                let _ = tx.send(results);
+            });
+        }
+        drop(tx);
+
+        // Receive all the headers from spawned threads
+        for received in rx {
+            let (path, scanned, changed) = received?;
+            self.changed.insert(path.clone());
+            self.files.insert(path, scanned);
+            num_changed += changed as usize;
+        }
+        Ok(num_changed)
+    }
+
+    /// Returns all the headers from every scanned file.
+    fn headers(&self) -> impl Iterator<Item = ParsedHeader> + '_ {
+        self.files.iter().flat_map(|(path, file)| {
+            file.headers.iter().map(|header| ParsedHeader {
+                inner: header.inner,
+                offset: header.offset,
+                header_offset: header.header_offset,
+                hash: header.hash,
+                path: path.clone(),
+                xor_mask: self.key.xor_mask,
+                chainwork: Work::from_be_bytes([0; 32]),
+                height: 0,
+                size: header.size,
+            })
+        })
+    }
+
+    /// Returns the byte ranges skipped in every scanned file.
+    fn skipped(&self) -> BTreeMap<PathBuf, Vec<Range<usize>>> {
+        self.files
+            .iter()
+            .filter(|(_, file)| !file.skipped.is_empty())
+            .map(|(path, file)| (path.clone(), file.skipped.clone()))
+            .collect()
+    }
+}
+
+/// A block record read from a BLK file.
+pub(crate) struct Record {
+    /// Header used for resolving the chain
+    pub(crate) header: Header,
+    /// Hash of the block
+    pub(crate) hash: BlockHash,
+    /// Size of the block written before the header
+    pub(crate) size: usize,
+    /// Number of bytes read after the size, including any AuxPoW proof
+    pub(crate) read: usize,
 }
-/// You can [specify the blocks directory](https://en.bitcoin.it/wiki/Data_directory) when
-/// running `bitcoind`.
 
 /// Fast multithreaded parser of [`ParsedHeader`] from the blocks directory
 pub struct HeaderParser;
@@ -45,74 +733,528 @@ impl HeaderParser {
     /// - Returns an `Err` if the directory contains invalid `.blk` files.
     /// - Takes a few seconds to run.
     pub fn parse(blocks_dir: &str) -> Result<Vec<ParsedHeader>> {
-        info!("Reading headers from {}", blocks_dir);
+        Self::parse_with_opts(blocks_dir, &ParserOptions::default())
+    }
+
+    /// Parses the headers using custom [`ParserOptions`].
+    /// - If [`ParserOptions::block_index`] is set, reads the block locations from Bitcoin Core's
+    ///   `blocks/index` database instead of scanning every BLK file, ignoring the
+    ///   [`ParserOptions::header_cache`] and [`ParserOptions::gap_policy`].
+    /// - If [`ParserOptions::header_cache`] is set, only BLK files that are new or have grown since
+    ///   the cache was written will be scanned.
+    pub fn parse_with_opts(blocks_dir: &str, options: &ParserOptions) -> Result<Vec<ParsedHeader>> {
+        Ok(Self::parse_chain(blocks_dir, options)?.active)
+    }
+
+    /// Parses the headers from any [`BlockSource`] returning [`ParsedHeader`] in height order.
+    pub fn parse_source(
+        source: &impl BlockSource,
+        options: &ParserOptions,
+    ) -> Result<Vec<ParsedHeader>> {
+        Ok(source.chain(options)?.active)
+    }
+
+    /// Parses the headers returning a [`HeaderChain`] that contains both the active chain and every
+    /// [`StaleBranch`] that lost to it.
+    /// - Headers that do not connect to the genesis block are handled according to
+    ///   [`ParserOptions::gap_policy`] and described in the [`HeaderReport`].
+    /// - The [`HeaderReport`] is empty if [`ParserOptions::block_index`] is set, since the index
+    ///   only contains connected blocks.
+    pub fn parse_chain(blocks_dir: &str, options: &ParserOptions) -> Result<HeaderChain> {
         let xor_mask = Self::read_xor_mask(blocks_dir)?;
-        let (tx, rx) = mpsc::channel();
-        let pool = ThreadPool::new(64);
+        if options.block_index {
+            let params = options.params();
+            if params.aux_pow || params.elements {
+                bail!(
+                    "The block index cannot be used to locate {} blocks",
+                    params.name
+                );
+            }
+            let chain = BlockIndex::read(blocks_dir)?.chain(blocks_dir, xor_mask)?;
+            Self::check_genesis(&chain.active, &params)?;
+            return Ok(chain);
+        }
 
-        // Read headers from every BLK file in a new thread
-        for path in Self::blk_files(blocks_dir)? {
-            let path = path.clone();
-            let tx = tx.clone();
-            pool.execute(move || {
-                let results = Self::parse_headers_file(path, xor_mask);
-                let _ = tx.send(results);
-            });
+        let mut cache = HeaderCache::open(xor_mask, options);
+        cache.update(blocks_dir)?;
+        if let Some(cache_file) = &options.header_cache {
+            cache.save(cache_file)?;
         }
-        drop(tx);
+        Self::resolve_cache(&cache, options)
+    }
 
-        // Receive all the headers from spawned threads
-        let mut locations = HashMap::default();
-        let mut collisions: Vec<ParsedHeader> = vec![];
-        for received in rx {
-            for header in received? {
-                if let Some(collision) = locations.insert(header.inner.prev_blockhash, header) {
-                    collisions.push(collision)
+    /// Resolves the headers scanned into the `cache` into a [`HeaderChain`].
+    pub(crate) fn resolve_cache(
+        cache: &HeaderCache,
+        options: &ParserOptions,
+    ) -> Result<HeaderChain> {
+        let headers: Vec<ParsedHeader> = cache.headers().collect();
+        HeaderChain::resolve(headers.iter(), cache.skipped(), options)
+    }
+
+    /// Returns an `Err` if the chain does not start with the genesis block of the chain `params`.
+    pub(crate) fn check_genesis(headers: &[ParsedHeader], params: &ChainParams) -> Result<()> {
+        match headers.first() {
+            Some(header) if header.height == 0 && header.hash != params.genesis_hash => {
+                bail!("Genesis block {} is not from {}", header.hash, params.name)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Orders the headers by height, resolving forks to the chain with the most work and handling
+    /// headers that do not connect to the genesis block according to the `gap_policy`.
+    fn resolve_headers<'a>(
+        headers: impl Iterator<Item = &'a ParsedHeader>,
+        gap_policy: GapPolicy,
+        params: &ChainParams,
+    ) -> Result<HeaderChain> {
+        let mut by_hash: HashMap<BlockHash, ParsedHeader> = HashMap::default();
+        for header in headers {
+            match by_hash.get(&header.hash) {
+                // The same block may be written more than once, keep the first location
+                Some(existing) if existing.location() <= header.location() => {}
+                _ => {
+                    by_hash.insert(header.hash, header.clone());
                 }
             }
         }
 
-        // Resolve reorgs and order the headers by block height
-        for collision in collisions {
-            Self::resolve_collisions(&mut locations, collision);
+        // Genesis block starts with prev = all_zeros
+        let genesis_prev = BlockHash::all_zeros();
+        let chainwork = Self::compute_chainwork(&by_hash);
+        let mut active = Self::best_chain(&mut by_hash, &chainwork, genesis_prev);
+        for (height, header) in active.iter_mut().enumerate() {
+            header.height = height;
         }
-        let ordered = Self::order_headers(locations);
-        info!("Finished reading {} headers", ordered.len());
-        Ok(ordered)
+
+        // Remaining headers that connect to the genesis block are in stale branches, the rest are
+        // grouped by the missing block they descend from
+        let mut stale = vec![];
+        let mut missing: HashMap<BlockHash, Vec<ParsedHeader>> = HashMap::default();
+        for header in by_hash.into_values() {
+            match chainwork[&header.hash] {
+                (root, work) if root == genesis_prev => stale.push(ParsedHeader {
+                    chainwork: work,
+                    ..header
+                }),
+                (root, _) => missing.entry(root).or_default().push(header),
+            }
+        }
+        let mut gaps: Vec<Gap> = missing
+            .into_iter()
+            .map(|(missing, unreachable)| Gap {
+                missing,
+                height: Self::gap_height(missing, &unreachable, params),
+                unreachable,
+            })
+            .collect();
+        gaps.sort_by_key(|gap| (gap.height.is_none(), gap.height, gap.missing));
+        let report = HeaderReport {
+            gaps,
+            skipped: BTreeMap::default(),
+        };
+
+        if let Some(first) = report.gaps.first() {
+            let num_unreachable = report.num_unreachable();
+            match gap_policy {
+                GapPolicy::Error => bail!(
+                    "{} headers do not connect to the genesis block, first {}",
+                    num_unreachable,
+                    first
+                ),
+                _ => warn!(
+                    "{} headers do not connect to the genesis block, first {}",
+                    num_unreachable, first
+                ),
+            }
+        }
+        if gap_policy == GapPolicy::Continue {
+            Self::continue_gaps(&mut active, &mut stale, &report, &chainwork);
+        }
+
+        let mut chain = HeaderChain::new(active, stale.into_iter());
+        chain.report = report;
+        Ok(chain)
     }
 
-    /// Parses headers from a BLK file
-    fn parse_headers_file(
+    /// Appends the best chain after each gap to the `active` headers if it starts above the tip,
+    /// adding the forks that lost to it to the `stale` headers.
+    fn continue_gaps(
+        active: &mut Vec<ParsedHeader>,
+        stale: &mut Vec<ParsedHeader>,
+        report: &HeaderReport,
+        chainwork: &HashMap<BlockHash, (BlockHash, Work)>,
+    ) {
+        for gap in &report.gaps {
+            let Some(height) = gap.height else {
+                continue;
+            };
+            let tip = active.last();
+            if tip.is_some_and(|tip| tip.height >= height) {
+                continue;
+            }
+            let base = tip.map_or(Work::from_be_bytes([0; 32]), |tip| tip.chainwork);
+            let start = tip.map_or(0, |tip| tip.height + 1);
+            warn!("Skipping missing blocks at heights {}..={}", start, height);
+
+            let mut unreachable = gap
+                .unreachable
+                .iter()
+                .map(|header| (header.hash, header.clone()))
+                .collect();
+            let mut headers = Self::best_chain(&mut unreachable, chainwork, gap.missing);
+            for (height, header) in (height + 1..).zip(headers.iter_mut()) {
+                header.height = height;
+                header.chainwork = base + header.chainwork;
+            }
+            active.extend(headers);
+            // Forks after the gap become stale branches like the forks before it
+            stale.extend(unreachable.into_values().map(|header| ParsedHeader {
+                chainwork: base + chainwork[&header.hash].1,
+                ..header
+            }));
+        }
+    }
+
+    /// Removes and returns the chain with the most work that descends from the `root` block, the
+    /// tip with the most work wins with ties broken by whichever block was written to disk first.
+    fn best_chain(
+        headers: &mut HashMap<BlockHash, ParsedHeader>,
+        chainwork: &HashMap<BlockHash, (BlockHash, Work)>,
+        root: BlockHash,
+    ) -> Vec<ParsedHeader> {
+        let tip = headers
+            .values()
+            .filter(|header| chainwork[&header.hash].0 == root)
+            .max_by(|a, b| {
+                let ordering = chainwork[&a.hash].1.cmp(&chainwork[&b.hash].1);
+                ordering.then_with(|| b.location().cmp(&a.location()))
+            })
+            .map(|header| header.hash);
+
+        let mut chain = vec![];
+        let mut next = tip;
+        while let Some(mut header) = next.and_then(|hash| headers.remove(&hash)) {
+            header.chainwork = chainwork[&header.hash].1;
+            next = Some(header.inner.prev_blockhash);
+            chain.push(header);
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// Computes the cumulative chainwork of every header along with the block it descends from,
+    /// which is all zeros for the genesis block or the hash of a missing block.
+    fn compute_chainwork(
+        headers: &HashMap<BlockHash, ParsedHeader>,
+    ) -> HashMap<BlockHash, (BlockHash, Work)> {
+        let mut chainwork: HashMap<BlockHash, (BlockHash, Work)> = HashMap::default();
+
+        for hash in headers.keys() {
+            // Walk backwards until we find a block with known chainwork (or a missing block)
+            let mut stack = vec![];
+            let mut next = *hash;
+            let (root, mut work) = loop {
+                if let Some(known) = chainwork.get(&next) {
+                    break *known;
+                }
+                match headers.get(&next) {
+                    Some(header) => {
+                        stack.push(header);
+                        next = header.inner.prev_blockhash;
+                    }
+                    None => break (next, Work::from_be_bytes([0; 32])),
+                }
+            };
+
+            // Walk forwards adding up the work of each block
+            for header in stack.into_iter().rev() {
+                work = work + header.inner.work();
+                chainwork.insert(header.hash, (root, work));
+            }
+        }
+        chainwork
+    }
+
+    /// Reads the height of the `missing` block from the BIP34 coinbase of one of its children.
+    ///
+    /// Returns `None` if the chain does not enforce BIP34 at the child's height, since the coinbase
+    /// may then start with anything.
+    fn gap_height(
+        missing: BlockHash,
+        unreachable: &[ParsedHeader],
+        params: &ChainParams,
+    ) -> Option<usize> {
+        // Elements coinbases cannot be decoded as bitcoin transactions
+        let bip34_height = params.bip34_height.filter(|_| !params.elements)?;
+        let child = unreachable
+            .iter()
+            .find(|header| header.inner.prev_blockhash == missing)?;
+        let mut reader = XorReader::new(File::open(&child.path).ok()?, child.xor_mask);
+        reader.seek(SeekFrom::Start(child.offset as u64)).ok()?;
+        let mut reader = BufReader::new(reader);
+        let _num_tx = VarInt::consensus_decode(&mut reader).ok()?;
+        let coinbase = Transaction::consensus_decode(&mut reader).ok()?;
+        let script_sig = &coinbase.input.first()?.script_sig;
+
+        // Heights up to 16 are pushed with a single opcode
+        let height = match script_sig.instructions_minimal().next()?.ok()? {
+            Instruction::PushBytes(bytes) => read_scriptint(bytes.as_bytes()).ok()?,
+            Instruction::Op(op) => match op.classify(ClassifyContext::Legacy) {
+                Class::PushNum(num) => num as i64,
+                _ => return None,
+            },
+        };
+        let height = usize::try_from(height).ok()?;
+        match height >= bip34_height {
+            true => height.checked_sub(1),
+            false => None,
+        }
+    }
+
+    /// Parses headers from a BLK file starting at the byte `offset` of a record.
+    ///
+    /// Zeros, garbage and truncated blocks are skipped by scanning forward to the next magic bytes.
+    /// Returns the headers, the offset where the next record will be written and the byte ranges
+    /// that were skipped.  An incomplete record at the end of the file is not skipped since it may
+    /// still be being written, instead the returned offset points at it.
+    /// - Returns an `Err` if the magic bytes belong to a different bitcoin network.
+    pub(crate) fn parse_headers_file(
         path: PathBuf,
         xor_mask: Option<[u8; XOR_MASK_LEN]>,
-    ) -> Result<Vec<ParsedHeader>> {
+        params: &ChainParams,
+        mut offset: usize,
+    ) -> Result<(Vec<ParsedHeader>, usize, Vec<Range<usize>>)> {
+        let file = File::open(&path)?;
+        let file_len = file.metadata()?.len() as usize;
         let buffer_size = PRE_HEADER_SIZE + Header::SIZE;
-        let reader = XorReader::new(File::open(&path)?, xor_mask);
-        let mut reader = BufReader::with_capacity(buffer_size, reader);
+        let mut reader = BufReader::with_capacity(buffer_size, XorReader::new(file, xor_mask));
+        reader.seek(SeekFrom::Start(offset as u64))?;
 
-        let mut offset = 0;
-        // First 8 bytes are 4 magic bytes and 4 bytes that indicate the block size
-        let mut buffer = vec![0; PRE_HEADER_SIZE];
         let mut headers = vec![];
-
-        while reader.read_exact(&mut buffer).is_ok() {
-            offset += buffer.len();
-            if let Ok(header) = Header::consensus_decode(&mut reader) {
+        let mut skipped = vec![];
+        loop {
+            let record = match Self::read_record(&mut reader, &path, params, offset, file_len)? {
+                Some(record)
+                    if Self::is_complete(&mut reader, params, offset, &record, file_len) =>
+                {
+                    Some(record)
+                }
+                _ => None,
+            };
+            if let Some(record) = record {
+                offset += PRE_HEADER_SIZE;
                 headers.push(ParsedHeader {
-                    inner: header,
-                    offset: offset + Header::SIZE,
-                    hash: header.block_hash(),
+                    inner: record.header,
+                    offset: offset + record.read,
+                    header_offset: offset,
+                    hash: record.hash,
                     path: path.clone(),
                     xor_mask,
+                    chainwork: Work::from_be_bytes([0; 32]),
+                    height: 0,
+                    size: record.size,
                 });
-                // Get the size of the next block
-                let size = u32::from_le_bytes(buffer[4..].try_into()?) as usize;
-                // Seek to the next block, subtracting the block header bytes we parsed
-                reader.seek_relative(size.saturating_sub(Header::SIZE) as i64)?;
-                offset += size;
+                offset += record.size;
+                continue;
+            }
+
+            // Scan forward for the next record, skipping over any zeros or garbage
+            let magic = Magic::from_bytes(params.magic);
+            let (next, nonzero) = Self::find_magic(&mut reader, magic, offset + 1)?;
+            match next {
+                Some(next) => {
+                    skipped.push(offset..next);
+                    reader.seek(SeekFrom::Start(next as u64))?;
+                    offset = next;
+                }
+                None => {
+                    // Bitcoin Core preallocates BLK files with zeros and a record at the end may
+                    // still be being written, so it is scanned again instead of being skipped
+                    if nonzero && !Self::starts_with_magic(&mut reader, magic, offset)? {
+                        skipped.push(offset..file_len);
+                    }
+                    break;
+                }
             }
         }
-        Ok(headers)
+        Ok((headers, offset, skipped))
+    }
+
+    /// Reads the header of the record at `offset`.
+    ///
+    /// Returns `None` if the record is invalid or truncated.
+    fn read_record(
+        reader: &mut BufReader<impl Read>,
+        path: &Path,
+        params: &ChainParams,
+        offset: usize,
+        file_len: usize,
+    ) -> Result<Option<Record>> {
+        // First 8 bytes are 4 magic bytes and 4 bytes that indicate the block size
+        let mut buffer = [0; PRE_HEADER_SIZE];
+        if reader.read_exact(&mut buffer).is_err() {
+            return Ok(None);
+        }
+        let magic = Magic::from_bytes(buffer[..4].try_into()?);
+        if magic.to_bytes() != params.magic {
+            if let Some(other) = Network::from_magic(magic) {
+                bail!(
+                    "{:?} contains {} blocks, expected {}",
+                    path,
+                    other,
+                    params.name
+                )
+            }
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes(buffer[4..].try_into()?) as usize;
+        if offset + PRE_HEADER_SIZE + size > file_len {
+            return Ok(None);
+        }
+        Self::read_header(reader, params, size)
+    }
+
+    /// Reads the header of a block of `size` bytes, skipping any AuxPoW proof after it.
+    ///
+    /// Returns `None` if the header is invalid or does not fit in the block.
+    pub(crate) fn read_header(
+        reader: &mut BufReader<impl Read>,
+        params: &ChainParams,
+        size: usize,
+    ) -> Result<Option<Record>> {
+        if !(Header::SIZE..=params.max_block_size).contains(&size) {
+            return Ok(None);
+        }
+        if params.elements {
+            params.check_elements_headers()?;
+            // Signed headers have no proof-of-work to check
+            let Ok(header) = ElementsHeader::consensus_decode(reader) else {
+                return Ok(None);
+            };
+            let read = header.consensus_encode(&mut bitcoin::io::sink())?;
+            if read > size {
+                return Ok(None);
+            }
+            return Ok(Some(Record {
+                header: header.to_bitcoin(),
+                hash: header.block_hash(),
+                size,
+                read,
+            }));
+        }
+        let Ok(header) = Header::consensus_decode(reader) else {
+            return Ok(None);
+        };
+        let mut record = Record {
+            header,
+            hash: header.block_hash(),
+            size,
+            read: Header::SIZE,
+        };
+        if params.has_aux_pow(header.version.to_consensus()) {
+            // The proof-of-work is in the parent block of the AuxPoW proof
+            return match ChainParams::skip_aux_pow(reader) {
+                Ok(aux_pow_size) if Header::SIZE + aux_pow_size <= size => {
+                    record.read += aux_pow_size;
+                    Ok(Some(record))
+                }
+                _ => Ok(None),
+            };
+        }
+        // A header of zeros or garbage will not meet its own target
+        if params.hash_is_pow && !header.target().is_met_by(record.hash) {
+            return Ok(None);
+        }
+        Ok(Some(record))
+    }
+
+    /// Returns false if the record at `offset` may only be partially written, otherwise seeks
+    /// the `reader` to the end of the record.
+    ///
+    /// Bitcoin Core preallocates BLK files with zeros, so a block that is still being written
+    /// already fits in the file.  Records followed by another record or the end of the file are
+    /// complete, otherwise the transactions must decode and match the merkle root.
+    fn is_complete(
+        reader: &mut BufReader<impl Read + Seek>,
+        params: &ChainParams,
+        offset: usize,
+        record: &Record,
+        file_len: usize,
+    ) -> bool {
+        let start = offset + PRE_HEADER_SIZE;
+        let end = start + record.size;
+        let mut magic = [0; 4];
+        let next = reader
+            .seek(SeekFrom::Start(end as u64))
+            .and_then(|_| reader.read_exact(&mut magic));
+        if next.is_ok() && magic == params.magic {
+            // The next record is already in the buffer
+            return reader.seek_relative(-(magic.len() as i64)).is_ok();
+        }
+        let complete = end == file_len
+            || (reader.seek(SeekFrom::Start(start as u64)).is_ok() && {
+                let mut reader = BufReader::new(reader.by_ref().take(record.size as u64));
+                Self::has_merkle_root(&mut reader, params).unwrap_or(false)
+            });
+        complete && reader.seek(SeekFrom::Start(end as u64)).is_ok()
+    }
+
+    /// Decodes a block, returning true if its transactions match the merkle root in its header.
+    fn has_merkle_root(reader: &mut BufReader<impl Read>, params: &ChainParams) -> Result<bool> {
+        if params.elements {
+            return Ok(ElementsBlock::consensus_decode(reader)?.check_merkle_root());
+        }
+        let header = Header::consensus_decode(reader)?;
+        if params.has_aux_pow(header.version.to_consensus()) {
+            ChainParams::skip_aux_pow(reader)?;
+        }
+        let txdata = read_transactions(reader)?;
+        Ok(Block { header, txdata }.check_merkle_root())
+    }
+
+    /// Scans forward from `start` for the next `magic` bytes, returning their offset and whether
+    /// any non-zero bytes were scanned over.
+    fn find_magic(
+        reader: &mut (impl Read + Seek),
+        magic: Magic,
+        start: usize,
+    ) -> Result<(Option<usize>, bool)> {
+        let magic = magic.to_bytes();
+        let mut chunk = Vec::with_capacity(SCAN_CHUNK_SIZE);
+        let mut nonzero = false;
+        let mut pos = start;
+
+        loop {
+            chunk.clear();
+            reader.seek(SeekFrom::Start(pos as u64))?;
+            let len = reader
+                .by_ref()
+                .take(SCAN_CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            if let Some(found) = chunk.windows(magic.len()).position(|w| w == magic) {
+                nonzero |= chunk[..found].iter().any(|byte| *byte != 0);
+                return Ok((Some(pos + found), nonzero));
+            }
+            nonzero |= chunk.iter().any(|byte| *byte != 0);
+            if len < SCAN_CHUNK_SIZE {
+                return Ok((None, nonzero));
+            }
+            // The magic bytes may straddle two chunks
+            pos += len - (magic.len() - 1);
+        }
+    }
+
+    /// Returns true if the bytes at `offset` are the `magic` bytes that start a record.
+    fn starts_with_magic(
+        reader: &mut (impl Read + Seek),
+        magic: Magic,
+        offset: usize,
+    ) -> Result<bool> {
+        let mut buffer = [0; 4];
+        reader.seek(SeekFrom::Start(offset as u64))?;
+        Ok(reader.read_exact(&mut buffer).is_ok() && buffer == magic.to_bytes())
     }
 
     /// Returns the list of all BLK files in the dir
@@ -137,7 +1279,7 @@ impl HeaderParser {
 
     /// Reads the block XOR mask. If no `xor.dat` file is present,
     /// use all-zeroed array to perform an XOR no-op.
-    fn read_xor_mask<P: AsRef<Path>>(dir: P) -> Result<Option<[u8; XOR_MASK_LEN]>> {
+    pub(crate) fn read_xor_mask<P: AsRef<Path>>(dir: P) -> Result<Option<[u8; XOR_MASK_LEN]>> {
         let path = dir.as_ref().join("xor.dat");
         if !path.exists() {
             return Ok(None);
@@ -147,37 +1289,419 @@ impl HeaderParser {
         file.read_exact(&mut buf)?;
         Ok(Some(buf))
     }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::leveldb::tests::temp_dir;
+    use crate::source::MemoryBlocks;
+    use bitcoin::absolute::LockTime;
+    use bitcoin::block::Version;
+    use bitcoin::script::Builder;
+    use bitcoin::{
+        transaction, Amount, CompactTarget, OutPoint, ScriptBuf, Sequence, TxMerkleNode,
+    };
+    use bitcoin::{TxIn, TxOut, Witness};
+
+    /// Mines a regtest block on top of `prev` with a BIP34 coinbase.
+    pub(crate) fn mine(prev: &Header, height: i64) -> Block {
+        mine_with_bits(prev, height, CompactTarget::from_consensus(0x207fffff))
+    }
 
-    /// In case of reorgs we need to resolve to the longest chain
-    fn resolve_collisions(headers: &mut HashMap<BlockHash, ParsedHeader>, collision: ParsedHeader) {
-        let existing = headers
-            .get(&collision.inner.prev_blockhash)
-            .expect("Missing previous blockhash (corrupted blocks)");
-        let mut e_hash = &existing.hash;
-        let mut c_hash = &collision.hash;
+    /// Mines a block on top of `prev` with a BIP34 coinbase that meets the target of the `bits`.
+    pub(crate) fn mine_with_bits(prev: &Header, height: i64, bits: CompactTarget) -> Block {
+        let coinbase = Transaction {
+            version: transaction::Version::ONE,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height).push_int(0).into_script(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(50_0000_0000),
+                script_pubkey: ScriptBuf::from_bytes(vec![0x6a; 100]),
+            }],
+        };
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: prev.block_hash(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: prev.time + 600,
+                bits,
+                nonce: 0,
+            },
+            txdata: vec![coinbase],
+        };
+        solve(&mut block);
+        block
+    }
 
-        while let (Some(e), Some(c)) = (headers.get(e_hash), headers.get(c_hash)) {
-            e_hash = &e.hash;
-            c_hash = &c.hash;
+    /// Updates the merkle root of the `block` and mines it again.
+    pub(crate) fn solve(block: &mut Block) {
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
         }
+    }
 
-        // In case collision is the longest, update the blocks map
-        if headers.contains_key(c_hash) {
-            headers.insert(collision.inner.prev_blockhash, collision);
+    /// Returns a regtest chain of `len` blocks starting with the genesis block.
+    pub(crate) fn regtest_chain(len: usize) -> Vec<Block> {
+        let mut blocks = vec![bitcoin::constants::genesis_block(Network::Regtest)];
+        for height in 1..len {
+            let block = mine(&blocks[height - 1].header, height as i64);
+            blocks.push(block);
         }
+        blocks
     }
 
-    /// Puts the headers into the correct order by block height (using the hashes)
-    fn order_headers(mut headers: HashMap<BlockHash, ParsedHeader>) -> Vec<ParsedHeader> {
-        let mut ordered_headers = vec![];
-        // Genesis block starts with prev = all_zeros
-        let mut next_hash = BlockHash::all_zeros();
+    /// Returns a regtest chain of `len` blocks where every block from height 3 also spends the
+    /// coinbase from 2 blocks earlier into 2 outputs, then the second output of the previous
+    /// block's spend.
+    pub(crate) fn spending_chain(len: usize) -> Vec<Block> {
+        let mut blocks = regtest_chain(len.min(3));
+        for height in 3..len {
+            let mut block = mine(&blocks[height - 1].header, height as i64);
+            let spend = |txid, vout, value| Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::ZERO,
+                input: vec![TxIn {
+                    previous_output: OutPoint::new(txid, vout),
+                    script_sig: ScriptBuf::new(),
+                    sequence: Sequence::MAX,
+                    witness: Witness::new(),
+                }],
+                output: vec![
+                    TxOut {
+                        value,
+                        script_pubkey: ScriptBuf::from_bytes(vec![0x51]),
+                    };
+                    2
+                ],
+            };
+            let coinbase = blocks[height - 2].txdata[0].compute_txid();
+            block
+                .txdata
+                .push(spend(coinbase, 0, Amount::from_sat(25_0000_0000)));
+            if height > 3 {
+                let prev = blocks[height - 1].txdata[1].compute_txid();
+                block.txdata.push(spend(prev, 1, Amount::from_sat(100)));
+            }
+            solve(&mut block);
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Encodes the blocks as records of a regtest BLK file.
+    pub(crate) fn blk_records(blocks: &[Block]) -> Vec<u8> {
+        let mut out = vec![];
+        for block in blocks {
+            let data = bitcoin::consensus::serialize(block);
+            out.extend(Network::Regtest.magic().to_bytes());
+            out.extend((data.len() as u32).to_le_bytes());
+            out.extend(data);
+        }
+        out
+    }
+
+    #[test]
+    fn cache_appends_changed_files() {
+        let dir = temp_dir("header-cache");
+        let blocks_dir = dir.to_str().unwrap();
+        let blocks = regtest_chain(7);
+        fs::write(dir.join("blk00000.dat"), blk_records(&blocks[..3])).unwrap();
+        fs::write(dir.join("blk00001.dat"), blk_records(&blocks[3..5])).unwrap();
+        let cache_file = dir.join("headers.cache").to_str().unwrap().to_string();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            header_cache: Some(cache_file.clone()),
+            ..ParserOptions::default()
+        };
+
+        let chain = HeaderParser::parse_chain(blocks_dir, &options).unwrap();
+        assert_eq!(chain.active.len(), 5);
+
+        let mut blk = fs::OpenOptions::new()
+            .append(true)
+            .open(dir.join("blk00001.dat"))
+            .unwrap();
+        blk.write_all(&blk_records(&blocks[5..])).unwrap();
+        drop(blk);
+        let chain = HeaderParser::parse_chain(blocks_dir, &options).unwrap();
+        let hashes: Vec<_> = chain.active.iter().map(|header| header.hash).collect();
+        let expected: Vec<_> = blocks.iter().map(|block| block.block_hash()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(chain.active[6].height, 6);
+        assert_eq!(
+            chain.active[6].chainwork,
+            chain.active[5].chainwork + blocks[6].header.work()
+        );
+
+        // Only the grown file was appended to the cache
+        let mut cache = HeaderCache::open(None, &options);
+        assert_eq!((cache.files.len(), cache.written), (2, 3));
+        assert_eq!(cache.update(blocks_dir).unwrap(), 0);
+        assert_eq!(cache.headers().count(), 7);
+        // Rescanning a modified file without finding new headers does not change the chain
+        let blk = dir.join("blk00001.dat");
+        cache.files.get_mut(&blk).unwrap().modified = None;
+        assert_eq!(cache.update(blocks_dir).unwrap(), 0);
+        assert!(cache.changed.contains(&blk));
 
-        while let Some(index) = headers.remove(&next_hash) {
-            next_hash = index.hash;
-            ordered_headers.push(index);
+        // A file rewritten in a different order, such as by a reindex, is scanned from the start
+        let blk0 = dir.join("blk00000.dat");
+        let reordered = [&blocks[1], &blocks[0], &blocks[2], &blocks[5]].map(Block::clone);
+        let data = blk_records(&reordered);
+        assert!(data.len() as u64 > fs::metadata(&blk0).unwrap().len());
+        fs::write(&blk0, data).unwrap();
+        let mut rewritten = cache.clone();
+        assert_eq!(rewritten.update(blocks_dir).unwrap(), 1);
+        let locations = |headers: &[CachedHeader]| -> Vec<_> {
+            let locations = headers
+                .iter()
+                .map(|header| (header.hash, header.header_offset));
+            locations.collect()
+        };
+        let (scanned, _, _) =
+            HeaderParser::parse_headers_file(blk0.clone(), None, &options.params(), 0).unwrap();
+        let scanned: Vec<_> = scanned.into_iter().map(CachedHeader::new).collect();
+        assert_eq!(
+            locations(&rewritten.files[&blk0].headers),
+            locations(&scanned)
+        );
+        assert_eq!(scanned[0].hash, blocks[1].block_hash());
+        fs::write(&blk0, blk_records(&blocks[..3])).unwrap();
+
+        // A partially written entry is dropped and the cache is rewritten
+        let len = fs::metadata(&cache_file).unwrap().len();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(&cache_file)
+            .unwrap();
+        file.set_len(len - 1).unwrap();
+        let mut cache = HeaderCache::open(None, &options);
+        assert_eq!(cache.headers().count(), 5);
+        assert_eq!(cache.update(blocks_dir).unwrap(), 1);
+        cache.save(&cache_file).unwrap();
+        let cache = HeaderCache::open(None, &options);
+        assert_eq!((cache.headers().count(), cache.written), (7, 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn chooses_chain_with_most_work() {
+        let blocks = regtest_chain(3);
+        let mut longer = vec![];
+        let mut prev = blocks[2].header;
+        for height in 3..6 {
+            let block = mine(&prev, height);
+            prev = block.header;
+            longer.push(block);
         }
+        // A lower target means a single block has more work than the longer branch
+        let harder = mine_with_bits(
+            &blocks[2].header,
+            3,
+            CompactTarget::from_consensus(0x1f7fffff),
+        );
+        let longer_work = longer
+            .iter()
+            .fold(Work::from_be_bytes([0; 32]), |work, block| {
+                work + block.header.work()
+            });
+        assert!(harder.header.work() > longer_work);
+
+        let all = blocks.iter().chain(&longer).chain([&harder]).cloned();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let chain = MemoryBlocks::new(all).chain(&options).unwrap();
+        let hashes: Vec<_> = chain.active.iter().map(|header| header.hash).collect();
+        let mut expected: Vec<_> = blocks.iter().map(|block| block.block_hash()).collect();
+        expected.push(harder.block_hash());
+        assert_eq!(hashes, expected);
+
+        let mut chainwork = Work::from_be_bytes([0; 32]);
+        for (header, block) in chain.active.iter().zip(blocks.iter().chain([&harder])) {
+            chainwork = chainwork + block.header.work();
+            assert_eq!(header.chainwork, chainwork, "height {}", header.height);
+        }
+        assert_eq!(chain.stale.len(), 1);
+        let branch = &chain.stale[0];
+        assert_eq!((branch.fork_height, branch.len()), (2, 3));
+        let tip_work = chain.active[2].chainwork + longer_work;
+        assert_eq!(branch.headers[2].chainwork, tip_work);
+        assert!(tip_work < chain.active[3].chainwork);
+    }
+
+    #[test]
+    fn continues_after_gaps_with_bip34_heights() {
+        let dir = temp_dir("gaps");
+        let blocks_dir = dir.to_str().unwrap();
+        let blocks = regtest_chain(24);
+        // Block 5 is pushed with OP_5 and block 20 with 1 byte
+        let mut present: Vec<Block> = blocks
+            .iter()
+            .enumerate()
+            .filter(|(height, _)| *height != 4 && *height != 19)
+            .map(|(_, block)| block.clone())
+            .collect();
+        // A fork with less work after the last gap
+        let fork = mine(&blocks[21].header, 220);
+        present.push(fork.clone());
+        fs::write(dir.join("blk00000.dat"), blk_records(&present)).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            gap_policy: GapPolicy::Continue,
+            ..ParserOptions::default()
+        };
+
+        let chain = HeaderParser::parse_chain(blocks_dir, &options).unwrap();
+        let gaps: Vec<_> = chain.report.gaps.iter().map(|gap| gap.height).collect();
+        assert_eq!(gaps, vec![Some(4), Some(19)]);
+        let heights: Vec<_> = chain.active.iter().map(|header| header.height).collect();
+        let expected: Vec<_> = (0..24)
+            .filter(|height| *height != 4 && *height != 19)
+            .collect();
+        assert_eq!(heights, expected);
+        for header in &chain.active {
+            assert_eq!(header.hash, blocks[header.height].block_hash());
+        }
+        assert_eq!(chain.stale.len(), 1);
+        assert_eq!(chain.stale[0].fork_height, 21);
+        let stale = &chain.stale[0].headers;
+        assert_eq!((stale.len(), stale[0].height), (1, 22));
+        assert_eq!(stale[0].hash, fork.block_hash());
+        let fork_parent = chain.active.iter().find(|header| header.height == 21);
+        assert_eq!(
+            stale[0].chainwork,
+            fork_parent.unwrap().chainwork + fork.header.work()
+        );
+
+        // Without BIP34 the heights are unknown
+        let options = ParserOptions {
+            chain_params: Some(ChainParams {
+                bip34_height: None,
+                ..ChainParams::bitcoin(Network::Regtest)
+            }),
+            ..options
+        };
+        let chain = HeaderParser::parse_chain(blocks_dir, &options).unwrap();
+        let gaps: Vec<_> = chain.report.gaps.iter().map(|gap| gap.height).collect();
+        assert_eq!(gaps, vec![None, None]);
+        assert_eq!(chain.active.len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_blocks_from_other_networks() {
+        let dir = temp_dir("other-network");
+        let blocks_dir = dir.to_str().unwrap();
+        let blocks = regtest_chain(4);
+        fs::write(dir.join("blk00000.dat"), blk_records(&blocks[..3])).unwrap();
+        let options = ParserOptions {
+            network: Network::Testnet4,
+            ..ParserOptions::default()
+        };
+        let err = HeaderParser::parse_chain(blocks_dir, &options).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("contains regtest blocks, expected testnet4"),
+            "{}",
+            err
+        );
+
+        // A single file from another network is enough to fail
+        let mut other = blk_records(&blocks[3..]);
+        other[..4].copy_from_slice(&Network::Testnet4.magic().to_bytes());
+        fs::write(dir.join("blk00001.dat"), other).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let err = HeaderParser::parse_chain(blocks_dir, &options).unwrap_err();
+        assert!(err.to_string().contains("blk00001.dat"), "{}", err);
+        assert!(
+            err.to_string()
+                .ends_with("contains testnet4 blocks, expected regtest"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skips_garbage_between_blocks() {
+        let dir = temp_dir("garbage");
+        let blocks_dir = dir.to_str().unwrap();
+        let blocks = regtest_chain(5);
+        let path = dir.join("blk00000.dat");
+        let mut data = blk_records(&blocks[..2]);
+        let garbage = data.len()..data.len() + 100;
+        data.extend([0xab; 100]);
+        data.extend(blk_records(&blocks[2..]));
+        fs::write(&path, &data).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+
+        let chain = HeaderParser::parse_chain(blocks_dir, &options).unwrap();
+        let hashes: Vec<_> = chain.active.iter().map(|header| header.hash).collect();
+        let expected: Vec<_> = blocks.iter().map(|block| block.block_hash()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(chain.active[2].header_offset, garbage.end + PRE_HEADER_SIZE);
+        assert_eq!(
+            chain.report.skipped,
+            BTreeMap::from([(path, vec![garbage])])
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn ignores_partially_written_blocks() {
+        let dir = temp_dir("partial-block");
+        let blocks_dir = dir.to_str().unwrap();
+        let blocks = regtest_chain(5);
+        let path = dir.join("blk00000.dat");
+        // Bitcoin Core preallocates the file with zeros before writing the blocks into it
+        let written = blk_records(&blocks[..4]);
+        let last = blk_records(&blocks[4..]);
+        let mut data = written.clone();
+        data.extend(&last[..last.len() / 2]);
+        data.resize(written.len() + 4096, 0);
+        fs::write(&path, &data).unwrap();
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+
+        let mut cache = HeaderCache::open(None, &options);
+        cache.update(blocks_dir).unwrap();
+        assert_eq!(cache.files[&path].end, written.len());
+        let chain = HeaderParser::resolve_cache(&cache, &options).unwrap();
+        assert_eq!(chain.active.len(), 4);
+        // The partial block is not yet written rather than garbage
+        assert!(cache.files[&path].skipped.is_empty());
+        assert!(chain.report.skipped.is_empty());
+        cache.files.get_mut(&path).unwrap().modified = None;
+        assert_eq!(cache.update(blocks_dir).unwrap(), 0);
+        assert!(cache.files[&path].skipped.is_empty());
 
-        ordered_headers
+        // Once the rest of the block is written it is found by the next scan
+        data[written.len()..written.len() + last.len()].copy_from_slice(&last);
+        fs::write(&path, &data).unwrap();
+        cache.files.get_mut(&path).unwrap().modified = None;
+        assert_eq!(cache.update(blocks_dir).unwrap(), 1);
+        assert_eq!(cache.files[&path].end, written.len() + last.len());
+        assert!(cache.files[&path].skipped.is_empty());
+        let chain = HeaderParser::resolve_cache(&cache, &options).unwrap();
+        assert_eq!(chain.active.len(), 5);
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -0,0 +1,492 @@
+//! Reads Bitcoin Core's block index from the `blocks/index` LevelDB database in order to locate
+//! every block without scanning the BLK files.
+//!
+//! - See https://github.com/bitcoin/bitcoin/blob/master/src/chain.h
+
+use crate::headers::{HeaderChain, ParsedHeader};
+use crate::leveldb;
+use crate::xor::XOR_MASK_LEN;
+use anyhow::{bail, Result};
+use bitcoin::block::Header;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::Hash;
+use bitcoin::pow::Work;
+use bitcoin::BlockHash;
+use log::info;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Key prefix of block index records in the LevelDB database
+const BLOCK_INDEX_PREFIX: u8 = b'b';
+
+/// The block has been validated up to and including its scripts (it was on the active chain)
+pub const BLOCK_VALID_SCRIPTS: u32 = 5;
+/// Mask for all the validity levels of a block
+pub const BLOCK_VALID_MASK: u32 = 7;
+/// The full block is available in a BLK file
+pub const BLOCK_HAVE_DATA: u32 = 8;
+/// The undo data is available in a REV file
+pub const BLOCK_HAVE_UNDO: u32 = 16;
+/// The block failed validation
+pub const BLOCK_FAILED_VALID: u32 = 32;
+/// The block descends from a block that failed validation
+pub const BLOCK_FAILED_CHILD: u32 = 64;
+
+/// A single block entry from Bitcoin Core's block index.
+#[derive(Clone, Debug)]
+pub struct IndexRecord {
+    /// Consensus parsed `bitcoin::Header`
+    pub header: Header,
+    /// This header's block hash
+    pub hash: BlockHash,
+    /// Height of the block in its chain
+    pub height: usize,
+    /// Validity and storage flags (e.g. [`BLOCK_HAVE_DATA`])
+    pub status: u32,
+    /// Number of transactions in the block
+    pub num_tx: u32,
+    /// Number of the BLK and REV files containing the block data
+    pub file: Option<usize>,
+    /// Byte offset of the block header in the BLK file
+    pub data_pos: Option<usize>,
+    /// Byte offset of the undo data in the REV file
+    pub undo_pos: Option<usize>,
+    /// Cumulative work of the chain up to and including this block
+    pub chainwork: Work,
+}
+
+impl IndexRecord {
+    /// Returns true if the full block is stored in a BLK file.
+    pub fn has_data(&self) -> bool {
+        self.status & BLOCK_HAVE_DATA != 0
+    }
+
+    /// Returns true if the block or one of its ancestors failed validation.
+    pub fn is_failed(&self) -> bool {
+        self.status & (BLOCK_FAILED_VALID | BLOCK_FAILED_CHILD) != 0
+    }
+
+    /// Returns true if the block has been fully validated and connected at some point.
+    pub fn is_valid(&self) -> bool {
+        self.status & BLOCK_VALID_MASK >= BLOCK_VALID_SCRIPTS && !self.is_failed()
+    }
+
+    /// Decodes a `CDiskBlockIndex` value.
+    fn decode(mut value: &[u8]) -> Result<Self> {
+        let reader = &mut value;
+        let _client_version = read_varint(reader)?;
+        let height = read_varint(reader)? as usize;
+        let status = read_varint(reader)? as u32;
+        let num_tx = read_varint(reader)? as u32;
+        let mut file = None;
+        let mut data_pos = None;
+        let mut undo_pos = None;
+        if status & (BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO) != 0 {
+            file = Some(read_varint(reader)? as usize);
+        }
+        if status & BLOCK_HAVE_DATA != 0 {
+            data_pos = Some(read_varint(reader)? as usize);
+        }
+        if status & BLOCK_HAVE_UNDO != 0 {
+            undo_pos = Some(read_varint(reader)? as usize);
+        }
+        let header = Header::consensus_decode(reader)?;
+
+        Ok(Self {
+            header,
+            hash: header.block_hash(),
+            height,
+            status,
+            num_tx,
+            file,
+            data_pos,
+            undo_pos,
+            chainwork: Work::from_be_bytes([0; 32]),
+        })
+    }
+}
+
+/// Bitcoin Core's view of every block it knows about, read from `blocks/index`.
+///
+/// Reading the index takes a fraction of the time of scanning every BLK file with
+/// [`crate::HeaderParser::parse`].  The database is read directly from disk without taking a lock,
+/// so results may be stale if `bitcoind` is writing at the same time.
+#[derive(Clone, Debug)]
+pub struct BlockIndex {
+    /// Every record in the index keyed by block hash
+    records: HashMap<BlockHash, IndexRecord>,
+}
+
+impl BlockIndex {
+    /// Reads the block index from the `index` directory inside of the `blocks` directory.
+    /// - Returns an `Err` if the database is missing or corrupted.
+    pub fn read(blocks_dir: &str) -> Result<Self> {
+        let index_dir = Path::new(blocks_dir).join("index");
+        info!("Reading block index from {:?}", index_dir);
+        if !index_dir.is_dir() {
+            bail!("No block index found in dir {:?}", index_dir);
+        }
+
+        let mut records = vec![];
+        for (key, value) in leveldb::read_prefix(&index_dir, &[BLOCK_INDEX_PREFIX])? {
+            let record = IndexRecord::decode(&value)?;
+            if key[1..] != record.hash.as_byte_array()[..] {
+                bail!("Block index record has mismatched hash {}", record.hash);
+            }
+            records.push(record);
+        }
+
+        // Parents always have a lower height so their chainwork will already be computed
+        records.sort_by_key(|record| record.height);
+        let mut index: HashMap<BlockHash, IndexRecord> = HashMap::default();
+        for mut record in records {
+            let prev_work = match index.get(&record.header.prev_blockhash) {
+                Some(prev) => prev.chainwork,
+                None if record.header.prev_blockhash == BlockHash::all_zeros() => {
+                    Work::from_be_bytes([0; 32])
+                }
+                None => bail!("Block index is missing parent of {}", record.hash),
+            };
+            record.chainwork = prev_work + record.header.work();
+            index.insert(record.hash, record);
+        }
+
+        info!("Finished reading {} block index records", index.len());
+        Ok(Self { records: index })
+    }
+
+    /// Returns the record for a block hash if it exists in the index.
+    pub fn get(&self, hash: &BlockHash) -> Option<&IndexRecord> {
+        self.records.get(hash)
+    }
+
+    /// Returns the number of records in the index, including blocks that are not in the active
+    /// chain or that have no data.
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Returns true if the index contains no records.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Returns the records of the active chain in height order, starting from the genesis block.
+    ///
+    /// The tip is the valid block with the most chainwork, ties are broken by the earliest
+    /// position on disk which is the block that was received first.
+    pub fn active_chain(&self) -> Result<Vec<&IndexRecord>> {
+        let tip = self
+            .records
+            .values()
+            .filter(|record| record.is_valid() && record.has_data())
+            .max_by(|a, b| {
+                let a_pos = (a.file, a.data_pos);
+                let b_pos = (b.file, b.data_pos);
+                a.chainwork.cmp(&b.chainwork).then(b_pos.cmp(&a_pos))
+            });
+
+        let mut chain = vec![];
+        let mut next = tip;
+        while let Some(record) = next {
+            if !record.has_data() {
+                bail!("Block index has no data for block {}", record.hash);
+            }
+            chain.push(record);
+            next = self.records.get(&record.header.prev_blockhash);
+        }
+        chain.reverse();
+
+        for (height, record) in chain.iter().enumerate() {
+            if record.height != height {
+                bail!(
+                    "Block index has {} at wrong height {}",
+                    record.hash,
+                    record.height
+                );
+            }
+        }
+        Ok(chain)
+    }
+
+    /// Returns the [`HeaderChain`] containing the active chain in height order, starting from the
+    /// genesis block, and every stale branch that still has its block data.
+    ///
+    /// Blocks that build on the tip but have not been connected yet are not included.  The index
+    /// does not store block sizes, so [`ParsedHeader::size`] is left unknown.
+    pub fn chain(
+        &self,
+        blocks_dir: &str,
+        xor_mask: Option<[u8; XOR_MASK_LEN]>,
+    ) -> Result<HeaderChain> {
+        let mut headers = vec![];
+        for record in self.active_chain()? {
+            match Self::parsed_header(record, blocks_dir, xor_mask) {
+                Some(header) => headers.push(header),
+                None => bail!("Block index has no data for block {}", record.hash),
+            }
+        }
+        let num_active = headers.len();
+        let active: HashSet<BlockHash> = headers.iter().map(|header| header.hash).collect();
+        let tip = headers.last().map(|header| header.hash);
+        headers.extend(
+            self.records
+                .values()
+                .filter(|record| !record.is_failed() && !active.contains(&record.hash))
+                .filter(|record| self.fork_point(record, &active) != tip)
+                .filter_map(|record| Self::parsed_header(record, blocks_dir, xor_mask)),
+        );
+
+        let stale = headers.split_off(num_active);
+        Ok(HeaderChain::new(headers, stale.into_iter()))
+    }
+
+    /// Returns the hash of the last `active` block the record descends from.
+    ///
+    /// Blocks that descend from the tip have not been connected yet (e.g. during IBD), so they
+    /// are not stale.
+    fn fork_point(&self, record: &IndexRecord, active: &HashSet<BlockHash>) -> Option<BlockHash> {
+        let mut next = record.header.prev_blockhash;
+        while !active.contains(&next) {
+            next = self.records.get(&next)?.header.prev_blockhash;
+        }
+        Some(next)
+    }
+
+    /// Converts a record into a [`ParsedHeader`] if the block data is available.
+    fn parsed_header(
+        record: &IndexRecord,
+        blocks_dir: &str,
+        xor_mask: Option<[u8; XOR_MASK_LEN]>,
+    ) -> Option<ParsedHeader> {
+        let (Some(file), Some(data_pos)) = (record.file, record.data_pos) else {
+            return None;
+        };
+        Some(ParsedHeader {
+            inner: record.header,
+            offset: data_pos + Header::SIZE,
+            header_offset: data_pos,
+            hash: record.hash,
+            path: Self::blk_path(blocks_dir, file),
+            xor_mask,
+            chainwork: record.chainwork,
+            height: record.height,
+            size: 0,
+        })
+    }
+
+    /// Path to the BLK file with the given number.
+    pub fn blk_path(blocks_dir: &str, file: usize) -> PathBuf {
+        Path::new(blocks_dir).join(format!("blk{:05}.dat", file))
+    }
+
+    /// Path to the REV file with the given number, which contains the undo data of the blocks in
+    /// the BLK file with the same number.
+    pub fn rev_path(blocks_dir: &str, file: usize) -> PathBuf {
+        Path::new(blocks_dir).join(format!("rev{:05}.dat", file))
+    }
+}
+
+/// Reads a Bitcoin Core `VARINT` (MSB base-128 encoding with an offset for each continuation).
+pub(crate) fn read_varint(reader: &mut &[u8]) -> Result<u64> {
+    let mut result: u64 = 0;
+    loop {
+        let Some((&byte, rest)) = reader.split_first() else {
+            bail!("VARINT ended unexpectedly");
+        };
+        *reader = rest;
+        if result > (u64::MAX >> 7) {
+            bail!("VARINT is too large");
+        }
+        result = (result << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+        result += 1;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::headers::HeaderInfoIter;
+    use crate::leveldb::tests::{temp_dir, write_db};
+    use bitcoin::block::Version;
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Block, CompactTarget, Network, TxMerkleNode};
+    use std::fs;
+
+    /// Encodes a Bitcoin Core `VARINT`.
+    pub(crate) fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+        let mut bytes = vec![(value & 0x7f) as u8];
+        while value > 0x7f {
+            value = (value >> 7) - 1;
+            bytes.push((value & 0x7f) as u8 | 0x80);
+        }
+        out.extend(bytes.iter().rev());
+    }
+
+    pub(crate) fn header(prev: BlockHash, nonce: u32) -> Header {
+        Header {
+            version: Version::TWO,
+            prev_blockhash: prev,
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_700_000_000 + nonce,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce,
+        }
+    }
+
+    /// Encodes a `CDiskBlockIndex` value.
+    pub(crate) fn index_value(header: &Header, height: u64, status: u32, pos: &[u64]) -> Vec<u8> {
+        let mut out = vec![];
+        for value in [260000, height, status as u64, 1].iter().chain(pos) {
+            put_varint(&mut out, *value);
+        }
+        out.extend(serialize(header));
+        out
+    }
+
+    #[test]
+    fn reads_varints() {
+        let vectors: [(u64, &[u8]); 8] = [
+            (0, &[0x00]),
+            (127, &[0x7f]),
+            (128, &[0x80, 0x00]),
+            (255, &[0x80, 0x7f]),
+            (16383, &[0xfe, 0x7f]),
+            (16384, &[0xff, 0x00]),
+            (65535, &[0x82, 0xfe, 0x7f]),
+            (1 << 32, &[0x8e, 0xfe, 0xfe, 0xff, 0x00]),
+        ];
+        for (value, bytes) in vectors {
+            let mut reader = bytes;
+            assert_eq!(read_varint(&mut reader).unwrap(), value);
+            assert!(reader.is_empty());
+            let mut encoded = vec![];
+            put_varint(&mut encoded, value);
+            assert_eq!(encoded, bytes);
+        }
+        assert!(read_varint(&mut &[0x80][..]).is_err());
+        assert!(read_varint(&mut &[0xff; 10][..]).is_err());
+    }
+
+    #[test]
+    fn decodes_records() {
+        let header = header(BlockHash::all_zeros(), 1);
+        let status = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA | BLOCK_HAVE_UNDO;
+        let record =
+            IndexRecord::decode(&index_value(&header, 200, status, &[3, 500, 70])).unwrap();
+        assert_eq!(record.hash, header.block_hash());
+        assert_eq!(record.height, 200);
+        assert_eq!(record.num_tx, 1);
+        assert_eq!(
+            (record.file, record.data_pos, record.undo_pos),
+            (Some(3), Some(500), Some(70))
+        );
+        assert!(record.is_valid() && record.has_data());
+
+        let status = BLOCK_VALID_SCRIPTS | BLOCK_FAILED_CHILD;
+        let record = IndexRecord::decode(&index_value(&header, 5, status, &[])).unwrap();
+        assert_eq!(
+            (record.file, record.data_pos, record.undo_pos),
+            (None, None, None)
+        );
+        assert!(record.is_failed() && !record.is_valid() && !record.has_data());
+
+        let value = index_value(&header, 5, BLOCK_HAVE_DATA, &[0, 8]);
+        assert!(IndexRecord::decode(&value[..value.len() - 1]).is_err());
+    }
+
+    /// Writes the headers to `blk00000.dat` and returns the index values pointing at them.
+    pub(crate) fn write_blocks(
+        dir: &Path,
+        blocks: &[(Header, u64, u32)],
+    ) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut blk = vec![];
+        let mut entries = vec![];
+        for (header, height, status) in blocks {
+            let block = [serialize(header), vec![0]].concat();
+            blk.extend([0xfa, 0xbf, 0xb5, 0xda]);
+            blk.extend((block.len() as u32).to_le_bytes());
+            let pos = [0, blk.len() as u64];
+            blk.extend(block);
+            let key = [
+                &[BLOCK_INDEX_PREFIX][..],
+                header.block_hash().as_byte_array(),
+            ]
+            .concat();
+            entries.push((key, index_value(header, *height, *status, &pos)));
+        }
+        fs::write(dir.join("blk00000.dat"), blk).unwrap();
+        entries
+    }
+
+    /// Writes the regtest `blocks` to `blk00000.dat` and indexes them as the active chain.
+    pub(crate) fn write_index(dir: &Path, blocks: &[Block]) {
+        let mut blk = vec![];
+        let mut entries = vec![];
+        for (height, block) in blocks.iter().enumerate() {
+            let data = serialize(block);
+            blk.extend(Network::Regtest.magic().to_bytes());
+            blk.extend((data.len() as u32).to_le_bytes());
+            let pos = [0, blk.len() as u64];
+            blk.extend(data);
+            let key = [
+                &[BLOCK_INDEX_PREFIX][..],
+                block.block_hash().as_byte_array(),
+            ]
+            .concat();
+            let status = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA;
+            entries.push((key, index_value(&block.header, height as u64, status, &pos)));
+        }
+        fs::write(dir.join("blk00000.dat"), blk).unwrap();
+        write_db(&dir.join("index"), &entries);
+    }
+
+    #[test]
+    fn reads_chain() {
+        let dir = temp_dir("block-index");
+        let valid = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA;
+        let genesis = header(BlockHash::all_zeros(), 0);
+        let a1 = header(genesis.block_hash(), 1);
+        let a2 = header(a1.block_hash(), 2);
+        let b2 = header(a1.block_hash(), 3);
+        let c2 = header(a1.block_hash(), 4);
+        // Downloaded ahead of the tip but not connected yet
+        let a3 = header(a2.block_hash(), 5);
+        let a4 = header(a3.block_hash(), 6);
+        let blocks = [
+            (genesis, 0, valid),
+            (a1, 1, valid),
+            (a2, 2, valid),
+            (b2, 2, BLOCK_HAVE_DATA | 3),
+            (c2, 2, BLOCK_HAVE_DATA | BLOCK_FAILED_VALID),
+            (a3, 3, BLOCK_HAVE_DATA | 3),
+            (a4, 4, BLOCK_HAVE_DATA | 3),
+        ];
+        let entries = write_blocks(&dir, &blocks);
+        write_db(&dir.join("index"), &entries);
+
+        let blocks_dir = dir.to_str().unwrap();
+        let index = BlockIndex::read(blocks_dir).unwrap();
+        assert_eq!(index.len(), 7);
+        let chain = index.chain(blocks_dir, None).unwrap();
+        let active: Vec<_> = chain.active.iter().map(|h| h.hash).collect();
+        let expected = [genesis, a1, a2].map(|h| h.block_hash());
+        assert_eq!(active, expected);
+        assert!(chain.active.iter().all(|h| h.size == 0));
+        let sizes: Vec<_> = HeaderInfoIter::new(&chain.active, 0)
+            .map(|i| i.size)
+            .collect();
+        assert_eq!(sizes, vec![Header::SIZE + 1; 3]);
+        assert_eq!(
+            chain.active[2].chainwork,
+            a2.work() + a1.work() + genesis.work()
+        );
+        assert_eq!(chain.stale.len(), 1);
+        assert_eq!(chain.stale[0].fork_height, 1);
+        assert_eq!(chain.stale[0].headers[0].hash, b2.block_hash());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
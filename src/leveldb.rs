@@ -0,0 +1,715 @@
+//! Minimal read-only LevelDB reader used to load Bitcoin Core's `blocks/index` database.
+//!
+//! Only supports what is needed to read every live key-value pair: the `CURRENT` and `MANIFEST`
+//! files listing the live files, table files (`*.ldb` and `*.sst`) and write-ahead logs (`*.log`).
+//! The newest sequence number wins for each key.
+//!
+//! - See https://github.com/google/leveldb/blob/main/doc/impl.md
+//! - See https://github.com/google/leveldb/blob/main/doc/table_format.md
+//! - See https://github.com/google/leveldb/blob/main/doc/log_format.md
+
+use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Size of the footer at the end of every table file
+const FOOTER_SIZE: usize = 48;
+/// Magic number at the end of every table file
+const TABLE_MAGIC: u64 = 0xdb4775248b80fb57;
+/// Every block in a table file ends with a 1 byte compression type and a 4 byte CRC
+const BLOCK_TRAILER_SIZE: usize = 5;
+/// Log files are written in fixed size blocks
+const LOG_BLOCK_SIZE: usize = 32 * 1024;
+/// Log records start with a 4 byte CRC, 2 byte length and 1 byte type
+const LOG_HEADER_SIZE: usize = 7;
+
+/// Version edit tag of the comparator name
+const TAG_COMPARATOR: u32 = 1;
+/// Version edit tag of the oldest log file that is still live
+const TAG_LOG_NUMBER: u32 = 2;
+/// Version edit tag of the next file number
+const TAG_NEXT_FILE_NUMBER: u32 = 3;
+/// Version edit tag of the last sequence number
+const TAG_LAST_SEQUENCE: u32 = 4;
+/// Version edit tag of the compaction pointer of a level
+const TAG_COMPACT_POINTER: u32 = 5;
+/// Version edit tag of a table file that was removed
+const TAG_DELETED_FILE: u32 = 6;
+/// Version edit tag of a table file that was added
+const TAG_NEW_FILE: u32 = 7;
+/// Version edit tag of the log file that was being compacted
+const TAG_PREV_LOG_NUMBER: u32 = 9;
+
+/// Value type tag of a deleted key
+const TYPE_DELETION: u8 = 0;
+/// Value type tag of a key that has a value
+const TYPE_VALUE: u8 = 1;
+
+/// Latest entry seen for a key, `None` if it was deleted
+type Entry = (u64, Option<Vec<u8>>);
+
+/// Reads every live key-value pair whose key starts with `prefix` from the database in `dir`.
+pub(crate) fn read_prefix(dir: &Path, prefix: &[u8]) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut entries: HashMap<Vec<u8>, Entry> = HashMap::default();
+    let mut insert = |key: &[u8], seq: u64, value: Option<&[u8]>| {
+        if !key.starts_with(prefix) {
+            return;
+        }
+        match entries.get(key) {
+            Some((existing, _)) if *existing > seq => {}
+            _ => {
+                entries.insert(key.to_vec(), (seq, value.map(|v| v.to_vec())));
+            }
+        }
+    };
+
+    let files = LiveFiles::read(dir)?;
+    for path in files.tables(dir)? {
+        read_table(&fs::read(&path)?, &mut insert)?;
+    }
+    for path in files.logs(dir)? {
+        read_log(&fs::read(&path)?, &mut insert)?;
+    }
+
+    Ok(entries
+        .into_iter()
+        .filter_map(|(key, (_, value))| value.map(|value| (key, value)))
+        .collect())
+}
+
+/// The files that make up the current version of the database according to its `MANIFEST`.
+///
+/// Files left behind by a crash or an unfinished compaction are ignored, since they may contain
+/// entries that have already been deleted or overwritten.
+#[derive(Debug, Default)]
+struct LiveFiles {
+    /// Numbers of the live table files
+    tables: HashSet<u64>,
+    /// Logs with a lower number have already been compacted into tables
+    log_number: u64,
+    /// Log that was being compacted when the `MANIFEST` was written (0 if none)
+    prev_log_number: u64,
+}
+
+impl LiveFiles {
+    /// Replays the version edits in the `MANIFEST` named by the `CURRENT` file.
+    fn read(dir: &Path) -> Result<Self> {
+        let current = fs::read_to_string(dir.join("CURRENT"))?;
+        let manifest = current.trim_end_matches('\n');
+        if !manifest.starts_with("MANIFEST-") || manifest.contains(['/', '\\']) {
+            bail!("LevelDB CURRENT names invalid manifest {:?}", manifest);
+        }
+
+        let mut files = Self::default();
+        for edit in log_records(&fs::read(dir.join(manifest))?)? {
+            files.apply(&edit)?;
+        }
+        Ok(files)
+    }
+
+    /// Applies a single version edit record.
+    fn apply(&mut self, edit: &[u8]) -> Result<()> {
+        let mut cursor = Cursor::new(edit);
+        while !cursor.is_empty() {
+            match cursor.varint()? as u32 {
+                TAG_COMPARATOR => {
+                    cursor.length_prefixed()?;
+                }
+                TAG_LOG_NUMBER => self.log_number = cursor.varint()?,
+                TAG_PREV_LOG_NUMBER => self.prev_log_number = cursor.varint()?,
+                TAG_NEXT_FILE_NUMBER | TAG_LAST_SEQUENCE => {
+                    cursor.varint()?;
+                }
+                TAG_COMPACT_POINTER => {
+                    let _level = cursor.varint()?;
+                    cursor.length_prefixed()?;
+                }
+                TAG_DELETED_FILE => {
+                    let _level = cursor.varint()?;
+                    self.tables.remove(&cursor.varint()?);
+                }
+                TAG_NEW_FILE => {
+                    let _level = cursor.varint()?;
+                    self.tables.insert(cursor.varint()?);
+                    let _size = cursor.varint()?;
+                    let _smallest = cursor.length_prefixed()?;
+                    let _largest = cursor.length_prefixed()?;
+                }
+                tag => bail!("LevelDB manifest has unknown tag {}", tag),
+            }
+        }
+        Ok(())
+    }
+
+    /// Paths of the live table files, which use the `.ldb` or legacy `.sst` extension.
+    fn tables(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut numbers: Vec<_> = self.tables.iter().collect();
+        numbers.sort();
+        let mut paths = vec![];
+        for number in numbers {
+            let ldb = dir.join(format!("{:06}.ldb", number));
+            let sst = dir.join(format!("{:06}.sst", number));
+            match (ldb.exists(), sst.exists()) {
+                (true, _) => paths.push(ldb),
+                (false, true) => paths.push(sst),
+                (false, false) => bail!("LevelDB table {:?} is missing", ldb),
+            }
+        }
+        Ok(paths)
+    }
+
+    /// Paths of the logs that have not been compacted into tables yet.
+    fn logs(&self, dir: &Path) -> Result<Vec<PathBuf>> {
+        let mut paths = vec![];
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("log") {
+                continue;
+            }
+            let number = path
+                .file_stem()
+                .and_then(|stem| stem.to_str()?.parse::<u64>().ok());
+            match number {
+                Some(n) if n >= self.log_number || n == self.prev_log_number => paths.push(path),
+                _ => {}
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+}
+
+/// Reads all entries from a sorted table file.
+fn read_table(data: &[u8], insert: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> Result<()> {
+    if data.len() < FOOTER_SIZE {
+        bail!("LevelDB table is too small");
+    }
+    let footer = &data[data.len() - FOOTER_SIZE..];
+    let magic = u64::from_le_bytes(footer[FOOTER_SIZE - 8..].try_into()?);
+    if magic != TABLE_MAGIC {
+        bail!("LevelDB table has invalid magic {:x}", magic);
+    }
+
+    let mut footer = Cursor::new(footer);
+    let _metaindex = footer.block_handle()?;
+    let (offset, size) = footer.block_handle()?;
+    let index = read_block(data, offset, size)?;
+
+    for (_, handle) in block_entries(&index)? {
+        let (offset, size) = Cursor::new(&handle).block_handle()?;
+        let block = read_block(data, offset, size)?;
+        for (key, value) in block_entries(&block)? {
+            let (user_key, seq, kind) = split_internal_key(&key)?;
+            match kind {
+                TYPE_VALUE => insert(user_key, seq, Some(&value)),
+                TYPE_DELETION => insert(user_key, seq, None),
+                _ => bail!("LevelDB table has unknown value type {}", kind),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a (possibly compressed) block given its handle.
+fn read_block(data: &[u8], offset: usize, size: usize) -> Result<Vec<u8>> {
+    let end = offset
+        .checked_add(size)
+        .and_then(|end| end.checked_add(BLOCK_TRAILER_SIZE));
+    match end {
+        Some(end) if end <= data.len() => {}
+        _ => bail!("LevelDB block handle is out of bounds"),
+    }
+    let contents = &data[offset..offset + size];
+    match data[offset + size] {
+        0 => Ok(contents.to_vec()),
+        1 => Ok(snap::raw::Decoder::new().decompress_vec(contents)?),
+        compression => bail!("LevelDB block has unknown compression {}", compression),
+    }
+}
+
+/// Decodes the prefix-compressed key-value entries of a block.
+fn block_entries(block: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    if block.len() < 4 {
+        bail!("LevelDB block is too small");
+    }
+    let num_restarts = u32::from_le_bytes(block[block.len() - 4..].try_into()?) as usize;
+    let end = block.len().saturating_sub(4 + num_restarts * 4);
+
+    let mut cursor = Cursor::new(&block[..end]);
+    let mut entries = vec![];
+    let mut key: Vec<u8> = vec![];
+    while !cursor.is_empty() {
+        let shared = cursor.varint()? as usize;
+        let non_shared = cursor.varint()? as usize;
+        let value_len = cursor.varint()? as usize;
+        if shared > key.len() {
+            bail!("LevelDB block has corrupted key");
+        }
+        key.truncate(shared);
+        key.extend_from_slice(cursor.bytes(non_shared)?);
+        entries.push((key.clone(), cursor.bytes(value_len)?.to_vec()));
+    }
+    Ok(entries)
+}
+
+/// Reads all entries from the write batches in a log file.
+fn read_log(data: &[u8], insert: &mut impl FnMut(&[u8], u64, Option<&[u8]>)) -> Result<()> {
+    for record in log_records(data)? {
+        read_write_batch(&record, insert)?;
+    }
+    Ok(())
+}
+
+/// Reassembles the records in a log formatted file, ignoring a partially written tail.
+fn log_records(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut records = vec![];
+    let mut record = vec![];
+    for block in data.chunks(LOG_BLOCK_SIZE) {
+        let mut pos = 0;
+        while pos + LOG_HEADER_SIZE <= block.len() {
+            let length = u16::from_le_bytes([block[pos + 4], block[pos + 5]]) as usize;
+            let kind = block[pos + 6];
+            let start = pos + LOG_HEADER_SIZE;
+            if kind == 0 || start + length > block.len() {
+                // Zeroed padding or a record that was only partially written
+                break;
+            }
+            let fragment = &block[start..start + length];
+            pos = start + length;
+            match kind {
+                // Full record
+                1 => records.push(fragment.to_vec()),
+                // First fragment
+                2 => record = fragment.to_vec(),
+                // Middle fragment
+                3 => record.extend_from_slice(fragment),
+                // Last fragment
+                4 => {
+                    record.extend_from_slice(fragment);
+                    records.push(std::mem::take(&mut record));
+                }
+                _ => bail!("LevelDB log has unknown record type {}", kind),
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Reads the operations in a write batch, each getting an incrementing sequence number.
+fn read_write_batch(
+    batch: &[u8],
+    insert: &mut impl FnMut(&[u8], u64, Option<&[u8]>),
+) -> Result<()> {
+    let mut cursor = Cursor::new(batch);
+    let start_seq = u64::from_le_bytes(cursor.bytes(8)?.try_into()?);
+    let count = u32::from_le_bytes(cursor.bytes(4)?.try_into()?) as u64;
+
+    for seq in start_seq..start_seq + count {
+        let kind = cursor.bytes(1)?[0];
+        let key_len = cursor.varint()? as usize;
+        let key = cursor.bytes(key_len)?;
+        match kind {
+            TYPE_VALUE => {
+                let value_len = cursor.varint()? as usize;
+                insert(key, seq, Some(cursor.bytes(value_len)?));
+            }
+            TYPE_DELETION => insert(key, seq, None),
+            _ => bail!("LevelDB log has unknown value type {}", kind),
+        }
+    }
+    Ok(())
+}
+
+/// Splits a table key into the user key, sequence number and value type.
+fn split_internal_key(key: &[u8]) -> Result<(&[u8], u64, u8)> {
+    if key.len() < 8 {
+        bail!("LevelDB internal key is too small");
+    }
+    let (user_key, tag) = key.split_at(key.len() - 8);
+    let tag = u64::from_le_bytes(tag.try_into()?);
+    Ok((user_key, tag >> 8, tag as u8))
+}
+
+/// Helper for reading LevelDB encoded data from a byte slice.
+struct Cursor<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Cursor<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.data.len() {
+            bail!("LevelDB data ended unexpectedly");
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Reads a little-endian base 128 varint.
+    fn varint(&mut self) -> Result<u64> {
+        let mut result = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.bytes(1)?[0];
+            result |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(result);
+            }
+        }
+        bail!("LevelDB varint is too long")
+    }
+
+    /// Reads a varint length followed by that many bytes.
+    fn length_prefixed(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        self.bytes(len)
+    }
+
+    /// Reads the offset and size of a block.
+    fn block_handle(&mut self) -> Result<(usize, usize)> {
+        Ok((self.varint()? as usize, self.varint()? as usize))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Creates an empty directory that is unique to this test process.
+    pub(crate) fn temp_dir(name: &str) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::SeqCst);
+        let dir = std::env::temp_dir().join(format!("{}-{}-{}", name, std::process::id(), id));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn put_length_prefixed(out: &mut Vec<u8>, bytes: &[u8]) {
+        put_varint(out, bytes.len() as u64);
+        out.extend_from_slice(bytes);
+    }
+
+    fn internal_key(user_key: &[u8], seq: u64, kind: u8) -> Vec<u8> {
+        let mut key = user_key.to_vec();
+        key.extend_from_slice(&((seq << 8) | kind as u64).to_le_bytes());
+        key
+    }
+
+    /// Encodes a block with prefix-compressed keys and a single restart point.
+    fn block(entries: &[(Vec<u8>, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![];
+        let mut prev: &[u8] = &[];
+        for (key, value) in entries {
+            let shared = prev.iter().zip(key).take_while(|(a, b)| a == b).count();
+            put_varint(&mut out, shared as u64);
+            put_varint(&mut out, (key.len() - shared) as u64);
+            put_varint(&mut out, value.len() as u64);
+            out.extend_from_slice(&key[shared..]);
+            out.extend_from_slice(value);
+            prev = key;
+        }
+        out.extend_from_slice(&0u32.to_le_bytes());
+        out.extend_from_slice(&1u32.to_le_bytes());
+        out
+    }
+
+    /// Appends a block with its trailer and returns the encoded handle.
+    fn put_block(out: &mut Vec<u8>, block: &[u8], compress: bool) -> Vec<u8> {
+        let contents = match compress {
+            true => snap::raw::Encoder::new().compress_vec(block).unwrap(),
+            false => block.to_vec(),
+        };
+        let mut handle = vec![];
+        put_varint(&mut handle, out.len() as u64);
+        put_varint(&mut handle, contents.len() as u64);
+        out.extend_from_slice(&contents);
+        out.push(compress as u8);
+        out.extend_from_slice(&[0; 4]);
+        handle
+    }
+
+    /// Encodes a table with one data block per chunk of sorted `(internal key, value)` entries.
+    fn table(chunks: &[&[(Vec<u8>, Vec<u8>)]], compress: bool) -> Vec<u8> {
+        let mut out = vec![];
+        let mut index = vec![];
+        for chunk in chunks {
+            let handle = put_block(&mut out, &block(chunk), compress);
+            index.push((chunk.last().unwrap().0.clone(), handle));
+        }
+        let metaindex = put_block(&mut out, &block(&[]), false);
+        let index = put_block(&mut out, &block(&index), false);
+        let mut footer = [metaindex, index].concat();
+        footer.resize(FOOTER_SIZE - 8, 0);
+        footer.extend_from_slice(&TABLE_MAGIC.to_le_bytes());
+        out.extend_from_slice(&footer);
+        out
+    }
+
+    /// Encodes records in the log format, fragmenting them across blocks.
+    fn log(records: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![];
+        for record in records {
+            let mut rest = &record[..];
+            let mut first = true;
+            loop {
+                let left = LOG_BLOCK_SIZE - out.len() % LOG_BLOCK_SIZE;
+                if left < LOG_HEADER_SIZE {
+                    out.resize(out.len() + left, 0);
+                    continue;
+                }
+                let len = rest.len().min(left - LOG_HEADER_SIZE);
+                let last = len == rest.len();
+                let kind = match (first, last) {
+                    (true, true) => 1,
+                    (true, false) => 2,
+                    (false, false) => 3,
+                    (false, true) => 4,
+                };
+                out.extend_from_slice(&[0; 4]);
+                out.extend_from_slice(&(len as u16).to_le_bytes());
+                out.push(kind);
+                out.extend_from_slice(&rest[..len]);
+                rest = &rest[len..];
+                first = false;
+                if last {
+                    break;
+                }
+            }
+        }
+        out
+    }
+
+    /// Encodes a write batch, `None` values are deletions.
+    fn batch(seq: u64, ops: &[(&[u8], Option<&[u8]>)]) -> Vec<u8> {
+        let mut out = seq.to_le_bytes().to_vec();
+        out.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        for (key, value) in ops {
+            out.push(value.is_some() as u8);
+            put_length_prefixed(&mut out, key);
+            if let Some(value) = value {
+                put_length_prefixed(&mut out, value);
+            }
+        }
+        out
+    }
+
+    /// Encodes a version edit with a log number and the added and deleted table numbers.
+    fn version_edit(log_number: u64, new_files: &[u64], deleted_files: &[u64]) -> Vec<u8> {
+        let mut out = vec![];
+        put_varint(&mut out, TAG_COMPARATOR as u64);
+        put_length_prefixed(&mut out, b"leveldb.BytewiseComparator");
+        put_varint(&mut out, TAG_LOG_NUMBER as u64);
+        put_varint(&mut out, log_number);
+        put_varint(&mut out, TAG_NEXT_FILE_NUMBER as u64);
+        put_varint(&mut out, 100);
+        put_varint(&mut out, TAG_LAST_SEQUENCE as u64);
+        put_varint(&mut out, 1000);
+        put_varint(&mut out, TAG_COMPACT_POINTER as u64);
+        put_varint(&mut out, 1);
+        put_length_prefixed(&mut out, &internal_key(b"k", 1, TYPE_VALUE));
+        for number in deleted_files {
+            put_varint(&mut out, TAG_DELETED_FILE as u64);
+            put_varint(&mut out, 0);
+            put_varint(&mut out, *number);
+        }
+        for number in new_files {
+            put_varint(&mut out, TAG_NEW_FILE as u64);
+            put_varint(&mut out, 0);
+            put_varint(&mut out, *number);
+            put_varint(&mut out, 1234);
+            put_length_prefixed(&mut out, &internal_key(b"a", 1, TYPE_VALUE));
+            put_length_prefixed(&mut out, &internal_key(b"z", 1, TYPE_VALUE));
+        }
+        out
+    }
+
+    fn write_manifest(dir: &Path, edits: &[Vec<u8>]) {
+        fs::write(dir.join("MANIFEST-000002"), log(edits)).unwrap();
+        fs::write(dir.join("CURRENT"), "MANIFEST-000002\n").unwrap();
+    }
+
+    /// Writes a database containing the `(key, value)` pairs in a single table.
+    pub(crate) fn write_db(dir: &Path, entries: &[(Vec<u8>, Vec<u8>)]) {
+        let mut entries: Vec<_> = entries
+            .iter()
+            .map(|(key, value)| (internal_key(key, 1, TYPE_VALUE), value.clone()))
+            .collect();
+        entries.sort();
+        fs::create_dir_all(dir).unwrap();
+        fs::write(dir.join("000005.ldb"), table(&[&entries], true)).unwrap();
+        write_manifest(dir, &[version_edit(6, &[5], &[])]);
+    }
+
+    fn entry(key: &[u8], seq: u64, value: Option<&[u8]>) -> (Vec<u8>, Vec<u8>) {
+        match value {
+            Some(value) => (internal_key(key, seq, TYPE_VALUE), value.to_vec()),
+            None => (internal_key(key, seq, TYPE_DELETION), vec![]),
+        }
+    }
+
+    fn read_table_entries(data: &[u8]) -> Vec<(Vec<u8>, u64, Option<Vec<u8>>)> {
+        let mut entries = vec![];
+        read_table(data, &mut |key, seq, value| {
+            entries.push((key.to_vec(), seq, value.map(|v| v.to_vec())))
+        })
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn reads_tables() {
+        let first = [entry(b"ba", 3, Some(b"1")), entry(b"bab", 4, None)];
+        let second = [entry(b"bb", 5, Some(&[7; 300]))];
+        let expected = vec![
+            (b"ba".to_vec(), 3, Some(b"1".to_vec())),
+            (b"bab".to_vec(), 4, None),
+            (b"bb".to_vec(), 5, Some(vec![7; 300])),
+        ];
+        for compress in [false, true] {
+            let data = table(&[&first, &second], compress);
+            assert_eq!(read_table_entries(&data), expected);
+        }
+
+        let mut data = table(&[&first], false);
+        let len = data.len();
+        data[len - 1] ^= 1;
+        assert!(read_table(&data, &mut |_, _, _| {}).is_err());
+    }
+
+    #[test]
+    fn decodes_blocks() {
+        let entries = vec![
+            (b"abc".to_vec(), b"1".to_vec()),
+            (b"abd".to_vec(), vec![]),
+            (b"b".to_vec(), b"22".to_vec()),
+        ];
+        assert_eq!(block_entries(&block(&entries)).unwrap(), entries);
+        assert!(block_entries(&[1, 0]).is_err());
+
+        let mut data = vec![];
+        let (offset, size) = Cursor::new(&put_block(&mut data, b"hello", true))
+            .block_handle()
+            .unwrap();
+        assert_eq!(read_block(&data, offset, size).unwrap(), b"hello");
+        data[offset + size] = 2;
+        assert!(read_block(&data, offset, size).is_err());
+        assert!(read_block(&data, offset, size + 1).is_err());
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_handles() {
+        let data = [0; 20];
+        assert!(read_block(&data, 0, 15).is_ok());
+        for (offset, size) in [
+            (0, 16),
+            (u64::MAX as usize - 2, 2),
+            (2, u64::MAX as usize - 4),
+        ] {
+            let err = read_block(&data, offset, size).unwrap_err();
+            assert_eq!(err.to_string(), "LevelDB block handle is out of bounds");
+        }
+    }
+
+    #[test]
+    fn reads_logs() {
+        let big = vec![9; LOG_BLOCK_SIZE * 2];
+        let records = vec![
+            batch(10, &[(b"a", Some(b"1")), (b"b", None)]),
+            batch(12, &[(b"c", Some(&big))]),
+            batch(13, &[(b"d", Some(b"4"))]),
+        ];
+        let data = log(&records);
+        assert_eq!(log_records(&data).unwrap(), records);
+
+        let mut entries = vec![];
+        read_log(&data, &mut |key, seq, value| {
+            entries.push((key.to_vec(), seq, value.map(|v| v.len())))
+        })
+        .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (b"a".to_vec(), 10, Some(1)),
+                (b"b".to_vec(), 11, None),
+                (b"c".to_vec(), 12, Some(big.len())),
+                (b"d".to_vec(), 13, Some(1)),
+            ]
+        );
+
+        // A partially written tail is ignored
+        let truncated = &data[..data.len() - 1];
+        assert_eq!(log_records(truncated).unwrap(), records[..2]);
+    }
+
+    #[test]
+    fn reads_varints() {
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX] {
+            let mut data = vec![];
+            put_varint(&mut data, value);
+            let mut cursor = Cursor::new(&data);
+            assert_eq!(cursor.varint().unwrap(), value);
+            assert!(cursor.is_empty());
+        }
+        assert!(Cursor::new(&[0x80]).varint().is_err());
+        assert!(Cursor::new(&[0xff; 11]).varint().is_err());
+    }
+
+    #[test]
+    fn reads_live_files_from_manifest() {
+        let dir = temp_dir("leveldb-manifest");
+        // Table 3 was compacted into table 7, but survived a crash
+        let obsolete = [entry(b"pa", 1, Some(b"old")), entry(b"pb", 2, Some(b"old"))];
+        fs::write(dir.join("000003.ldb"), table(&[&obsolete], false)).unwrap();
+        let live = [entry(b"pa", 3, Some(b"new"))];
+        fs::write(dir.join("000007.sst"), table(&[&live], true)).unwrap();
+        // Log 4 was compacted into table 7, log 8 is live
+        let old_log = log(&[batch(1, &[(b"pc", Some(b"old"))])]);
+        fs::write(dir.join("000004.log"), old_log).unwrap();
+        let new_log = log(&[batch(4, &[(b"pd", Some(b"new")), (b"xa", Some(b"1"))])]);
+        fs::write(dir.join("000008.log"), new_log).unwrap();
+        write_manifest(
+            &dir,
+            &[version_edit(4, &[3], &[]), version_edit(8, &[7], &[3])],
+        );
+
+        let mut entries: Vec<_> = read_prefix(&dir, b"p").unwrap().into_iter().collect();
+        entries.sort();
+        assert_eq!(
+            entries,
+            vec![
+                (b"pa".to_vec(), b"new".to_vec()),
+                (b"pd".to_vec(), b"new".to_vec()),
+            ]
+        );
+
+        // Newer entries in the log overwrite and delete entries in the tables
+        let newer_log = log(&[batch(5, &[(b"pa", None), (b"pd", Some(b"newer"))])]);
+        fs::write(dir.join("000009.log"), newer_log).unwrap();
+        let entries: Vec<_> = read_prefix(&dir, b"p").unwrap().into_iter().collect();
+        assert_eq!(entries, vec![(b"pd".to_vec(), b"newer".to_vec())]);
+
+        fs::remove_file(dir.join("000007.sst")).unwrap();
+        assert!(read_prefix(&dir, b"p").is_err());
+        fs::remove_file(dir.join("CURRENT")).unwrap();
+        assert!(read_prefix(&dir, b"p").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
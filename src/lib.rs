@@ -3,10 +3,21 @@
 #![allow(rustdoc::redundant_explicit_links)]
 
 pub mod blocks;
+pub mod elements;
+pub mod events;
+pub mod follow;
 pub mod headers;
+pub mod index;
+mod leveldb;
+pub mod params;
+pub mod peer;
+pub mod rpc;
+pub mod source;
+pub mod undo;
 pub mod utxos;
 pub mod xor;
 
 pub use blocks::BlockParser;
 pub use headers::HeaderParser;
+pub use undo::UndoParser;
 pub use utxos::UtxoParser;
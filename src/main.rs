@@ -1,7 +1,8 @@
 use anyhow::Result;
 use bitcoin::hashes::Hash;
-use bitcoin::{Amount, Block, BlockHash, Txid};
-use bitcoin_block_parser::blocks::{BlockParser, ParserIterator, Pipeline};
+use bitcoin::{Amount, Block, BlockHash, Network, Txid};
+use bitcoin_block_parser::blocks::{BlockParser, ParserIterator, ParserOptions, Pipeline};
+use bitcoin_block_parser::undo::UndoParser;
 use bitcoin_block_parser::utxos::{OutputStatus, UtxoParser};
 use clap::{Parser, ValueEnum};
 use dashmap::DashMap;
@@ -26,6 +27,10 @@ struct Args {
     /// Which of the functions to run
     #[arg(short, long)]
     run: Function,
+
+    /// Network the BLK files belong to
+    #[arg(short, long, default_value = "bitcoin")]
+    network: Network,
 }
 
 /// Types of functions we can run
@@ -40,6 +45,7 @@ enum Function {
     Pipeline,
     UtxoCreate,
     UtxoParse,
+    UndoParse,
     Test,
 }
 
@@ -50,24 +56,41 @@ fn main() -> Result<()> {
 
     let args = Args::parse();
 
-    fn block_parser(blocks_dir: &str) -> Result<BlockParser> {
-        Ok(BlockParser::new(blocks_dir)?.end_height(BLOCKS_TO_PARSE))
-    }
+    let options = ParserOptions {
+        network: args.network,
+        ..Default::default()
+    };
 
-    fn utxo_parser(blocks_dir: &str, filter_file: &str) -> Result<UtxoParser> {
-        Ok(UtxoParser::new(blocks_dir, filter_file).end_height(BLOCKS_TO_PARSE))
-    }
+    let block_parser = || -> Result<BlockParser> {
+        let parser = BlockParser::new_with_opts(&args.blocks_dir, options.clone())?;
+        Ok(parser.end_height(BLOCKS_TO_PARSE))
+    };
+
+    let undo_parser = || -> UndoParser {
+        let parser = UndoParser::new(&args.blocks_dir);
+        parser
+            .with_opts(options.clone())
+            .end_height(BLOCKS_TO_PARSE)
+    };
+
+    let utxo_parser = || -> Result<UtxoParser> {
+        let parser = UtxoParser::new(&args.blocks_dir, &args.filter_file);
+        Ok(parser
+            .with_opts(options.clone())
+            .end_height(BLOCKS_TO_PARSE))
+    };
 
     match args.run {
-        Function::Parse => parse(block_parser(&args.blocks_dir)?),
-        Function::ParseNoop => parse_noop(block_parser(&args.blocks_dir)?),
-        Function::MapParallel => map_parallel(block_parser(&args.blocks_dir)?),
-        Function::Ordered => ordered(block_parser(&args.blocks_dir)?),
-        Function::NoPipelineFn => no_pipeline_fn(block_parser(&args.blocks_dir)?),
-        Function::PipelineFn => pipeline_fn(block_parser(&args.blocks_dir)?),
-        Function::Pipeline => pipeline(block_parser(&args.blocks_dir)?),
-        Function::UtxoCreate => utxo_create(utxo_parser(&args.blocks_dir, &args.filter_file)?),
-        Function::UtxoParse => utxo_parse(utxo_parser(&args.blocks_dir, &args.filter_file)?),
+        Function::Parse => parse(block_parser()?),
+        Function::ParseNoop => parse_noop(block_parser()?),
+        Function::MapParallel => map_parallel(block_parser()?),
+        Function::Ordered => ordered(block_parser()?),
+        Function::NoPipelineFn => no_pipeline_fn(block_parser()?),
+        Function::PipelineFn => pipeline_fn(block_parser()?),
+        Function::Pipeline => pipeline(block_parser()?),
+        Function::UtxoCreate => utxo_create(utxo_parser()?),
+        Function::UtxoParse => utxo_parse(utxo_parser()?),
+        Function::UndoParse => undo_parse(undo_parser())?,
         Function::Test => test(args)?,
     }
     Ok(())
@@ -181,6 +204,17 @@ fn utxo_parse(parser: UtxoParser) {
     }
 }
 
+fn undo_parse(parser: UndoParser) -> Result<()> {
+    for txdata in parser.parse(|block| block.txdata)? {
+        for tx in txdata {
+            for (_, _) in tx.input() {
+                // Do something with TxOut that are used in the inputs
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Integration test based off of real mainchain data
 fn test(args: Args) -> Result<()> {
     println!("\nTesting write_filter");
@@ -197,6 +231,13 @@ fn test(args: Args) -> Result<()> {
         Txid::from_str("062ed26778b8d0794c269029ee7b1d56b4ecaa379048b21298bf6d35876d00c4").unwrap();
     let test_txid2 =
         Txid::from_str("cf2cc1897eb061e2406e644ecee3c26ee64cfadcc626890438c3d058511c9094").unwrap();
+    let real_amounts = vec![
+        Amount::from_sat(56892597),
+        Amount::from_sat(274000000),
+        Amount::from_sat(248000000),
+        Amount::from_sat(14832476),
+        Amount::from_sat(48506744443),
+    ];
 
     for block in parser {
         for tx in block.txdata {
@@ -211,13 +252,19 @@ fn test(args: Args) -> Result<()> {
             // Verify tx amounts here https://mempool.space/tx/cf2cc1897eb061e2406e644ecee3c26ee64cfadcc626890438c3d058511c9094
             if tx.txid == test_txid2 {
                 let amounts: Vec<_> = tx.input().map(|(_, out)| out.value).collect();
-                let real_amounts = vec![
-                    Amount::from_sat(56892597),
-                    Amount::from_sat(274000000),
-                    Amount::from_sat(248000000),
-                    Amount::from_sat(14832476),
-                    Amount::from_sat(48506744443),
-                ];
+                assert_eq!(amounts, real_amounts);
+            }
+        }
+    }
+
+    println!("\nTesting UndoParser");
+    let parser = UndoParser::new(&args.blocks_dir)
+        .end_height(151_000)
+        .parse(identity)?;
+    for block in parser {
+        for tx in block.txdata {
+            if tx.txid == test_txid2 {
+                let amounts: Vec<_> = tx.input().map(|(_, out)| out.value).collect();
                 assert_eq!(amounts, real_amounts);
             }
         }
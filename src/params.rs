@@ -0,0 +1,465 @@
+//! Contains [`ChainParams`] for parsing the BLK files of chains derived from bitcoin.
+
+use anyhow::{bail, Result};
+use bitcoin::absolute::LockTime;
+use bitcoin::block::Header;
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::Magic;
+use bitcoin::{constants, transaction, BlockHash, Network, Transaction, TxIn, TxMerkleNode};
+use bitcoin::{TxOut, VarInt, Witness};
+use serde::{Deserialize, Serialize};
+use std::io::{BufReader, Read};
+
+/// Version bit that indicates an AuxPoW proof follows the header in merge-mined chains
+const AUX_POW_VERSION: i32 = 1 << 8;
+/// Transaction flag that indicates witness data follows the outputs
+const WITNESS_FLAG: u8 = 1;
+/// Transaction flag that indicates Litecoin MWEB data follows the witnesses
+const MWEB_FLAG: u8 = 8;
+
+/// Describes how to read the BLK files of a chain, bitcoin is used unless
+/// [`crate::blocks::ParserOptions::chain_params`] is set.
+///
+/// Transactions from every chain are decoded into [`bitcoin::Transaction`], for example Litecoin's
+/// MWEB extension data is skipped.  Elements chains such as [`ChainParams::liquid`] are the
+/// exception and must be parsed with [`crate::elements::ElementsParser`].
+///
+/// # Examples
+/// Parsing the blocks of Dogecoin:
+/// ```no_run
+/// use bitcoin_block_parser::blocks::*;
+/// use bitcoin_block_parser::params::*;
+///
+/// let options = ParserOptions {
+///     chain_params: Some(ChainParams::dogecoin()),
+///     ..Default::default()
+/// };
+/// let parser = BlockParser::new_with_opts("/home/user/.dogecoin/blocks/", options).unwrap();
+/// println!("Dogecoin transactions: {}", parser.parse(|block| block.txdata.len()).sum::<usize>());
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChainParams {
+    /// Name of the chain used in error messages
+    pub name: String,
+    /// Magic bytes written before every block in the BLK files
+    pub magic: [u8; 4],
+    /// Hash of the first block in the chain
+    pub genesis_hash: BlockHash,
+    /// Whether headers with version bit 8 set are followed by a merge-mining AuxPoW proof
+    pub aux_pow: bool,
+    /// Whether the block hash is the proof-of-work hash, `false` for chains that mine with another
+    /// hash function such as scrypt
+    pub hash_is_pow: bool,
+    /// Maximum size of a serialized block, anything larger must be a corrupted size
+    pub max_block_size: usize,
+    /// Whether blocks can end with data that is not decoded, such as Litecoin's MWEB block
+    pub extension_data: bool,
+    /// Whether blocks use the Elements format, which can only be parsed with
+    /// [`crate::elements::ElementsParser`]
+    pub elements: bool,
+    /// Whether Elements headers contain the block height (`con_blockheightinheader`)
+    pub height_in_header: bool,
+    /// Whether Elements headers are signed instead of mined (`con_signed_blocks`)
+    pub signed_blocks: bool,
+    /// Height from which every coinbase starts with the block height (BIP34), `None` if the chain
+    /// does not enforce it.  Used to place headers after a gap with
+    /// [`crate::headers::GapPolicy::Continue`].
+    pub bip34_height: Option<usize>,
+}
+
+impl ChainParams {
+    /// Parameters for a bitcoin [`Network`].
+    pub fn bitcoin(network: Network) -> Self {
+        Self {
+            name: network.to_string(),
+            magic: network.magic().to_bytes(),
+            genesis_hash: constants::genesis_block(network).block_hash(),
+            aux_pow: false,
+            hash_is_pow: true,
+            max_block_size: 4_000_000,
+            extension_data: false,
+            elements: false,
+            height_in_header: false,
+            signed_blocks: false,
+            bip34_height: match network {
+                Network::Bitcoin => Some(227_931),
+                Network::Testnet => Some(21_111),
+                _ => Some(1),
+            },
+        }
+    }
+
+    /// Returns the bitcoin [`Network`] if these are its unmodified parameters, `None` for every
+    /// other chain.
+    pub fn network(&self) -> Option<Network> {
+        Network::from_magic(Magic::from_bytes(self.magic))
+            .filter(|network| *self == Self::bitcoin(*network))
+    }
+
+    /// Parameters for Bitcoin Cash, which Bitcoin Cash Node writes with bitcoin's magic bytes.
+    pub fn bitcoin_cash() -> Self {
+        Self {
+            name: "bitcoin cash".to_string(),
+            max_block_size: 256_000_000,
+            ..Self::bitcoin(Network::Bitcoin)
+        }
+    }
+
+    /// Parameters for Litecoin mainnet.
+    pub fn litecoin() -> Self {
+        Self {
+            name: "litecoin".to_string(),
+            magic: [0xfb, 0xc0, 0xb6, 0xdb],
+            genesis_hash: Self::hash(
+                "12a765e31ffd4059bada1e25190f6e98c99d9714d334efa41a195a7e7e04bfe2",
+            ),
+            aux_pow: false,
+            hash_is_pow: false,
+            // MWEB extension data is stored after the transactions
+            max_block_size: 8_000_000,
+            extension_data: true,
+            elements: false,
+            height_in_header: false,
+            signed_blocks: false,
+            bip34_height: Some(710_000),
+        }
+    }
+
+    /// Parameters for Dogecoin mainnet, which is merge-mined with Litecoin.
+    pub fn dogecoin() -> Self {
+        Self {
+            name: "dogecoin".to_string(),
+            magic: [0xc0, 0xc0, 0xc0, 0xc0],
+            genesis_hash: Self::hash(
+                "1a91e3dace36e2be3bf030a65679fe821aa1d6ef92e7c9902eb318182c355691",
+            ),
+            aux_pow: true,
+            hash_is_pow: false,
+            max_block_size: 1_000_000,
+            extension_data: false,
+            elements: false,
+            height_in_header: false,
+            signed_blocks: false,
+            bip34_height: Some(1_034_383),
+        }
+    }
+
+    /// Parameters for Namecoin mainnet, which is merge-mined with bitcoin.
+    pub fn namecoin() -> Self {
+        Self {
+            name: "namecoin".to_string(),
+            magic: [0xf9, 0xbe, 0xb4, 0xfe],
+            genesis_hash: Self::hash(
+                "000000000062b72c5e2ceb45fbc8587e807c155b0da735e6483dfba2f0a9c770",
+            ),
+            aux_pow: true,
+            hash_is_pow: true,
+            max_block_size: 4_000_000,
+            extension_data: false,
+            elements: false,
+            height_in_header: false,
+            signed_blocks: false,
+            bip34_height: None,
+        }
+    }
+
+    /// Parameters for the Liquid sidechain, whose blocks are signed by the federation.
+    pub fn liquid() -> Self {
+        Self {
+            name: "liquid".to_string(),
+            magic: [0xfa, 0xbf, 0xb5, 0xda],
+            genesis_hash: Self::hash(
+                "1466275836220db2944ca059a3a10ef6fd2ea684b0688d2c379296888a206003",
+            ),
+            aux_pow: false,
+            hash_is_pow: false,
+            max_block_size: 4_000_000,
+            extension_data: false,
+            elements: true,
+            height_in_header: true,
+            signed_blocks: true,
+            bip34_height: None,
+        }
+    }
+
+    /// Parses a block hash constant.
+    fn hash(hex: &str) -> BlockHash {
+        hex.parse().expect("valid hash")
+    }
+
+    /// Returns an `Err` for Elements chains whose headers cannot be decoded, only headers with the
+    /// block height and a signed block proof like Liquid's are supported.
+    pub(crate) fn check_elements_headers(&self) -> Result<()> {
+        if self.elements && !(self.height_in_header && self.signed_blocks) {
+            bail!(
+                "{} headers must contain the block height and be signed like Liquid's",
+                self.name
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns true if an AuxPoW proof follows a header with the `version`.
+    pub(crate) fn has_aux_pow(&self, version: i32) -> bool {
+        self.aux_pow && version & AUX_POW_VERSION != 0
+    }
+
+    /// Skips over an AuxPoW proof, returning its size in bytes.
+    ///
+    /// See https://en.bitcoin.it/wiki/Merged_mining_specification
+    pub(crate) fn skip_aux_pow(reader: &mut BufReader<impl Read>) -> Result<usize> {
+        // Coinbase of the parent block and the parent block hash
+        let coinbase = Transaction::consensus_decode(reader)?;
+        let _parent_hash = BlockHash::consensus_decode(reader)?;
+        let mut size = coinbase.total_size() + BlockHash::LEN;
+        // Merkle branches of the coinbase and the chain, each followed by a 4 byte index
+        for _ in 0..2 {
+            let branch = Vec::<TxMerkleNode>::consensus_decode(reader)?;
+            let _index = i32::consensus_decode(reader)?;
+            size += VarInt(branch.len() as u64).size() + branch.len() * TxMerkleNode::LEN + 4;
+        }
+        let _parent = Header::consensus_decode(reader)?;
+        Ok(size + Header::SIZE)
+    }
+}
+
+impl From<Network> for ChainParams {
+    fn from(network: Network) -> Self {
+        Self::bitcoin(network)
+    }
+}
+
+/// Reads the transactions of a block, skipping over any chain specific data.
+///
+/// Unlike [`Transaction`] decoding this also accepts the Litecoin HogEx transaction that ends
+/// every block with MWEB data.
+pub(crate) fn read_transactions(reader: &mut BufReader<impl Read>) -> Result<Vec<Transaction>> {
+    let num_tx = VarInt::consensus_decode(reader)?.0 as usize;
+    // Avoid allocating a large amount of memory if the count is corrupted
+    let mut txdata = Vec::with_capacity(num_tx.min(1024));
+    for _ in 0..num_tx {
+        txdata.push(read_transaction(reader)?);
+    }
+    Ok(txdata)
+}
+
+/// Reads a transaction, checking the flags that follow an empty input vector.
+fn read_transaction(reader: &mut BufReader<impl Read>) -> Result<Transaction> {
+    let version = transaction::Version::consensus_decode(reader)?;
+    let mut num_inputs = VarInt::consensus_decode(reader)?.0;
+    let mut flags = 0;
+    if num_inputs == 0 {
+        flags = u8::consensus_decode(reader)?;
+        if flags == 0 || flags & !(WITNESS_FLAG | MWEB_FLAG) != 0 {
+            bail!("Unsupported transaction flags {:#04x}", flags);
+        }
+        num_inputs = VarInt::consensus_decode(reader)?.0;
+    }
+    let mut input = vec![];
+    for _ in 0..num_inputs {
+        input.push(TxIn::consensus_decode(reader)?);
+    }
+    let output = Vec::<TxOut>::consensus_decode(reader)?;
+    if flags & WITNESS_FLAG != 0 {
+        for txin in input.iter_mut() {
+            txin.witness = Witness::consensus_decode(reader)?;
+        }
+    }
+    // The HogEx transaction sets the flag without any MWEB data
+    if flags & MWEB_FLAG != 0 && u8::consensus_decode(reader)? != 0 {
+        bail!("MWEB transactions are not supported");
+    }
+    Ok(Transaction {
+        version,
+        lock_time: LockTime::consensus_decode(reader)?,
+        input,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::{BlockParser, ParserOptions};
+    use crate::headers::tests::{mine, regtest_chain};
+    use crate::leveldb::tests::temp_dir;
+    use crate::source::{BlockSource, RawBlockFiles};
+    use crate::HeaderParser;
+    use bitcoin::consensus::serialize;
+    use bitcoin::{Amount, Block, OutPoint, ScriptBuf, Sequence, Txid};
+    use std::fs;
+
+    /// Writes the serialized blocks as records of a BLK file with the `magic` bytes.
+    fn write_blk_file(dir: &std::path::Path, magic: [u8; 4], blocks: &[Vec<u8>]) {
+        let mut out = vec![];
+        for block in blocks {
+            out.extend(magic);
+            out.extend((block.len() as u32).to_le_bytes());
+            out.extend(block);
+        }
+        fs::write(dir.join("blk00000.dat"), out).unwrap();
+    }
+
+    /// Returns a transaction spending a made up output.
+    fn spend(vout: u32) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), vout),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey: ScriptBuf::new_op_return([vout as u8]),
+            }],
+        }
+    }
+
+    /// Serializes a merge-mined `block` with an AuxPoW proof after its header.
+    fn aux_pow_block(mut block: Block) -> (Block, Vec<u8>) {
+        let version = block.header.version.to_consensus() | AUX_POW_VERSION;
+        block.header.version = bitcoin::block::Version::from_consensus(version);
+        let mut proof = serialize(&spend(0));
+        proof.extend([0xaa; 32]);
+        // Coinbase branch with one node and an empty chain branch, each followed by an index
+        proof.extend(serialize(&vec![TxMerkleNode::all_zeros()]));
+        proof.extend(0_i32.to_le_bytes());
+        proof.extend(serialize(&Vec::<TxMerkleNode>::new()));
+        proof.extend(0_i32.to_le_bytes());
+        proof.extend(serialize(&block.header));
+
+        let mut data = serialize(&block.header);
+        data.extend(&proof);
+        data.extend(serialize(&block.txdata));
+        (block, data)
+    }
+
+    #[test]
+    fn reads_aux_pow_blocks() {
+        for params in [ChainParams::dogecoin(), ChainParams::namecoin()] {
+            let dir = temp_dir("aux-pow");
+            let chain = regtest_chain(2);
+            let (aux_2, data_2) = aux_pow_block(mine(&chain[1].header, 2));
+            let (aux_3, data_3) = aux_pow_block(mine(&aux_2.header, 3));
+            let blocks = [chain[0].clone(), chain[1].clone(), aux_2, aux_3];
+            let data = [serialize(&blocks[0]), serialize(&blocks[1]), data_2, data_3];
+            write_blk_file(&dir, params.magic, &data);
+            let options = ParserOptions {
+                chain_params: Some(ChainParams {
+                    genesis_hash: blocks[0].block_hash(),
+                    ..params.clone()
+                }),
+                verify: true,
+                ..ParserOptions::default()
+            };
+
+            let headers = HeaderParser::parse_with_opts(dir.to_str().unwrap(), &options).unwrap();
+            let hashes: Vec<_> = headers.iter().map(|header| header.hash).collect();
+            let expected: Vec<_> = blocks.iter().map(Block::block_hash).collect();
+            assert_eq!(hashes, expected, "{}", params.name);
+            // Transactions start after the proof
+            let proof_len = data[2].len() - serialize(&blocks[2]).len();
+            let header = &headers[2];
+            assert_eq!(
+                header.offset,
+                header.header_offset + Header::SIZE + proof_len
+            );
+
+            let parser =
+                BlockParser::new_with_opts(dir.to_str().unwrap(), options.clone()).unwrap();
+            let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+            assert_eq!(parsed, blocks, "{}", params.name);
+
+            // Raw block files also skip the proof
+            let raw_dir = temp_dir("aux-pow-raw");
+            for (height, block) in data.iter().enumerate() {
+                fs::write(raw_dir.join(format!("{}.bin", height)), block).unwrap();
+            }
+            let source = RawBlockFiles::new(&raw_dir);
+            let chain = source.chain(&options).unwrap();
+            assert!(chain.report.skipped.is_empty(), "{}", params.name);
+            assert_eq!(chain.active[2].offset, Header::SIZE + proof_len);
+            let parser = BlockParser::from_source(source, options).unwrap();
+            let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+            assert_eq!(parsed, blocks, "{}", params.name);
+            fs::remove_dir_all(&dir).unwrap();
+            fs::remove_dir_all(&raw_dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_litecoin_mweb_blocks() {
+        let dir = temp_dir("mweb");
+        let chain = regtest_chain(3);
+        let mut mweb = chain[2].clone();
+        let hogex = spend(1);
+        mweb.txdata.push(hogex.clone());
+        mweb.header.merkle_root = mweb.compute_merkle_root().unwrap();
+
+        // The HogEx transaction sets the MWEB flag with no MWEB data before the lock time
+        let mut data = serialize(&mweb.header);
+        data.push(mweb.txdata.len() as u8);
+        data.extend(serialize(&mweb.txdata[0]));
+        let hogex_data = serialize(&hogex);
+        let (version, rest) = hogex_data.split_at(4);
+        let (rest, lock_time) = rest.split_at(rest.len() - 4);
+        data.extend(version);
+        data.extend([0, MWEB_FLAG]);
+        data.extend(rest);
+        data.push(0);
+        data.extend(lock_time);
+        // The MWEB block is stored after the transactions
+        data.extend([1, 0xde, 0xad, 0xbe, 0xef]);
+
+        let next = mine(&mweb.header, 3);
+        let blocks = [chain[0].clone(), chain[1].clone(), mweb, next];
+        let data = [
+            serialize(&blocks[0]),
+            serialize(&blocks[1]),
+            data,
+            serialize(&blocks[3]),
+        ];
+        let params = ChainParams {
+            genesis_hash: blocks[0].block_hash(),
+            ..ChainParams::litecoin()
+        };
+        write_blk_file(&dir, params.magic, &data);
+        let options = ParserOptions {
+            chain_params: Some(params),
+            verify: true,
+            ..ParserOptions::default()
+        };
+
+        let parser = BlockParser::new_with_opts(dir.to_str().unwrap(), options).unwrap();
+        assert_eq!(parser.get_header_at_height(2).unwrap().size, data[2].len());
+        let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+        assert_eq!(parsed, blocks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_bitcoin_has_network() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Testnet4,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            assert_eq!(ChainParams::bitcoin(network).network(), Some(network));
+        }
+        for params in [
+            ChainParams::bitcoin_cash(),
+            ChainParams::litecoin(),
+            ChainParams::dogecoin(),
+            ChainParams::namecoin(),
+            ChainParams::liquid(),
+        ] {
+            assert_eq!(params.network(), None, "{}", params.name);
+        }
+    }
+}
@@ -0,0 +1,433 @@
+//! Contains [`PeerSource`] for downloading blocks from a peer using the Bitcoin wire protocol.
+
+use crate::blocks::ParserOptions;
+use crate::headers::{HeaderChain, ParsedHeader};
+use crate::source::BlockSource;
+use anyhow::{bail, Result};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::Hash;
+use bitcoin::p2p::address::Address;
+use bitcoin::p2p::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::p2p::message_network::VersionMessage;
+use bitcoin::p2p::ServiceFlags;
+use bitcoin::pow::Work;
+use bitcoin::{constants, Block, BlockHash, Network};
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+use log::info;
+use std::collections::BTreeMap;
+use std::io::{BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of headers a peer returns in response to `getheaders`
+const MAX_HEADERS: usize = 2000;
+/// How long to wait for the peer to respond before giving up
+const TIMEOUT: Duration = Duration::from_secs(60);
+/// User agent sent to the peer in the `version` message
+const USER_AGENT: &str = concat!("/bitcoin-block-parser:", env!("CARGO_PKG_VERSION"), "/");
+
+/// Downloads blocks from a peer, such as a node on the local network, without needing access to
+/// its data directory.
+///
+/// Headers are synced with `getheaders` when the parser is created and blocks are downloaded with
+/// `getdata` by the parser threads, using up to [`PeerSource::connections`] connections to the
+/// peer at once.
+///
+/// - [`ParsedHeader::size`] is `0` because headers are downloaded without their blocks.
+/// - The peer must serve witness data, which every node since Bitcoin Core v0.13.1 does.
+/// - Blocks are not validated, only connect to peers you trust.
+///
+/// # Examples
+/// ```no_run
+/// use bitcoin::Network;
+/// use bitcoin_block_parser::blocks::*;
+/// use bitcoin_block_parser::peer::*;
+///
+/// let source = PeerSource::new("127.0.0.1:8333", Network::Bitcoin).connections(8);
+/// let parser = BlockParser::from_source(source, ParserOptions::default()).unwrap();
+/// let iterator = parser.start_height(850_000).parse(|block| block.txdata.len());
+/// println!("Transactions: {}", iterator.sum::<usize>());
+/// ```
+#[derive(Clone, Debug)]
+pub struct PeerSource {
+    /// Host and port of the peer
+    address: String,
+    /// Network the peer belongs to
+    network: Network,
+    /// Maximum number of connections to open to the peer
+    connections: usize,
+    /// Holds one message for every connection in use, blocking when full
+    permits: (Sender<()>, Receiver<()>),
+    /// Connections that are not in use
+    idle: (Sender<Peer>, Receiver<Peer>),
+}
+
+impl PeerSource {
+    /// Creates a source given the `address` of the peer such as `127.0.0.1:8333`.
+    pub fn new(address: &str, network: Network) -> Self {
+        Self {
+            address: address.to_string(),
+            network,
+            connections: 4,
+            permits: bounded(4),
+            idle: unbounded(),
+        }
+    }
+
+    /// Sets the maximum number of connections used to download blocks, defaults to 4.
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self.permits = bounded(self.connections);
+        self
+    }
+
+    /// Runs `function` with an idle connection, waiting if every connection is in use.
+    fn with_peer<T>(&self, function: impl FnOnce(&mut Peer) -> Result<T>) -> Result<T> {
+        let _ = self.permits.0.send(());
+        let result = self.with_permit(function);
+        let _ = self.permits.1.recv();
+        result
+    }
+
+    /// Runs `function` with an idle connection, opening a new one if there are none.
+    fn with_permit<T>(&self, function: impl FnOnce(&mut Peer) -> Result<T>) -> Result<T> {
+        let mut peer = match self.idle.1.try_recv() {
+            Ok(peer) => peer,
+            Err(_) => Peer::connect(&self.address, self.network)?,
+        };
+        let result = function(&mut peer);
+        // Connections in an unknown state are closed rather than reused
+        if result.is_ok() {
+            self.idle.0.send(peer)?;
+        }
+        result
+    }
+}
+
+impl BlockSource for PeerSource {
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+        if options.network != self.network {
+            bail!("Peer is on {}, expected {}", self.network, options.network);
+        }
+        let genesis = constants::genesis_block(self.network).header;
+        let mut headers = vec![genesis];
+        info!("Downloading headers from {}", self.address);
+        self.with_peer(|peer| loop {
+            let mut prev = headers[headers.len() - 1].block_hash();
+            let request = GetHeadersMessage::new(vec![prev], BlockHash::all_zeros());
+            peer.send(NetworkMessage::GetHeaders(request))?;
+            let received = loop {
+                if let NetworkMessage::Headers(received) = peer.receive()? {
+                    break received;
+                }
+            };
+            // Every header must build on the one before it, starting from the locator
+            for header in &received {
+                if header.prev_blockhash != prev {
+                    bail!(
+                        "Peer sent header {} that does not connect to {}",
+                        header.block_hash(),
+                        prev
+                    );
+                }
+                prev = header.block_hash();
+            }
+            let num_received = received.len();
+            headers.extend(received);
+            if num_received < MAX_HEADERS {
+                return Ok(());
+            }
+        })?;
+
+        let headers: Vec<ParsedHeader> = headers
+            .into_iter()
+            .map(|header| ParsedHeader {
+                inner: header,
+                offset: 0,
+                header_offset: 0,
+                hash: header.block_hash(),
+                path: PathBuf::default(),
+                xor_mask: None,
+                chainwork: Work::from_be_bytes([0; 32]),
+                height: 0,
+                size: 0,
+            })
+            .collect();
+        HeaderChain::resolve(headers.iter(), BTreeMap::default(), options)
+    }
+
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+        self.with_peer(|peer| {
+            let inventory = Inventory::WitnessBlock(header.hash);
+            peer.send(NetworkMessage::GetData(vec![inventory]))?;
+            loop {
+                match peer.receive()? {
+                    NetworkMessage::Block(block) if block.block_hash() == header.hash => {
+                        return Ok(block);
+                    }
+                    NetworkMessage::NotFound(_) => {
+                        bail!("Peer does not have block {}", header.hash)
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+}
+
+/// A connection to a peer that has completed the version handshake.
+#[derive(Debug)]
+struct Peer {
+    /// Reads messages from the peer
+    reader: BufReader<TcpStream>,
+    /// Writes messages to the peer
+    writer: TcpStream,
+    /// Network the peer belongs to
+    network: Network,
+}
+
+impl Peer {
+    /// Connects to the peer at `address`, performing the version handshake.
+    fn connect(address: &str, network: Network) -> Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_nodelay(true)?;
+        let mut peer = Self {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            network,
+        };
+
+        let local = Address::new(&peer.writer.local_addr()?, ServiceFlags::NONE);
+        let remote = Address::new(&peer.writer.peer_addr()?, ServiceFlags::NONE);
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let version = VersionMessage::new(
+            ServiceFlags::NONE,
+            timestamp,
+            remote,
+            local,
+            rand::random(),
+            USER_AGENT.to_string(),
+            0,
+        );
+        peer.send(NetworkMessage::Version(version))?;
+
+        let (mut received_version, mut received_verack) = (false, false);
+        while !received_version || !received_verack {
+            match peer.receive()? {
+                NetworkMessage::Version(version) => {
+                    if !version.services.has(ServiceFlags::WITNESS) {
+                        bail!("Peer {} does not serve witness data", address);
+                    }
+                    received_version = true;
+                    peer.send(NetworkMessage::Verack)?;
+                }
+                NetworkMessage::Verack => received_verack = true,
+                _ => {}
+            }
+        }
+        Ok(peer)
+    }
+
+    /// Sends a message to the peer.
+    fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        let message = RawNetworkMessage::new(self.network.magic(), payload);
+        let mut data = vec![];
+        message.consensus_encode(&mut data)?;
+        self.writer.write_all(&data)?;
+        Ok(())
+    }
+
+    /// Receives the next message from the peer, answering any pings.
+    fn receive(&mut self) -> Result<NetworkMessage> {
+        loop {
+            let message = RawNetworkMessage::consensus_decode(&mut self.reader)?;
+            if *message.magic() != self.network.magic() {
+                bail!("Peer sent a message for a different network");
+            }
+            match message.into_payload() {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockParser;
+    use crate::headers::tests::regtest_chain;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    /// Counts the messages received by a [`fake_peer`].
+    #[derive(Default)]
+    struct Received {
+        versions: AtomicUsize,
+        getheaders: AtomicUsize,
+    }
+
+    /// Starts a fake regtest peer serving the `blocks` that disconnects when asked for the
+    /// `disconnect` block, returning its address.
+    fn fake_peer(blocks: Vec<Block>, disconnect: BlockHash) -> (String, Arc<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (blocks, received) = (Arc::new(blocks), Arc::new(Received::default()));
+        let counts = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (blocks, counts) = (blocks.clone(), counts.clone());
+                let stream = stream.unwrap();
+                thread::spawn(move || serve(stream, &blocks, disconnect, &counts));
+            }
+        });
+        (address, received)
+    }
+
+    /// Answers messages on a single connection until it is closed.
+    fn serve(stream: TcpStream, blocks: &[Block], disconnect: BlockHash, received: &Received) {
+        let mut peer = Peer {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            network: Network::Regtest,
+        };
+        while let Ok(message) = peer.receive() {
+            let response = match message {
+                NetworkMessage::Version(version) => {
+                    received.versions.fetch_add(1, Ordering::SeqCst);
+                    let services = ServiceFlags::NETWORK | ServiceFlags::WITNESS;
+                    let addr = Address::new(&peer.writer.local_addr().unwrap(), services);
+                    let version = VersionMessage::new(
+                        services,
+                        0,
+                        version.sender,
+                        addr,
+                        0,
+                        "/fake/".into(),
+                        0,
+                    );
+                    peer.send(NetworkMessage::Version(version)).unwrap();
+                    vec![NetworkMessage::Verack]
+                }
+                NetworkMessage::GetHeaders(request) => {
+                    received.getheaders.fetch_add(1, Ordering::SeqCst);
+                    let locator = request.locator_hashes[0];
+                    let start = blocks
+                        .iter()
+                        .position(|b| b.block_hash() == locator)
+                        .unwrap();
+                    let headers = blocks[start + 1..].iter().map(|block| block.header);
+                    vec![NetworkMessage::Headers(headers.take(MAX_HEADERS).collect())]
+                }
+                NetworkMessage::GetData(inventory) => {
+                    let Inventory::WitnessBlock(hash) = inventory[0] else {
+                        panic!("Unexpected inventory {:?}", inventory)
+                    };
+                    if hash == disconnect {
+                        return;
+                    }
+                    match blocks.iter().find(|block| block.block_hash() == hash) {
+                        Some(block) => vec![NetworkMessage::Block(block.clone())],
+                        None => vec![NetworkMessage::NotFound(inventory)],
+                    }
+                }
+                _ => vec![],
+            };
+            for message in response {
+                peer.send(message).unwrap();
+            }
+        }
+    }
+
+    fn regtest_options() -> ParserOptions {
+        ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        }
+    }
+
+    #[test]
+    fn downloads_headers_in_batches() {
+        let blocks = regtest_chain(MAX_HEADERS + 500);
+        let (address, received) = fake_peer(blocks.clone(), BlockHash::all_zeros());
+        let source = PeerSource::new(&address, Network::Regtest);
+
+        let chain = source.chain(&regtest_options()).unwrap();
+        let hashes: Vec<_> = chain.active.iter().map(|header| header.hash).collect();
+        let expected: Vec<_> = blocks.iter().map(|block| block.block_hash()).collect();
+        assert_eq!(hashes, expected);
+        assert_eq!(received.getheaders.load(Ordering::SeqCst), 2);
+
+        for height in [0, 1, MAX_HEADERS + 499] {
+            let block = source.read_block(&chain.active[height]).unwrap();
+            assert_eq!(block, blocks[height]);
+        }
+        assert_eq!(received.versions.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn parses_downloaded_blocks() {
+        let blocks = regtest_chain(50);
+        let (address, received) = fake_peer(blocks.clone(), BlockHash::all_zeros());
+        let source = PeerSource::new(&address, Network::Regtest).connections(2);
+        let parser = BlockParser::from_source(source, regtest_options()).unwrap();
+
+        let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+        assert_eq!(parsed, blocks);
+        // The connection used for the headers is reused for the blocks
+        let versions = received.versions.load(Ordering::SeqCst);
+        assert!((1..=2).contains(&versions), "{} connections", versions);
+    }
+
+    #[test]
+    fn rejects_headers_that_do_not_connect() {
+        let blocks = regtest_chain(6);
+        for missing in [1, 3] {
+            let served: Vec<_> = [&blocks[..missing], &blocks[missing + 1..]].concat();
+            let (address, _) = fake_peer(served, BlockHash::all_zeros());
+            let source = PeerSource::new(&address, Network::Regtest);
+            let err = source.chain(&regtest_options()).unwrap_err();
+            let expected = format!(
+                "Peer sent header {} that does not connect to {}",
+                blocks[missing + 1].block_hash(),
+                blocks[missing - 1].block_hash()
+            );
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn reconnects_after_errors() {
+        let blocks = regtest_chain(5);
+        let (address, received) = fake_peer(blocks.clone(), blocks[4].block_hash());
+        let source = PeerSource::new(&address, Network::Regtest).connections(1);
+        let chain = source.chain(&regtest_options()).unwrap();
+        let mut missing = chain.active[1].clone();
+        missing.hash = BlockHash::all_zeros();
+
+        // Waiting threads must continue after the connection in use is closed
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..4 {
+            let (source, chain, missing) = (source.clone(), chain.clone(), missing.clone());
+            let (blocks, tx) = (blocks.clone(), tx.clone());
+            thread::spawn(move || {
+                let err = source.read_block(&missing).unwrap_err();
+                assert!(err.to_string().contains("does not have"), "{}", err);
+                assert!(source.read_block(&chain.active[4]).is_err());
+                for (header, block) in chain.active[..4].iter().zip(&blocks) {
+                    assert_eq!(&source.read_block(header).unwrap(), block);
+                }
+                tx.send(()).unwrap();
+            });
+        }
+        for _ in 0..4 {
+            rx.recv_timeout(Duration::from_secs(10)).unwrap();
+        }
+        assert!(received.versions.load(Ordering::SeqCst) > 4);
+    }
+}
@@ -0,0 +1,318 @@
+//! Contains the [`BlockSource`] trait for reading blocks from somewhere other than a `blocks`
+//! directory.
+//!
+//! # Examples
+//! Running the same analysis on exported blocks that runs on a live node:
+//! ```no_run
+//! use bitcoin_block_parser::blocks::*;
+//! use bitcoin_block_parser::source::*;
+//!
+//! fn total_size(parser: BlockParser) -> u64 {
+//!     parser.parse(|block| block.total_size() as u64).sum()
+//! }
+//!
+//! let node = BlockParser::new("/home/user/.bitcoin/blocks/").unwrap();
+//! let exported = RawBlockFiles::new("/home/user/exported-blocks/");
+//! let exported = BlockParser::from_source(exported, ParserOptions::default()).unwrap();
+//! println!("{} {}", total_size(node), total_size(exported));
+//! ```
+
+use crate::blocks::ParserOptions;
+use crate::headers::{HeaderChain, ParsedHeader};
+use crate::params::read_transactions;
+use crate::xor::XorReader;
+use crate::HeaderParser;
+use anyhow::{bail, Result};
+use bitcoin::pow::Work;
+use bitcoin::{Block, BlockHash};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+/// A source of blocks that [`crate::BlockParser`], [`crate::HeaderParser`] and
+/// [`crate::UtxoParser`] can read from.
+///
+/// Implement this trait to parse blocks from a custom location.  The parsers only use the
+/// [`ParsedHeader::path`] and [`ParsedHeader::offset`] through [`BlockSource::read_block`] so
+/// they can be used to locate the block however the source needs.
+pub trait BlockSource: Debug + Send + Sync {
+    /// Reads every header, resolving them into the active chain and any stale branches.
+    ///
+    /// Most sources should call [`HeaderChain::resolve`] with the headers they found.
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain>;
+
+    /// Reads the block given one of the headers returned from [`BlockSource::chain`].
+    ///
+    /// Called from multiple threads at once.
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block>;
+}
+
+/// Reads blocks from the BLK files in Bitcoin Core's `blocks` directory.
+///
+/// Used by [`crate::BlockParser::new`], respects every option in [`ParserOptions`].
+#[derive(Clone, Debug)]
+pub struct BlocksDir {
+    /// Directory where the `*.blk` files are located
+    blocks_dir: String,
+}
+
+impl BlocksDir {
+    /// Creates a source given the `blocks` directory where the `*.blk` files are located.
+    pub fn new(blocks_dir: &str) -> Self {
+        Self {
+            blocks_dir: blocks_dir.to_string(),
+        }
+    }
+}
+
+impl BlockSource for BlocksDir {
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+        HeaderParser::parse_chain(&self.blocks_dir, options)
+    }
+
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+        read_file_block(header)
+    }
+}
+
+/// Reads blocks from a single BLK file, such as one exported from a node.
+///
+/// If an `xor.dat` file is next to the BLK file it will be used to unmask the blocks.
+#[derive(Clone, Debug)]
+pub struct BlkFile {
+    /// Path of the BLK file
+    path: PathBuf,
+}
+
+impl BlkFile {
+    /// Creates a source given the `path` of the BLK file.
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl BlockSource for BlkFile {
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        let xor_mask = HeaderParser::read_xor_mask(dir)?;
+        let (headers, _, skipped) =
+            HeaderParser::parse_headers_file(self.path.clone(), xor_mask, &options.params(), 0)?;
+        let mut skipped_files = BTreeMap::default();
+        if !skipped.is_empty() {
+            skipped_files.insert(self.path.clone(), skipped);
+        }
+        HeaderChain::resolve(headers.iter(), skipped_files, options)
+    }
+
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+        read_file_block(header)
+    }
+}
+
+/// Reads blocks from a directory where every file contains a single consensus serialized block,
+/// such as the hex decoded output of `bitcoin-cli getblock <hash> 0`.
+///
+/// Headers are read like the records of a BLK file for the [`ParserOptions::chain_params`], so
+/// AuxPoW proofs are skipped.  Files that do not start with a valid header are skipped like
+/// garbage in a BLK file, and are listed in [`crate::headers::HeaderReport::skipped`].
+#[derive(Clone, Debug)]
+pub struct RawBlockFiles {
+    /// Directory where the block files are located
+    dir: PathBuf,
+}
+
+impl RawBlockFiles {
+    /// Creates a source given the `dir` that contains the block files.
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl BlockSource for RawBlockFiles {
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+        let params = options.params();
+        let mut headers = vec![];
+        let mut skipped = BTreeMap::default();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if !path.is_file() {
+                continue;
+            }
+            let file = File::open(&path)?;
+            let size = file.metadata()?.len() as usize;
+            // Read like a record of a BLK file, so garbage will not meet its own target
+            let Some(record) = HeaderParser::read_header(&mut BufReader::new(file), &params, size)?
+            else {
+                let whole_file = 0..size;
+                skipped.insert(path, vec![whole_file]);
+                continue;
+            };
+            headers.push(ParsedHeader {
+                inner: record.header,
+                offset: record.read,
+                header_offset: 0,
+                hash: record.hash,
+                path,
+                xor_mask: None,
+                chainwork: Work::from_be_bytes([0; 32]),
+                height: 0,
+                size,
+            });
+        }
+        if headers.is_empty() {
+            bail!("No block files found in dir {:?}", self.dir);
+        }
+        HeaderChain::resolve(headers.iter(), skipped, options)
+    }
+
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+        read_file_block(header)
+    }
+}
+
+/// Reads blocks that are already in memory, useful for tests and small exports.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryBlocks {
+    /// The blocks keyed by their block hash
+    blocks: HashMap<BlockHash, Block>,
+}
+
+impl MemoryBlocks {
+    /// Creates a source from the `blocks` in any order.
+    pub fn new(blocks: impl IntoIterator<Item = Block>) -> Self {
+        Self {
+            blocks: blocks
+                .into_iter()
+                .map(|block| (block.block_hash(), block))
+                .collect(),
+        }
+    }
+}
+
+impl BlockSource for MemoryBlocks {
+    fn chain(&self, options: &ParserOptions) -> Result<HeaderChain> {
+        let headers: Vec<ParsedHeader> = self
+            .blocks
+            .iter()
+            .map(|(hash, block)| ParsedHeader {
+                inner: block.header,
+                offset: 0,
+                header_offset: 0,
+                hash: *hash,
+                path: PathBuf::default(),
+                xor_mask: None,
+                chainwork: Work::from_be_bytes([0; 32]),
+                height: 0,
+                size: block.total_size(),
+            })
+            .collect();
+        HeaderChain::resolve(headers.iter(), BTreeMap::default(), options)
+    }
+
+    fn read_block(&self, header: &ParsedHeader) -> Result<Block> {
+        match self.blocks.get(&header.hash) {
+            Some(block) => Ok(block.clone()),
+            None => bail!("Block {} is not in memory", header.hash),
+        }
+    }
+}
+
+/// Helper function for reading the transactions of a block from a file given the header.
+pub(crate) fn read_file_block(header: &ParsedHeader) -> Result<Block> {
+    let reader = BufReader::new(File::open(&header.path)?);
+    let mut reader = BufReader::new(XorReader::new(reader, header.xor_mask));
+    reader.seek_relative(header.offset as i64)?;
+    Ok(Block {
+        header: header.inner,
+        txdata: read_transactions(&mut reader)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::BlockParser;
+    use crate::headers::tests::{blk_records, regtest_chain};
+    use crate::leveldb::tests::temp_dir;
+    use crate::xor::XOR_MASK_LEN;
+    use bitcoin::block::Header;
+    use bitcoin::Network;
+
+    fn options() -> ParserOptions {
+        ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        }
+    }
+
+    #[test]
+    fn reads_masked_blk_file() {
+        let dir = temp_dir("blk-file");
+        let blocks = regtest_chain(5);
+        let mut data = blk_records(&blocks[..2]);
+        let garbage = data.len()..data.len() + 10;
+        data.extend([0xab; 10]);
+        data.extend(blk_records(&blocks[2..]));
+        let mask = [1, 2, 3, 4, 5, 6, 7, 8];
+        for (pos, byte) in data.iter_mut().enumerate() {
+            *byte ^= mask[pos % XOR_MASK_LEN];
+        }
+        let path = dir.join("blk00042.dat");
+        fs::write(&path, data).unwrap();
+        fs::write(dir.join("xor.dat"), mask).unwrap();
+
+        let source = BlkFile::new(&path);
+        let chain = source.chain(&options()).unwrap();
+        assert_eq!(
+            chain.report.skipped,
+            BTreeMap::from([(path, vec![garbage])])
+        );
+        let parser = BlockParser::from_source(source, options()).unwrap();
+        let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+        assert_eq!(parsed, blocks);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_raw_block_files() {
+        let dir = temp_dir("raw-blocks");
+        let blocks = regtest_chain(4);
+        for block in &blocks {
+            let path = dir.join(format!("{}.bin", block.block_hash()));
+            fs::write(path, bitcoin::consensus::serialize(block)).unwrap();
+        }
+        fs::create_dir(dir.join("nested")).unwrap();
+        let readme = dir.join("README.md");
+        // Long enough to decode as a header that does not meet its target
+        let text =
+            "Regtest blocks exported with `bitcoin-cli getblock <hash> 0` and decoded from hex.\n";
+        assert!(text.len() > Header::SIZE);
+        fs::write(&readme, text).unwrap();
+
+        let source = RawBlockFiles::new(&dir);
+        let chain = source.chain(&options()).unwrap();
+        let whole_file = 0..text.len();
+        let skipped = BTreeMap::from([(readme, vec![whole_file])]);
+        assert_eq!(chain.report.skipped, skipped);
+        let parser = BlockParser::from_source(source, options()).unwrap();
+        let parsed: Vec<_> = parser.parse(|block| block).ordered().collect();
+        assert_eq!(parsed, blocks);
+
+        let empty = temp_dir("no-raw-blocks");
+        let err = RawBlockFiles::new(&empty).chain(&options()).unwrap_err();
+        assert!(
+            err.to_string().starts_with("No block files found"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&empty).unwrap();
+    }
+}
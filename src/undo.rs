@@ -0,0 +1,489 @@
+//! Contains [`UndoParser`] for getting the input amounts of blocks from Bitcoin Core's undo data.
+//!
+//! - See https://github.com/bitcoin/bitcoin/blob/master/src/undo.h
+//! - See https://github.com/bitcoin/bitcoin/blob/master/src/compressor.h
+
+use crate::blocks::{BlockParser, ParserIterator, ParserOptions, TxContext, TxIterator};
+use crate::headers::ParsedHeader;
+use crate::index::{read_varint, BlockIndex, IndexRecord};
+use crate::source::BlocksDir;
+use crate::utxos::{UtxoBlock, UtxoTransaction};
+use crate::xor::{XorReader, XOR_MASK_LEN};
+use crate::HeaderParser;
+use anyhow::{bail, Context, Result};
+use bitcoin::consensus::Decodable;
+use bitcoin::hashes::Hash;
+use bitcoin::{secp256k1, Amount, PubkeyHash, PublicKey, ScriptBuf, ScriptHash};
+use bitcoin::{TxOut, VarInt};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Number of special script types used by the script compression
+const SPECIAL_SCRIPTS: usize = 6;
+
+/// Multithreaded parser that returns a [`ParserIterator`] of [`UtxoBlock`] with the input amounts
+/// read from the `rev*.dat` undo files that Bitcoin Core writes next to the BLK files.
+///
+/// Unlike [`crate::UtxoParser`] no filter or ordering is required, so parsing can start from any
+/// height.  The undo data only contains the outputs a block spends, so every output will have
+/// [`crate::utxos::OutputStatus::Unknown`].
+///
+/// Requires the `blocks/index` database to locate the undo data.
+///
+/// # Examples
+/// Computing the total fees paid in a range of blocks:
+/// ```no_run
+/// use bitcoin::Amount;
+/// use bitcoin_block_parser::undo::*;
+///
+/// let parser = UndoParser::new("/home/user/.bitcoin/blocks/")
+///     .start_height(800_000)
+///     .end_height(800_100);
+/// let fees = parser.parse(|block| {
+///     let mut fees = Amount::ZERO;
+///     for tx in block.txdata.into_iter().skip(1) {
+///         let inputs: Amount = tx.input().map(|(_, out)| out.value).sum();
+///         let outputs: Amount = tx.output().map(|(out, _)| out.value).sum();
+///         fees += inputs - outputs;
+///     }
+///     fees
+/// }).unwrap();
+/// println!("Total fees: {}", fees.sum::<Amount>());
+/// ```
+#[derive(Clone, Debug)]
+pub struct UndoParser {
+    /// Directory where the `*.blk` and `*.rev` files are located
+    blocks_dir: String,
+    /// Options for the underlying parser
+    options: ParserOptions,
+    /// The block height range to start at
+    start_height: usize,
+    /// The block height range to end at
+    end_height: usize,
+}
+
+impl UndoParser {
+    /// Creates a new parser given the `blocks` directory where the `*.blk` and `*.rev` files are
+    /// located.
+    pub fn new(blocks_dir: &str) -> Self {
+        Self {
+            blocks_dir: blocks_dir.to_string(),
+            options: Default::default(),
+            start_height: 0,
+            end_height: usize::MAX,
+        }
+    }
+
+    /// Creates a parser with custom [`ParserOptions`].
+    pub fn with_opts(mut self, options: ParserOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Sets the *inclusive* start of block heights to parse.
+    ///
+    /// * `start_height` - `0` will start at the genesis block.
+    pub fn start_height(mut self, start_height: usize) -> Self {
+        self.start_height = start_height;
+        self
+    }
+
+    /// Sets the *inclusive* end of block heights to parse.
+    ///
+    /// * `end_height` - the height to end at, [`usize::MAX`] will stop at the last block
+    ///   available.
+    pub fn end_height(mut self, end_height: usize) -> Self {
+        self.end_height = end_height;
+        self
+    }
+
+    /// Parse all [`UtxoBlock`] into type `T` and return a [`ParserIterator<T>`].  Results will
+    /// be in random order due to multithreading.
+    ///
+    /// - Returns an `Err` if the block index cannot be read.
+    /// - The iterator panics on the calling thread if a block or its undo data cannot be read, see
+    ///   [`UndoParser::try_parse`].
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse<T: Send + 'static>(
+        &self,
+        extract: impl Fn(UtxoBlock) -> T + Clone + Send + 'static,
+    ) -> Result<ParserIterator<T>> {
+        let (parser, read) = self.block_parser()?;
+        Ok(parser.parse_with(read, extract))
+    }
+
+    /// Parse all [`UtxoBlock`] into type `T`, returning an `Err` for every block whose data or
+    /// undo data cannot be read or where `extract` panics.
+    ///
+    /// - Returns an `Err` if the block index cannot be read.
+    pub fn try_parse<T: Send + 'static>(
+        &self,
+        extract: impl Fn(UtxoBlock) -> T + Clone + Send + 'static,
+    ) -> Result<ParserIterator<Result<T>>> {
+        let (parser, read) = self.block_parser()?;
+        Ok(parser.try_parse_with(read, extract))
+    }
+
+    /// Creates the [`BlockParser`] from the block index along with a function that reads a
+    /// [`UtxoBlock`] with its undo data.
+    fn block_parser(
+        &self,
+    ) -> Result<(
+        BlockParser,
+        impl Fn(&ParsedHeader) -> Result<UtxoBlock> + Clone + Send + 'static,
+    )> {
+        let xor_mask = HeaderParser::read_xor_mask(&self.blocks_dir)?;
+        let index = BlockIndex::read(&self.blocks_dir)?;
+        let chain = index.chain(&self.blocks_dir, xor_mask)?;
+        HeaderParser::check_genesis(&chain.active, &self.options.params())?;
+
+        let index = Arc::new(index);
+        let blocks_dir = self.blocks_dir.clone();
+
+        let source = Arc::new(BlocksDir::new(&self.blocks_dir));
+        let parser = BlockParser::from_chain(source, chain, self.options.clone())
+            .start_height(self.start_height)
+            .end_height(self.end_height);
+        let read_block = parser.block_reader();
+        let read = move |header: &ParsedHeader| {
+            let block = read_block(header)?;
+            let Some(record) = index.get(&header.hash) else {
+                bail!("Block index has no record for {}", header);
+            };
+            let spent = match Self::undo_location(&blocks_dir, record)? {
+                Some((path, undo_pos)) => Self::read_block_undo(&path, undo_pos, xor_mask)
+                    .with_context(|| {
+                        format!(
+                            "Error reading undo data in {:?} at offset {}",
+                            path, undo_pos
+                        )
+                    })?,
+                None => vec![],
+            };
+            UtxoBlock::from_undo(block, spent)
+        };
+        Ok((parser, read))
+    }
+
+    /// Parse every [`UtxoTransaction`] into type `T` and return a [`TxIterator<T>`] with one
+    /// result per transaction, see [`BlockParser::parse_transactions`].
+    ///
+    /// - Returns an `Err` if the block index cannot be read.
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse_transactions<T: Send + 'static>(
+        &self,
+        extract: impl Fn(TxContext, &UtxoTransaction) -> T + Clone + Send + 'static,
+    ) -> Result<TxIterator<T>> {
+        Ok(UtxoBlock::transactions(self.parse(|block| block)?, extract))
+    }
+
+    /// Returns the REV file and offset of the undo data of a block.
+    ///
+    /// - Returns `None` for the genesis block, which has no undo data since its coinbase cannot
+    ///   be spent.
+    /// - Returns an `Err` if the undo data is missing.
+    fn undo_location(blocks_dir: &str, record: &IndexRecord) -> Result<Option<(PathBuf, usize)>> {
+        match (record.file, record.undo_pos) {
+            _ if record.height == 0 => Ok(None),
+            (Some(file), Some(undo_pos)) => {
+                Ok(Some((BlockIndex::rev_path(blocks_dir, file), undo_pos)))
+            }
+            _ => bail!(
+                "No undo data for block {} at height {} (pruned or not connected)",
+                record.hash,
+                record.height
+            ),
+        }
+    }
+
+    /// Reads the spent outputs of every non-coinbase transaction from a `CBlockUndo`.
+    fn read_block_undo(
+        path: &Path,
+        undo_pos: usize,
+        xor_mask: Option<[u8; XOR_MASK_LEN]>,
+    ) -> Result<Vec<Vec<TxOut>>> {
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut reader = XorReader::new(file, xor_mask);
+        // Before the undo data are 4 bytes that indicate its size
+        let mut size = [0; 4];
+        let Some(size_pos) = undo_pos.checked_sub(size.len()) else {
+            bail!("{:?} has no undo size before offset {}", path, undo_pos);
+        };
+        reader.seek(SeekFrom::Start(size_pos as u64))?;
+        reader.read_exact(&mut size)?;
+        let size = u32::from_le_bytes(size) as usize;
+        // Avoid allocating a large amount of memory if the size is corrupted
+        if size > file_len.saturating_sub(undo_pos) {
+            bail!(
+                "{:?} has undo size {} past the end of the file at offset {}",
+                path,
+                size,
+                undo_pos
+            );
+        }
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data)?;
+
+        let reader = &mut data.as_slice();
+        let num_tx = VarInt::consensus_decode(reader)?.0;
+        let mut spent = vec![];
+        for _ in 0..num_tx {
+            let num_coins = VarInt::consensus_decode(reader)?.0;
+            let mut coins = vec![];
+            for _ in 0..num_coins {
+                coins.push(Self::read_coin(reader)?);
+            }
+            spent.push(coins);
+        }
+        if !reader.is_empty() {
+            bail!("{:?} has invalid undo data at offset {}", path, undo_pos);
+        }
+        Ok(spent)
+    }
+
+    /// Reads a spent `Coin`, ignoring the height and coinbase flag.
+    fn read_coin(reader: &mut &[u8]) -> Result<TxOut> {
+        // Height is multiplied by 2 with the lowest bit indicating a coinbase output
+        let code = read_varint(reader)?;
+        if code / 2 > 0 {
+            // Old versions stored the transaction version, now always 0
+            let _version = read_varint(reader)?;
+        }
+        let value = Amount::from_sat(Self::decompress_amount(read_varint(reader)?));
+        let script_pubkey = Self::decompress_script(reader)?;
+        Ok(TxOut {
+            value,
+            script_pubkey,
+        })
+    }
+
+    /// Reverses the amount compression that removes trailing zeros.
+    fn decompress_amount(x: u64) -> u64 {
+        if x == 0 {
+            return 0;
+        }
+        let mut x = x - 1;
+        let exponent = x % 10;
+        x /= 10;
+        let mut n = if exponent < 9 {
+            let digit = (x % 9) + 1;
+            x /= 9;
+            x * 10 + digit
+        } else {
+            x + 1
+        };
+        for _ in 0..exponent {
+            n *= 10;
+        }
+        n
+    }
+
+    /// Reverses the script compression that shortens the most common script types.
+    fn decompress_script(reader: &mut &[u8]) -> Result<ScriptBuf> {
+        let size = read_varint(reader)? as usize;
+        Ok(match size {
+            0 => ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array(take(reader, 20)?.try_into()?)),
+            1 => ScriptBuf::new_p2sh(&ScriptHash::from_byte_array(take(reader, 20)?.try_into()?)),
+            // Compressed public key with an even or odd y-coordinate
+            2 | 3 => {
+                let mut key = vec![size as u8];
+                key.extend_from_slice(take(reader, 32)?);
+                ScriptBuf::new_p2pk(&PublicKey::from_slice(&key)?)
+            }
+            // Uncompressed public key, stored in compressed form
+            4 | 5 => {
+                let mut key = vec![size as u8 - 2];
+                key.extend_from_slice(take(reader, 32)?);
+                let key = secp256k1::PublicKey::from_slice(&key)?;
+                ScriptBuf::new_p2pk(&PublicKey::new_uncompressed(key))
+            }
+            _ => ScriptBuf::from_bytes(take(reader, size - SPECIAL_SCRIPTS)?.to_vec()),
+        })
+    }
+}
+
+/// Takes the next `len` bytes from the `reader`.
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if len > reader.len() {
+        bail!("Undo data ended unexpectedly");
+    }
+    let (bytes, rest) = reader.split_at(len);
+    *reader = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::tests::{header, put_varint, write_blocks};
+    use crate::index::{BLOCK_HAVE_DATA, BLOCK_HAVE_UNDO, BLOCK_VALID_SCRIPTS};
+    use crate::leveldb::tests::{temp_dir, write_db};
+    use crate::params::ChainParams;
+    use bitcoin::pow::Work;
+    use bitcoin::{BlockHash, Network};
+    use std::fs;
+
+    fn record(height: usize, status: u32, undo_pos: Option<usize>) -> IndexRecord {
+        let header = header(BlockHash::all_zeros(), height as u32);
+        IndexRecord {
+            header,
+            hash: header.block_hash(),
+            height,
+            status: BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA | status,
+            num_tx: 1,
+            file: Some(2),
+            data_pos: Some(8),
+            undo_pos,
+            chainwork: Work::from_be_bytes([0; 32]),
+        }
+    }
+
+    #[test]
+    fn requires_undo_data() {
+        let location = UndoParser::undo_location("blocks", &record(0, 0, None)).unwrap();
+        assert_eq!(location, None);
+        let record_with_undo = record(5, BLOCK_HAVE_UNDO, Some(40));
+        let location = UndoParser::undo_location("blocks", &record_with_undo).unwrap();
+        assert_eq!(
+            location,
+            Some((Path::new("blocks").join("rev00002.dat"), 40))
+        );
+
+        let pruned = record(5, 0, None);
+        let err = UndoParser::undo_location("blocks", &pruned).unwrap_err();
+        let expected = format!("No undo data for block {} at height 5", pruned.hash);
+        assert!(err.to_string().starts_with(&expected), "{}", err);
+    }
+
+    #[test]
+    fn reads_block_undo() {
+        let dir = temp_dir("block-undo");
+        let path = dir.join("rev00000.dat");
+        let hash = [7; 20];
+        let generator = secp256k1::constants::GENERATOR_X;
+        let mut key = vec![4];
+        key.extend(generator);
+        key.extend(&secp256k1::constants::GENERATOR_Y);
+
+        // Compressed amount, script size and script of the coins spent by each transaction
+        let coins: [&[(u64, u64, &[u8])]; 2] = [
+            &[(9, 0, &hash), (50, 1, &hash)],
+            // Uncompressed keys are stored with the parity of the y-coordinate, which is even
+            &[
+                (7, 2, &generator),
+                (1, 4, &generator),
+                (0, 9, &[0x6a, 1, 2]),
+            ],
+        ];
+        let mut data = vec![coins.len() as u8];
+        for tx_coins in coins {
+            data.push(tx_coins.len() as u8);
+            for (height, (amount, size, script)) in tx_coins.iter().enumerate() {
+                // Height and coinbase flag, followed by a version for non-zero heights
+                put_varint(&mut data, height as u64 * 2);
+                if height > 0 {
+                    put_varint(&mut data, 0);
+                }
+                put_varint(&mut data, *amount);
+                put_varint(&mut data, *size);
+                data.extend(*script);
+            }
+        }
+        let mut file = Network::Regtest.magic().to_bytes().to_vec();
+        file.extend((data.len() as u32).to_le_bytes());
+        file.extend(&data);
+        fs::write(&path, &file).unwrap();
+
+        let undo = UndoParser::read_block_undo(&path, 8, None).unwrap();
+        let mut compressed = vec![2];
+        compressed.extend(generator);
+        let compressed = PublicKey::from_slice(&compressed).unwrap();
+        let uncompressed = PublicKey::from_slice(&key).unwrap();
+        let out = |sats, script_pubkey| TxOut {
+            value: Amount::from_sat(sats),
+            script_pubkey,
+        };
+        let expected = vec![
+            vec![
+                out(
+                    100_000_000,
+                    ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array(hash)),
+                ),
+                out(
+                    50_0000_0000,
+                    ScriptBuf::new_p2sh(&ScriptHash::from_byte_array(hash)),
+                ),
+            ],
+            vec![
+                out(1_000_000, ScriptBuf::new_p2pk(&compressed)),
+                out(1, ScriptBuf::new_p2pk(&uncompressed)),
+                out(0, ScriptBuf::from_bytes(vec![0x6a, 1, 2])),
+            ],
+        ];
+        assert_eq!(undo, expected);
+
+        // Corrupted positions and sizes return errors instead of panicking or allocating
+        let err = UndoParser::read_block_undo(&path, 2, None).unwrap_err();
+        assert!(err.to_string().contains("rev00000.dat"), "{}", err);
+        file[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &file).unwrap();
+        let err = UndoParser::read_block_undo(&path, 8, None).unwrap_err();
+        assert!(
+            err.to_string().contains("past the end of the file"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn decompresses_amounts() {
+        for (compressed, amount) in [(0, 0), (1, 1), (7, 1_000_000), (9, 100_000_000)] {
+            assert_eq!(UndoParser::decompress_amount(compressed), amount);
+        }
+        assert_eq!(UndoParser::decompress_amount(50), 50_0000_0000);
+    }
+
+    #[test]
+    fn returns_blocks_without_undo_data_as_errors() {
+        let dir = temp_dir("undo-parser");
+        let valid = BLOCK_VALID_SCRIPTS | BLOCK_HAVE_DATA;
+        let genesis = header(BlockHash::all_zeros(), 0);
+        let pruned = header(genesis.block_hash(), 1);
+        let entries = write_blocks(&dir, &[(genesis, 0, valid), (pruned, 1, valid)]);
+        write_db(&dir.join("index"), &entries);
+        let options = ParserOptions {
+            network: Network::Regtest,
+            chain_params: Some(ChainParams {
+                genesis_hash: genesis.block_hash(),
+                ..ChainParams::bitcoin(Network::Regtest)
+            }),
+            ..ParserOptions::default()
+        };
+        let parser = UndoParser::new(dir.to_str().unwrap()).with_opts(options);
+
+        let results: Vec<_> = parser
+            .try_parse(|block| block.txdata.len())
+            .unwrap()
+            .ordered()
+            .collect();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].as_ref().unwrap(), &0);
+        let err = results[1].as_ref().unwrap_err();
+        assert!(format!("{:?}", err).contains("No undo data"), "{:?}", err);
+
+        let mut iterator = parser.parse(|block| block.txdata.len()).unwrap().ordered();
+        assert_eq!(iterator.try_next().unwrap(), Some(0));
+        let err = iterator.try_next().unwrap_err();
+        assert!(format!("{:?}", err).contains("No undo data"), "{:?}", err);
+        assert_eq!(iterator.try_next().unwrap(), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,7 +1,9 @@
 //! Contains [`UtxoParser`] for tracking input amounts and output statuses in [`UtxoBlock`].
 
-use crate::blocks::{BlockParser, ParserIterator, ParserOptions, Pipeline};
-use anyhow::Result;
+use crate::blocks::{BlockParser, ParserIterator, ParserOptions, Pipeline, TxContext, TxIterator};
+use crate::headers::GapPolicy;
+use crate::source::{BlockSource, BlocksDir};
+use anyhow::{bail, Result};
 use bitcoin::block::Header;
 use bitcoin::hashes::Hash;
 use bitcoin::{Block, OutPoint, Transaction, TxIn, TxOut, Txid};
@@ -35,6 +37,52 @@ impl UtxoBlock {
         }
     }
 
+    /// Construct from a bitcoin [`Block`] and the spent [`TxOut`] of every non-coinbase transaction
+    /// read from its undo data.  Output statuses will be [`OutputStatus::Unknown`].
+    pub(crate) fn from_undo(block: Block, spent: Vec<Vec<TxOut>>) -> Result<Self> {
+        let hash = block.block_hash();
+        let mut spent = spent.into_iter();
+        let mut txdata = vec![];
+        for tx in block.txdata {
+            let mut tx = UtxoTransaction::new(tx);
+            tx.outputs = vec![OutputStatus::Unknown; tx.transaction.output.len()];
+            tx.inputs = if tx.transaction.is_coinbase() {
+                // coinbase transactions will not have a previous input
+                vec![TxOut::NULL; tx.transaction.input.len()]
+            } else {
+                spent.next().unwrap_or_default()
+            };
+            if tx.inputs.len() != tx.transaction.input.len() {
+                bail!("Undo data does not match the inputs of block {}", hash);
+            }
+            txdata.push(tx);
+        }
+        if spent.next().is_some() {
+            bail!("Undo data has more transactions than block {}", hash);
+        }
+        Ok(Self {
+            header: block.header,
+            txdata,
+        })
+    }
+
+    /// Runs `extract` on every transaction of the `blocks` on multiple threads, after the parser
+    /// has returned them since only the iterator knows their heights.
+    pub(crate) fn transactions<T: Send + 'static>(
+        blocks: ParserIterator<UtxoBlock>,
+        extract: impl Fn(TxContext, &UtxoTransaction) -> T + Clone + Send + 'static,
+    ) -> TxIterator<T> {
+        let iterator = blocks.with_height().map_parallel(move |(height, block)| {
+            let txids = block.txdata.iter().map(|tx| tx.txid);
+            let contexts = TxContext::of_block(height, block.header.block_hash(), txids);
+            contexts
+                .zip(&block.txdata)
+                .map(|(context, tx)| extract(context, tx))
+                .collect()
+        });
+        TxIterator::new(iterator)
+    }
+
     /// Convert back into a [`bitcoin::Block`].
     pub fn to_block(self) -> Block {
         Block {
@@ -80,12 +128,17 @@ impl UtxoTransaction {
 }
 
 /// Status of the [`TxOut`] within the transaction graph.
+///
+/// More statuses may be added as new parsers track outputs, so matches need a wildcard arm.
 #[derive(Clone, Debug, Eq, PartialEq, Copy)]
+#[non_exhaustive]
 pub enum OutputStatus {
     /// The output was spent in a later block.
     Spent,
     /// The output was never spent in any later block (it is a UTXO).
     Unspent,
+    /// The parser does not track whether the output was spent, such as [`crate::UndoParser`].
+    Unknown,
 }
 
 type ShortOutPoints = (Vec<ShortOutPoint>, Vec<ShortOutPoint>);
@@ -145,12 +198,14 @@ type ShortOutPointFilter = ScalableCuckooFilter<ShortOutPoint, DefaultHasher, Fa
 pub struct UtxoParser {
     /// Filter file that contains all UTXOs
     filter_file: String,
-    /// Underlying parser for parsing the blocks.
-    blocks_dir: String,
+    /// Where the blocks are read from
+    source: Arc<dyn BlockSource>,
     /// Used to allocate the initial capacity of shared state.
     estimated_utxos: usize,
     /// The block height range to end at
     end_height: usize,
+    /// Unix timestamp of the latest median-time-past to parse
+    end_time: Option<u32>,
     /// Options for the underlying parser
     options: ParserOptions,
 }
@@ -159,17 +214,24 @@ impl UtxoParser {
     /// Creates a new parser.
     ///
     /// - `blocks_dir` - directory where the `*.blk` files are located.
-    /// - `filter_file` - file that will store the UTXO filter.
+    /// - `filter_file` - file that will store the UTXO filter.  The parsed headers are cached next
+    ///   to it in `<filter_file>.headers`.
     ///
     /// Returns an `Err` if unable to parse the `blk` files.
     /// You can [specify the blocks directory](https://en.bitcoin.it/wiki/Data_directory) when
     ///   running `bitcoind`.
     pub fn new(blocks_dir: &str, filter_file: &str) -> Self {
+        Self::from_source(BlocksDir::new(blocks_dir), filter_file)
+    }
+
+    /// Creates a parser that reads blocks from any [`BlockSource`].
+    pub fn from_source(source: impl BlockSource + 'static, filter_file: &str) -> Self {
         Self {
             filter_file: filter_file.to_string(),
-            blocks_dir: blocks_dir.to_string(),
+            source: Arc::new(source),
             estimated_utxos: 250_000_000,
             end_height: usize::MAX,
+            end_time: None,
             options: Default::default(),
         }
     }
@@ -186,23 +248,56 @@ impl UtxoParser {
     /// Parsing always starts at the genesis block in order to track the transaction graph properly.
     ///
     /// * `end_height` - the height to end at, [`usize::MAX`] will stop at the last block
-    ///    available.
+    ///   available.
     pub fn end_height(mut self, end_height: usize) -> Self {
         self.end_height = end_height;
         self
     }
 
+    /// Sets the *inclusive* end of block times to parse, see [`BlockParser::end_time`].
+    /// Parsing always starts at the genesis block in order to track the transaction graph properly.
+    ///
+    /// * `end_time` - unix timestamp compared against the median-time-past of each block.
+    pub fn end_time(mut self, end_time: u32) -> Self {
+        self.end_time = Some(end_time);
+        self
+    }
+
     /// Creates a parser with custom [`ParserOptions`].
     pub fn with_opts(mut self, options: ParserOptions) -> Self {
         self.options = options;
         self
     }
 
+    /// Creates the underlying [`BlockParser`], caching the headers next to the `filter_file` unless
+    /// a [`ParserOptions::header_cache`] was set.
+    /// - Returns an `Err` for [`GapPolicy::Continue`] since outputs created in the missing blocks
+    ///   could never be tracked.
+    /// - Returns an `Err` for Elements chains, whose confidential amounts cannot be tracked.
+    fn block_parser(&self) -> Result<BlockParser> {
+        if self.options.gap_policy == GapPolicy::Continue {
+            bail!("UTXOs cannot be tracked across missing blocks, use another GapPolicy");
+        }
+        let mut options = self.options.clone();
+        if options.header_cache.is_none() {
+            options.header_cache = Some(format!("{}.headers", self.filter_file));
+        }
+        let parser = BlockParser::from_shared_source(self.source.clone(), options)?;
+        let parser = parser.end_height(self.end_height);
+        Ok(match self.end_time {
+            Some(end_time) => parser.end_time(end_time),
+            None => parser,
+        })
+    }
+
     /// Parse all [`UtxoBlock`] into type `T` and return a [`ParserIterator<T>`].  Results will
     /// be in random order due to multithreading.
     ///
+    /// - The iterator panics on the calling thread if a block cannot be read, since every later
+    ///   block depends on it.  Use [`ParserIterator::try_next`] to handle the error instead.
+    ///
     /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
-    ///    computation and data reduction here as possible.
+    ///   computation and data reduction here as possible.
     pub fn parse<T: Send + 'static>(
         self,
         extract: impl Fn(UtxoBlock) -> T + Clone + Send + 'static,
@@ -217,25 +312,34 @@ impl UtxoParser {
         let filter = bincode::deserialize_from(reader)?;
         let pipeline = UtxoPipeline::new(filter, extract);
 
-        Ok(
-            BlockParser::new_with_opts(&self.blocks_dir, self.options.clone())?
-                .end_height(self.end_height)
-                .parse(UtxoBlock::new)
-                .ordered()
-                .pipeline(&pipeline),
-        )
+        Ok(self
+            .block_parser()?
+            .parse(UtxoBlock::new)
+            .ordered()
+            .pipeline(&pipeline))
+    }
+
+    /// Parse every [`UtxoTransaction`] into type `T` and return a [`TxIterator<T>`] with one
+    /// result per transaction, see [`BlockParser::parse_transactions`].
+    ///
+    /// * `extract` - a closure that runs on multiple threads.  For best performance perform as much
+    ///   computation and data reduction here as possible.
+    pub fn parse_transactions<T: Send + 'static>(
+        self,
+        extract: impl Fn(TxContext, &UtxoTransaction) -> T + Clone + Send + 'static,
+    ) -> Result<TxIterator<T>> {
+        Ok(UtxoBlock::transactions(self.parse(|block| block)?, extract))
     }
 
     /// Force the creation of a new `filter_file`.
     pub fn create_filter(&self) -> Result<Self> {
         info!("Creating UTXO filter '{}'", self.filter_file);
         let filter = UtxoFilter::new(self.estimated_utxos);
-        BlockParser::new_with_opts(&self.blocks_dir, self.options.clone())?
-            .end_height(self.end_height)
-            .parse(UtxoFilter::outpoints)
-            .ordered()
-            .map(&|outpoints| filter.update(outpoints))
-            .for_each(|_| {});
+        let iterator = self.block_parser()?.parse(UtxoFilter::outpoints);
+        let mut iterator = iterator.ordered();
+        while let Some(outpoints) = iterator.try_next()? {
+            filter.update(outpoints);
+        }
 
         let filter = Arc::try_unwrap(filter.filter).expect("Arc still referenced");
         let mut filter = Mutex::into_inner(filter)?;
@@ -412,3 +516,96 @@ impl RngCore for FastRng {
         self.0.try_fill_bytes(dest)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::TxContext;
+    use crate::headers::tests::spending_chain;
+    use crate::leveldb::tests::temp_dir;
+    use crate::params::ChainParams;
+    use crate::source::MemoryBlocks;
+    use bitcoin::{Amount, Network};
+
+    #[test]
+    fn refuses_to_continue_after_gaps() {
+        let options = ParserOptions {
+            gap_policy: GapPolicy::Continue,
+            ..ParserOptions::default()
+        };
+        let parser =
+            UtxoParser::new("/nonexistent/blocks", "/nonexistent/filter").with_opts(options);
+        let err = parser.create_filter().err().unwrap();
+        assert!(err.to_string().contains("GapPolicy"), "{}", err);
+    }
+
+    #[test]
+    fn refuses_elements_chains() {
+        let options = ParserOptions {
+            chain_params: Some(ChainParams::liquid()),
+            ..ParserOptions::default()
+        };
+        let parser =
+            UtxoParser::new("/nonexistent/blocks", "/nonexistent/filter").with_opts(options);
+        let err = parser.create_filter().err().unwrap();
+        assert!(err.to_string().contains("ElementsParser"), "{}", err);
+    }
+
+    #[test]
+    fn parses_utxo_transactions() {
+        let dir = temp_dir("utxo-transactions");
+        let filter_file = dir.join("filter.bin");
+        let blocks = spending_chain(7);
+        let options = ParserOptions {
+            network: Network::Regtest,
+            ..ParserOptions::default()
+        };
+        let parser = UtxoParser::from_source(
+            MemoryBlocks::new(blocks.clone()),
+            filter_file.to_str().unwrap(),
+        )
+        .estimated_utxos(1000)
+        .with_opts(options);
+
+        let iterator = parser.parse_transactions(|context, tx| {
+            assert_eq!(context.txid, tx.txid);
+            let inputs: Vec<_> = tx.input().map(|(_, output)| output.value).collect();
+            let outputs: Vec<_> = tx.output().map(|(_, status)| *status).collect();
+            (context, inputs, outputs)
+        });
+        let results: Vec<_> = iterator.unwrap().ordered().collect();
+        let contexts: Vec<_> = results.iter().map(|(context, _, _)| *context).collect();
+        let expected: Vec<_> = blocks
+            .iter()
+            .enumerate()
+            .flat_map(|(height, block)| {
+                let txids = block.txdata.iter().map(Transaction::compute_txid);
+                TxContext::of_block(height, block.block_hash(), txids)
+            })
+            .collect();
+        assert_eq!(contexts, expected);
+
+        let (spent, unspent) = (OutputStatus::Spent, OutputStatus::Unspent);
+        let coin = |btc: u64| Amount::from_sat(btc * 1_0000_0000);
+        for (context, inputs, outputs) in &results {
+            let (height, index) = (context.height, context.index);
+            let expected = match (index, context.is_coinbase) {
+                // Coinbases are spent 2 blocks later, except the genesis block and last 2 blocks
+                (0, true) if (1..5).contains(&height) => (vec![TxOut::NULL.value], vec![spent]),
+                (0, true) => (vec![TxOut::NULL.value], vec![unspent]),
+                // Only the second output of the first spend is spent by the next block
+                (1, false) if height < 6 => (vec![coin(50)], vec![unspent, spent]),
+                (1, false) => (vec![coin(50)], vec![unspent, unspent]),
+                (2, false) => (vec![coin(25)], vec![unspent, unspent]),
+                _ => panic!("Unexpected transaction {:?}", context),
+            };
+            assert_eq!(
+                (inputs, outputs),
+                (&expected.0, &expected.1),
+                "{:?}",
+                context
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}